@@ -1,37 +1,430 @@
+use std::fmt::{self, Write};
+use std::iter;
+use std::rc::Rc;
+
 use value::Value;
 
+// The most constants a chunk may hold, as a constant operand is one byte in
+// clox's bytecode format.
+pub const MAX_CONSTANTS: usize = 256;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Instruction {
     Return,
     Constant(usize),
+    Nil,
+    True,
+    False,
+    Duplicate,
+    Pop,
+    DefineGlobal(usize),
+    GetGlobal(usize),
+    SetGlobal(usize),
+    GetLocal(usize),
+    SetLocal(usize),
     Negate,
+    Not,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Loop(usize),
+    Call(usize),
+    Power,
+    Class(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Method(usize),
+    BuildList(usize),
+    GetIndex,
+    SetIndex,
+    BuildMap(usize),
+    Modulo,
+    PopN(usize),
+    // A method call on the receiver below the arguments: the method name's
+    // constant and the argument count.
+    Invoke(usize, usize),
 }
 
+impl Instruction {
+    // The opcode identifying the instruction in serialized bytecode. The
+    // numbering is part of the bytecode format, so existing opcodes must
+    // never change and new instructions get the next free number:
+    //
+    //   0 Return        7 DefineGlobal  14 Add       21 Print
+    //   1 Constant      8 GetGlobal     15 Subtract  22 Jump
+    //   2 Nil           9 SetGlobal     16 Multiply  23 JumpIfFalse
+    //   3 True         10 GetLocal      17 Divide    24 Loop
+    //   4 False        11 SetLocal      18 Equal     25 Call
+    //   5 Duplicate    12 Negate        19 Greater
+    //   6 Pop          13 Not           20 Less
+    //
+    //  26 Power        27 Class         28 GetProperty
+    //  29 SetProperty  30 Method        31 BuildList
+    //  32 GetIndex     33 SetIndex      34 BuildMap
+    //  35 Modulo       36 PopN          37 Invoke
+    pub fn opcode(&self) -> u8 {
+        use self::Instruction::*;
+
+        match self {
+            Return => 0,
+            Constant(_) => 1,
+            Nil => 2,
+            True => 3,
+            False => 4,
+            Duplicate => 5,
+            Pop => 6,
+            DefineGlobal(_) => 7,
+            GetGlobal(_) => 8,
+            SetGlobal(_) => 9,
+            GetLocal(_) => 10,
+            SetLocal(_) => 11,
+            Negate => 12,
+            Not => 13,
+            Add => 14,
+            Subtract => 15,
+            Multiply => 16,
+            Divide => 17,
+            Equal => 18,
+            Greater => 19,
+            Less => 20,
+            Print => 21,
+            Jump(_) => 22,
+            JumpIfFalse(_) => 23,
+            Loop(_) => 24,
+            Call(_) => 25,
+            Power => 26,
+            Class(_) => 27,
+            GetProperty(_) => 28,
+            SetProperty(_) => 29,
+            Method(_) => 30,
+            BuildList(_) => 31,
+            GetIndex => 32,
+            SetIndex => 33,
+            BuildMap(_) => 34,
+            Modulo => 35,
+            PopN(_) => 36,
+            Invoke(..) => 37,
+        }
+    }
+
+    // How many operands follow the opcode, `None` for unknown opcodes.
+    pub fn operand_count_of(opcode: u8) -> Option<usize> {
+        match opcode {
+            37 => Some(2),
+            1 | 7..=11 | 22..=25 | 27..=31 | 34 | 36 => Some(1),
+            0..=36 => Some(0),
+            _ => None,
+        }
+    }
+
+    // The inverse of `opcode`. Returns `None` for unknown opcodes or the wrong
+    // number of operands.
+    pub fn from_opcode_and_operands(opcode: u8, operands: &[usize]) -> Option<Instruction> {
+        use self::Instruction::*;
+
+        let instruction = match (opcode, operands) {
+            (0, []) => Return,
+            (1, &[o]) => Constant(o),
+            (2, []) => Nil,
+            (3, []) => True,
+            (4, []) => False,
+            (5, []) => Duplicate,
+            (6, []) => Pop,
+            (7, &[o]) => DefineGlobal(o),
+            (8, &[o]) => GetGlobal(o),
+            (9, &[o]) => SetGlobal(o),
+            (10, &[o]) => GetLocal(o),
+            (11, &[o]) => SetLocal(o),
+            (12, []) => Negate,
+            (13, []) => Not,
+            (14, []) => Add,
+            (15, []) => Subtract,
+            (16, []) => Multiply,
+            (17, []) => Divide,
+            (18, []) => Equal,
+            (19, []) => Greater,
+            (20, []) => Less,
+            (21, []) => Print,
+            (22, &[o]) => Jump(o),
+            (23, &[o]) => JumpIfFalse(o),
+            (24, &[o]) => Loop(o),
+            (25, &[o]) => Call(o),
+            (26, []) => Power,
+            (27, &[o]) => Class(o),
+            (28, &[o]) => GetProperty(o),
+            (29, &[o]) => SetProperty(o),
+            (30, &[o]) => Method(o),
+            (31, &[o]) => BuildList(o),
+            (32, []) => GetIndex,
+            (33, []) => SetIndex,
+            (34, &[o]) => BuildMap(o),
+            (35, []) => Modulo,
+            (36, &[o]) => PopN(o),
+            (37, &[name, arg_count]) => Invoke(name, arg_count),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    // How many operands follow the opcode.
+    pub fn operand_count(&self) -> usize {
+        self.operands().len()
+    }
+
+    pub fn name(&self) -> &'static str {
+        use self::Instruction::*;
+
+        match self {
+            Return => "OP_RETURN",
+            Constant(_) => "OP_CONSTANT",
+            Nil => "OP_NIL",
+            True => "OP_TRUE",
+            False => "OP_FALSE",
+            Duplicate => "OP_DUPLICATE",
+            Pop => "OP_POP",
+            DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+            GetGlobal(_) => "OP_GET_GLOBAL",
+            SetGlobal(_) => "OP_SET_GLOBAL",
+            GetLocal(_) => "OP_GET_LOCAL",
+            SetLocal(_) => "OP_SET_LOCAL",
+            Negate => "OP_NEGATE",
+            Not => "OP_NOT",
+            Add => "OP_ADD",
+            Subtract => "OP_SUBTRACT",
+            Multiply => "OP_MULTIPLY",
+            Divide => "OP_DIVIDE",
+            Equal => "OP_EQUAL",
+            Greater => "OP_GREATER",
+            Less => "OP_LESS",
+            Print => "OP_PRINT",
+            Jump(_) => "OP_JUMP",
+            JumpIfFalse(_) => "OP_JUMP_IF_FALSE",
+            Loop(_) => "OP_LOOP",
+            Call(_) => "OP_CALL",
+            Power => "OP_POWER",
+            Class(_) => "OP_CLASS",
+            GetProperty(_) => "OP_GET_PROPERTY",
+            SetProperty(_) => "OP_SET_PROPERTY",
+            Method(_) => "OP_METHOD",
+            BuildList(_) => "OP_BUILD_LIST",
+            GetIndex => "OP_GET_INDEX",
+            SetIndex => "OP_SET_INDEX",
+            BuildMap(_) => "OP_BUILD_MAP",
+            Modulo => "OP_MODULO",
+            PopN(_) => "OP_POPN",
+            Invoke(..) => "OP_INVOKE",
+        }
+    }
+
+    pub(crate) fn operands(&self) -> Vec<usize> {
+        use self::Instruction::*;
+
+        match *self {
+            Constant(o) | DefineGlobal(o) | GetGlobal(o) | SetGlobal(o) | GetLocal(o)
+            | SetLocal(o) | Jump(o) | JumpIfFalse(o) | Loop(o) | Call(o) | Class(o)
+            | GetProperty(o) | SetProperty(o) | Method(o) | BuildList(o) | BuildMap(o)
+            | PopN(o) => vec![o],
+            Invoke(name, arg_count) => vec![name, arg_count],
+            _ => vec![],
+        }
+    }
+
+    // The operand if it indexes the constant pool.
+    fn constant_operand(&self) -> Option<usize> {
+        use self::Instruction::*;
+
+        match *self {
+            Constant(c)
+            | DefineGlobal(c)
+            | GetGlobal(c)
+            | SetGlobal(c)
+            | Class(c)
+            | GetProperty(c)
+            | SetProperty(c)
+            | Method(c)
+            | Invoke(c, _) => Some(c),
+            _ => None,
+        }
+    }
+
+    // The constant holding the name of the variable, property, class or
+    // method the instruction works with.
+    fn name_operand(&self) -> Option<usize> {
+        match *self {
+            Instruction::Constant(_) => None,
+            _ => self.constant_operand(),
+        }
+    }
+
+    fn jump_target(&self) -> Option<usize> {
+        use self::Instruction::*;
+
+        match *self {
+            Jump(target) | JumpIfFalse(target) | Loop(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+// Why a chunk can't be run safely. `at` is the offending instruction's index.
 #[derive(Debug, PartialEq)]
-pub struct InstructionWithLine(pub Instruction, pub usize);
+pub enum VerifyError {
+    ConstantOutOfRange {
+        at: usize,
+        name: &'static str,
+        index: usize,
+        count: usize,
+    },
+    JumpOutOfRange {
+        at: usize,
+        name: &'static str,
+        target: usize,
+        len: usize,
+    },
+    NotAName {
+        at: usize,
+        name: &'static str,
+        index: usize,
+    },
+    LinesMismatch {
+        lines: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::ConstantOutOfRange {
+                at,
+                name,
+                index,
+                count,
+            } => write!(
+                f,
+                "{} at {:04} refers to constant {}, but the chunk has {} constants",
+                name, at, index, count
+            ),
+            VerifyError::JumpOutOfRange {
+                at,
+                name,
+                target,
+                len,
+            } => write!(
+                f,
+                "{} at {:04} jumps to {}, but the chunk has {} instructions",
+                name, at, target, len
+            ),
+            VerifyError::NotAName { at, name, index } => write!(
+                f,
+                "{} at {:04} refers to constant {}, which is not a name",
+                name, at, index
+            ),
+            VerifyError::LinesMismatch { lines, len } => write!(
+                f,
+                "The line table covers {} instructions, but the chunk has {}",
+                lines, len
+            ),
+        }
+    }
+}
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct Chunk {
-    pub instructions: Vec<InstructionWithLine>,
+    pub instructions: Vec<Instruction>,
+    // Run-length encoded source lines of the instructions, as
+    // `(line, instruction count)` pairs.
+    pub lines: Vec<(usize, usize)>,
     pub constants: Vec<Value>,
 }
 
+impl Default for Chunk {
+    fn default() -> Chunk {
+        Chunk::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             instructions: Vec::new(),
+            lines: Vec::new(),
             constants: Vec::new(),
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    // The index the next added instruction will get, which is where a jump
+    // patched now lands.
+    pub fn next_index(&self) -> usize {
+        self.instructions.len()
+    }
+
     pub fn add_instruction(&mut self, oc: Instruction, line: usize) {
-        self.instructions.push(InstructionWithLine(oc, line))
+        self.instructions.push(oc);
+        match self.lines.last_mut() {
+            Some((last_line, count)) if *last_line == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    pub fn pop_instruction(&mut self) -> Option<Instruction> {
+        let instruction = self.instructions.pop()?;
+        if let Some((_, count)) = self.lines.last_mut() {
+            *count -= 1;
+            if *count == 0 {
+                self.lines.pop();
+            }
+        }
+        Some(instruction)
+    }
+
+    // Each instruction with its index and source line, walking the line runs
+    // alongside instead of looking up every line.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Instruction, usize)> {
+        let lines = self
+            .lines
+            .iter()
+            .flat_map(|&(line, count)| iter::repeat(line).take(count));
+        self.instructions
+            .iter()
+            .enumerate()
+            .zip(lines)
+            .map(|((i, instruction), line)| (i, instruction, line))
+    }
+
+    pub fn line_at(&self, instruction_index: usize) -> usize {
+        let mut end = 0;
+        for &(line, count) in &self.lines {
+            end += count;
+            if instruction_index < end {
+                return line;
+            }
+        }
+        panic!("No line for instruction {}", instruction_index)
     }
 
+    // Reuses the index of an identical constant already in the pool. Numbers
+    // are compared by their bits, so `0` and `-0` stay distinct while NaN
+    // is only shared with a NaN of the same bits.
     pub fn add_constant(&mut self, c: Value) -> usize {
+        if let Some(i) = self.constants.iter().position(|e| is_same_constant(e, &c)) {
+            return i;
+        }
         self.constants.push(c);
         self.constants.len() - 1
     }
@@ -39,12 +432,428 @@ impl Chunk {
     pub fn read_constant(&self, i: usize) -> &Value {
         &self.constants[i]
     }
+
+    // Checks that every constant operand and jump target is in range, here
+    // and in the chunks of the functions among the constants. The checks are
+    // bounds only: local slots and the stack shape around jumps aren't
+    // checked, so a verified chunk isn't proven safe to run.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let lines = self.lines.iter().map(|&(_, count)| count).sum();
+        if lines != self.len() {
+            return Err(VerifyError::LinesMismatch {
+                lines,
+                len: self.len(),
+            });
+        }
+        for (at, instruction) in self.instructions.iter().enumerate() {
+            if let Some(index) = instruction.constant_operand() {
+                if index >= self.constants.len() {
+                    return Err(VerifyError::ConstantOutOfRange {
+                        at,
+                        name: instruction.name(),
+                        index,
+                        count: self.constants.len(),
+                    });
+                }
+            }
+            if let Some(index) = instruction.name_operand() {
+                if !matches!(self.constants[index], Value::Str(_)) {
+                    return Err(VerifyError::NotAName {
+                        at,
+                        name: instruction.name(),
+                        index,
+                    });
+                }
+            }
+            if let Some(target) = instruction.jump_target() {
+                if target >= self.len() {
+                    return Err(VerifyError::JumpOutOfRange {
+                        at,
+                        name: instruction.name(),
+                        target,
+                        len: self.len(),
+                    });
+                }
+            }
+        }
+        for constant in &self.constants {
+            if let Value::Function(ref function) = *constant {
+                function.chunk.verify()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_same_constant(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Double(l), Value::Double(r)) => l.to_bits() == r.to_bits(),
+        (Value::Int(l), Value::Int(r)) => l == r,
+        (Value::Int(_), _) | (_, Value::Int(_)) => false,
+        (Value::Function(l), Value::Function(r)) => Rc::ptr_eq(l, r),
+        _ => l == r,
+    }
 }
 
 impl Chunk {
     pub fn disassemble(&self) {
-        for (i, inc) in self.instructions.iter().enumerate() {
-            println!("{} {:?}", i, inc);
+        print!("{}", self.disassemble_to_string());
+    }
+
+    pub fn disassemble_to_string(&self) -> String {
+        let mut out = String::new();
+        self.write_disassembly(&mut out);
+        out
+    }
+
+    fn write_disassembly(&self, out: &mut String) {
+        let mut previous_line = None;
+        for (i, inst, line) in self.iter() {
+            self.write_instruction(out, i, inst, line, previous_line);
+            previous_line = Some(line);
+        }
+
+        for constant in &self.constants {
+            if let Value::Function(ref function) = *constant {
+                let name = function.name.as_ref().map_or("script", |n| n);
+                writeln!(out, "== {} ==", name).unwrap();
+                function.chunk.write_disassembly(out);
+            }
         }
     }
+
+    // Formats the instruction at `i` as a single line ending with a newline.
+    pub fn disassemble_instruction(&self, i: usize) -> String {
+        let mut out = String::new();
+        let previous_line = if i > 0 {
+            Some(self.line_at(i - 1))
+        } else {
+            None
+        };
+        self.write_instruction(
+            &mut out,
+            i,
+            &self.instructions[i],
+            self.line_at(i),
+            previous_line,
+        );
+        out
+    }
+
+    fn write_instruction(
+        &self,
+        out: &mut String,
+        i: usize,
+        inst: &Instruction,
+        line: usize,
+        previous_line: Option<usize>,
+    ) {
+        write!(out, "{:04} ", i).unwrap();
+        if previous_line == Some(line) {
+            write!(out, "   | ").unwrap();
+        } else {
+            write!(out, "{:4} ", line).unwrap();
+        }
+
+        match inst.operands().first() {
+            None => writeln!(out, "{}", inst.name()).unwrap(),
+            Some(operand) => {
+                write!(out, "{:<16} {:4}", inst.name(), operand).unwrap();
+                if let Some(c) = inst.constant_operand() {
+                    write!(out, " '{}'", self.read_constant(c)).unwrap();
+                }
+                if let Instruction::Invoke(_, arg_count) = *inst {
+                    write!(out, " ({} args)", arg_count).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use interner::Str;
+    use std::collections::HashSet;
+
+    #[test]
+    fn iter_pairs_instructions_with_lines() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Pop, 1);
+        chunk.add_instruction(Instruction::Return, 3);
+
+        let items: Vec<(usize, &Instruction, usize)> = chunk.iter().collect();
+        assert_eq!(
+            vec![
+                (0, &Instruction::Nil, 1),
+                (1, &Instruction::Pop, 1),
+                (2, &Instruction::Return, 3),
+            ],
+            items
+        );
+        assert_eq!(0, Chunk::new().iter().count());
+    }
+
+    #[test]
+    fn len_and_next_index() {
+        let mut chunk = Chunk::new();
+        assert!(chunk.is_empty());
+        assert_eq!(0, chunk.len());
+        assert_eq!(0, chunk.next_index());
+
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Return, 1);
+        assert!(!chunk.is_empty());
+        assert_eq!(2, chunk.len());
+        assert_eq!(2, chunk.next_index());
+
+        chunk.pop_instruction();
+        assert_eq!(1, chunk.len());
+        assert_eq!(1, chunk.next_index());
+    }
+
+    fn all_instructions() -> Vec<Instruction> {
+        use self::Instruction::*;
+
+        vec![
+            Return,
+            Constant(7),
+            Nil,
+            True,
+            False,
+            Duplicate,
+            Pop,
+            DefineGlobal(7),
+            GetGlobal(7),
+            SetGlobal(7),
+            GetLocal(7),
+            SetLocal(7),
+            Negate,
+            Not,
+            Add,
+            Subtract,
+            Multiply,
+            Divide,
+            Equal,
+            Greater,
+            Less,
+            Print,
+            Jump(7),
+            JumpIfFalse(7),
+            Loop(7),
+            Call(7),
+            Power,
+            Class(7),
+            GetProperty(7),
+            SetProperty(7),
+            Method(7),
+            BuildList(7),
+            GetIndex,
+            SetIndex,
+            BuildMap(7),
+            Modulo,
+            PopN(7),
+            Invoke(7, 2),
+        ]
+    }
+
+    #[test]
+    fn opcodes_round_trip() {
+        for instruction in all_instructions() {
+            let opcode = instruction.opcode();
+            let operands = instruction.operands();
+
+            assert_eq!(Some(operands.len()), Instruction::operand_count_of(opcode));
+            assert_eq!(
+                Some(instruction.clone()),
+                Instruction::from_opcode_and_operands(opcode, &operands)
+            );
+        }
+    }
+
+    #[test]
+    fn opcodes_are_unique() {
+        let instructions = all_instructions();
+        let opcodes: HashSet<u8> = instructions.iter().map(|i| i.opcode()).collect();
+
+        assert_eq!(instructions.len(), opcodes.len());
+        assert_eq!(
+            None,
+            Instruction::operand_count_of(instructions.len() as u8)
+        );
+        assert_eq!(None, Instruction::from_opcode_and_operands(0, &[1]));
+    }
+
+    #[test]
+    fn pop_instruction_drops_its_line() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Nil, 2);
+        chunk.add_instruction(Instruction::Pop, 2);
+
+        assert_eq!(Some(Instruction::Pop), chunk.pop_instruction());
+        assert_eq!(vec![(1, 1), (2, 1)], chunk.lines);
+        assert_eq!(Some(Instruction::Nil), chunk.pop_instruction());
+        assert_eq!(vec![(1, 1)], chunk.lines);
+        chunk.pop_instruction();
+        assert_eq!(None, chunk.pop_instruction());
+        assert!(chunk.lines.is_empty());
+    }
+
+    #[test]
+    fn lines_are_run_length_encoded() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Pop, 2);
+        chunk.add_instruction(Instruction::Nil, 4);
+        chunk.add_instruction(Instruction::Nil, 4);
+        chunk.add_instruction(Instruction::Return, 4);
+
+        assert_eq!(vec![(1, 2), (2, 1), (4, 3)], chunk.lines);
+        let lines: Vec<usize> = (0..chunk.len()).map(|i| chunk.line_at(i)).collect();
+        assert_eq!(vec![1, 1, 2, 4, 4, 4], lines);
+    }
+
+    #[test]
+    fn equal_constants_are_shared() {
+        let mut chunk = Chunk::new();
+
+        assert_eq!(0, chunk.add_constant(Value::Double(1.0)));
+        assert_eq!(1, chunk.add_constant(Value::Str(Str::from("a"))));
+        assert_eq!(0, chunk.add_constant(Value::Double(1.0)));
+        assert_eq!(1, chunk.add_constant(Value::Str(Str::from("a"))));
+        assert_eq!(2, chunk.add_constant(Value::Double(-0.0)));
+        assert_eq!(3, chunk.add_constant(Value::Double(0.0)));
+        assert_eq!(4, chunk.constants.len());
+    }
+
+    #[test]
+    fn disassemble_instruction() {
+        let mut chunk = Chunk::new();
+        let a = chunk.add_constant(Value::Double(1.0));
+        let b = chunk.add_constant(Value::Double(2.0));
+        chunk.add_instruction(Instruction::Constant(a), 3);
+        chunk.add_instruction(Instruction::Constant(b), 3);
+
+        assert_eq!(
+            "0000    3 OP_CONSTANT         0 '1'\n",
+            chunk.disassemble_instruction(0)
+        );
+        assert_eq!(
+            "0001    | OP_CONSTANT         1 '2'\n",
+            chunk.disassemble_instruction(1)
+        );
+
+        let m = chunk.add_constant(Value::Str(Str::from("m")));
+        chunk.add_instruction(Instruction::Invoke(m, 2), 4);
+        assert_eq!(
+            "0002    4 OP_INVOKE           2 'm' (2 args)\n",
+            chunk.disassemble_instruction(2)
+        );
+    }
+
+    #[test]
+    fn operand_counts_match_the_opcodes() {
+        for instruction in all_instructions() {
+            assert_eq!(
+                Instruction::operand_count_of(instruction.opcode()),
+                Some(instruction.operand_count()),
+                "{}",
+                instruction.name()
+            );
+        }
+    }
+
+    #[test]
+    fn verify_accepts_operands_in_range() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(Instruction::Constant(c), 1);
+        chunk.add_instruction(Instruction::JumpIfFalse(3), 1);
+        chunk.add_instruction(Instruction::Loop(0), 1);
+        chunk.add_instruction(Instruction::Return, 1);
+
+        assert_eq!(Ok(()), chunk.verify());
+    }
+
+    #[test]
+    fn verify_rejects_constants_out_of_range() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Constant(99), 1);
+
+        let error = chunk.verify().unwrap_err();
+        assert_eq!(
+            VerifyError::ConstantOutOfRange {
+                at: 1,
+                name: "OP_CONSTANT",
+                index: 99,
+                count: 1,
+            },
+            error
+        );
+        assert_eq!(
+            "OP_CONSTANT at 0001 refers to constant 99, but the chunk has 1 constants",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn verify_rejects_names_that_are_not_strings() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(Instruction::GetGlobal(c), 1);
+
+        let error = chunk.verify().unwrap_err();
+        assert_eq!(
+            VerifyError::NotAName {
+                at: 0,
+                name: "OP_GET_GLOBAL",
+                index: 0,
+            },
+            error
+        );
+        assert_eq!(
+            "OP_GET_GLOBAL at 0000 refers to constant 0, which is not a name",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn verify_rejects_a_short_line_table() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Return, 1);
+        chunk.lines = vec![(1, 1)];
+
+        assert_eq!(
+            Err(VerifyError::LinesMismatch { lines: 1, len: 2 }),
+            chunk.verify()
+        );
+    }
+
+    #[test]
+    fn disassemble_to_string() {
+        let mut chunk = Chunk::new();
+        let a = chunk.add_constant(Value::Double(1.5));
+        let b = chunk.add_constant(Value::Str(Str::from("b")));
+        chunk.add_instruction(Instruction::Constant(a), 1);
+        chunk.add_instruction(Instruction::Constant(b), 1);
+        chunk.add_instruction(Instruction::Constant(a), 2);
+        chunk.add_instruction(Instruction::Jump(5), 2);
+        chunk.add_instruction(Instruction::Return, 3);
+
+        assert_eq!(
+            "0000    1 OP_CONSTANT         0 '1.5'\n\
+             0001    | OP_CONSTANT         1 'b'\n\
+             0002    2 OP_CONSTANT         0 '1.5'\n\
+             0003    | OP_JUMP             5\n\
+             0004    3 OP_RETURN\n",
+            chunk.disassemble_to_string()
+        );
+    }
 }
@@ -1,49 +1,174 @@
+use std::error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::iter;
+use std::mem;
+use std::rc::Rc;
+
 use common::*;
+use interner::Str;
+use optimizer::optimize;
 use scanner::*;
 use value::*;
 
-pub fn compile(source: &str) {
-    let chunk = compile_to_chunk(source);
+pub fn compile(source: &str) -> Result<Chunk, Vec<Error>> {
+    compile_in_mode(source, Mode::Script)
+}
+
+// Compiles a line typed into the REPL, where a trailing expression without a
+// semicolon has its value printed. Lines are numbered from `first_line`, the
+// line's position in the session.
+pub fn compile_repl_line(source: &str, first_line: usize) -> Result<Chunk, Vec<Error>> {
+    let scanner = Scanner::with_starting_line(source, first_line);
+    optimized(compile_scanned(scanner, Mode::Repl, true)?)
+}
+
+// Compiles a snippet whose trailing expression without a semicolon becomes
+// the script's return value.
+pub fn compile_eval(source: &str) -> Result<Chunk, Vec<Error>> {
+    compile_in_mode(source, Mode::Eval)
+}
+
+fn compile_in_mode(source: &str, mode: Mode) -> Result<Chunk, Vec<Error>> {
+    optimized(compile_chunk(source, mode, true)?)
+}
+
+fn optimized(mut chunk: Chunk) -> Result<Chunk, Vec<Error>> {
+    optimize(&mut chunk);
+    verify(chunk)
+}
+
+// Compiles without folding constants or running the optimizer, so the
+// bytecode follows the source. Only for checking what optimizations change.
+#[cfg(test)]
+fn compile_unoptimized(source: &str, mode: Mode) -> Result<Chunk, Vec<Error>> {
+    compile_chunk(source, mode, false).and_then(verify)
+}
+
+// Jump targets are computed by hand, so one out of range is a compiler bug.
+// It fails the compilation instead of sending the VM somewhere random.
+fn verify(chunk: Chunk) -> Result<Chunk, Vec<Error>> {
+    match chunk.verify() {
+        Ok(()) => Ok(chunk),
+        Err(error) => Err(vec![Error::internal(&error)]),
+    }
+}
 
-    chunk.disassemble();
+fn compile_chunk(source: &str, mode: Mode, fold_constants: bool) -> Result<Chunk, Vec<Error>> {
+    compile_scanned(Scanner::new(source), mode, fold_constants)
 }
 
-fn compile_to_chunk(source: &str) -> Chunk {
-    let scanner = Scanner::new(source);
-    let mut chunk = Chunk::new();
-    {
-        let mut compiler = Compiler::new(scanner, &mut chunk);
-        compiler.expression();
-        compiler.finish_compiler();
+fn compile_scanned(
+    scanner: Scanner,
+    mode: Mode,
+    fold_constants: bool,
+) -> Result<Chunk, Vec<Error>> {
+    let mut compiler = Compiler::new(scanner, mode);
+    compiler.fold_constants = fold_constants;
+    while !compiler.is_at_end() {
+        compiler.declaration();
     }
+    let script = compiler.end_function();
 
-    chunk
+    if compiler.errors.is_empty() {
+        Ok(script.chunk)
+    } else {
+        Err(compiler.errors)
+    }
 }
 
-pub struct Compiler<'a, 'b> {
+pub struct Compiler<'a> {
     scanner: Scanner<'a>,
-    current: Option<Token>,
-    previous: Option<Token>,
+    current: Option<Token<'a>>,
+    previous: Option<Token<'a>>,
     errors: Vec<Error>,
     panic_mode: bool,
-    chunk: &'b mut Chunk,
     last_token_line: usize,
+    mode: Mode,
+    fold_constants: bool,
+    // Whether the next statement is a whole top-level declaration, the only
+    // place a trailing expression may end the input.
+    top_level_statement: bool,
+    // The innermost function being compiled is the last one.
+    functions: Vec<FunctionCompiler<'a>>,
+}
+
+struct FunctionCompiler<'a> {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    // The innermost enclosing loop is the last one.
+    loops: Vec<Loop>,
+    // The furthest instruction a patched jump lands on.
+    last_jump_target: Option<usize>,
+}
+
+struct Loop {
+    // Where `continue` jumps back to: the increment of a `for`, or the
+    // condition otherwise.
+    start: usize,
+    // Locals deeper than this are popped before leaving the loop body.
+    scope_depth: usize,
+    // `break` jumps to be patched once the loop's exit is known.
+    break_jumps: Vec<usize>,
+}
+
+// What happens to a top-level expression ending the input without a
+// semicolon: an error in scripts, printed in the REPL, returned by `eval`.
+#[derive(PartialEq, Clone, Copy)]
+enum Mode {
+    Script,
+    Repl,
+    Eval,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionType {
+    Script,
+    Function,
+    Method,
+}
+
+// Names borrow from the source, like the tokens they come from.
+struct Local<'a> {
+    name: &'a str,
+    // `None` while the variable's initializer is being compiled.
+    depth: Option<usize>,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct Error {
     location: ErrorLocation,
     msg: String,
 }
 
+#[derive(Debug, PartialEq)]
 pub enum ErrorLocation {
-    Token(Token),
-    AtTheEnd,
+    Token {
+        line: usize,
+        column: usize,
+        lexeme: String,
+    },
+    // Scanner errors describe the offending text themselves.
+    ScannerError {
+        line: usize,
+        column: usize,
+    },
+    // The line is the one of the last token.
+    AtTheEnd {
+        line: usize,
+    },
+    // The compiler produced invalid bytecode.
+    Internal,
 }
 
 #[derive(PartialEq, Clone, PartialOrd)]
 enum Precedence {
     None,
+    Comma,
     Assignment,
+    Conditional,
     Or,
     And,
     Equality,
@@ -51,305 +176,2744 @@ enum Precedence {
     Term,
     Factor,
     Unary,
+    Power,
     Call,
     Primary,
 }
 
-impl<'a, 'b> Compiler<'a, 'b> {
-    fn new(mut scanner: Scanner<'a>, chunk: &'b mut Chunk) -> Compiler<'a, 'b> {
-        let current = scanner.next();
-        Compiler {
+impl<'a> FunctionCompiler<'a> {
+    fn new(name: Option<String>, function_type: FunctionType) -> FunctionCompiler<'a> {
+        // Slot 0 holds the function being called, or the receiver in methods.
+        let slot_zero = match function_type {
+            FunctionType::Method => "this",
+            _ => "",
+        };
+        FunctionCompiler {
+            function: Function::new(name),
+            function_type,
+            locals: vec![Local {
+                name: slot_zero,
+                depth: Some(0),
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+            last_jump_target: None,
+        }
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(scanner: Scanner<'a>, mode: Mode) -> Compiler<'a> {
+        let mut compiler = Compiler {
             scanner,
-            current,
+            current: None,
             previous: None,
             errors: Vec::new(),
             panic_mode: false,
-            chunk,
             last_token_line: 0,
-        }
+            mode,
+            fold_constants: false,
+            top_level_statement: false,
+            functions: vec![FunctionCompiler::new(None, FunctionType::Script)],
+        };
+        compiler.advance();
+        compiler
     }
 
-    fn finish_compiler(&mut self) {
+    fn end_function(&mut self) -> Function {
+        self.emit_instruction_for_last_token(Instruction::Nil);
         self.emit_instruction_for_last_token(Instruction::Return);
+
+        self.functions
+            .pop()
+            .expect("Function compiler stack is empty")
+            .function
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    fn current_function(&mut self) -> &mut FunctionCompiler<'a> {
+        self.functions
+            .last_mut()
+            .expect("Function compiler stack is empty")
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) {
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current_function().function.chunk
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.top_level_statement =
+                self.functions.len() == 1 && self.current_function().scope_depth == 0;
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        let global = self.parse_variable("Expect class name");
+        let name_token = self.previous().expect("A token was just consumed");
+        let name = match name_token.t_type {
+            TokenType::Identifier(name) => name,
+            _ => return,
+        };
+        let name_constant = self.identifier_constant(name);
+        self.emit_instruction(Instruction::Class(name_constant), &name_token);
+        self.define_variable(global);
+
+        // The class stays on the stack while its methods are attached to it.
+        self.variable(name, false, &name_token);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body");
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body");
+        self.emit_instruction_for_last_token(Instruction::Pop);
+    }
+
+    fn method(&mut self) {
+        let name = match self.current() {
+            Some(Token {
+                t_type: TokenType::Identifier(name),
+                ..
+            }) => name,
+            _ => {
+                self.error_at_current("Expect method name");
+                self.advance();
+                return;
+            }
+        };
         self.advance();
+        let constant = self.identifier_constant(name);
+        self.function(name, FunctionType::Method);
+        self.emit_instruction_for_last_token(Instruction::Method(constant));
+    }
 
-        if let Some(token) = self.previous() {
-            self.prefix_rule(&token);
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name");
+        let name = match self.previous() {
+            Some(Token {
+                t_type: TokenType::Identifier(name),
+                ..
+            }) => name,
+            _ => "",
+        };
+        self.mark_initialized();
+        self.function(name, FunctionType::Function);
+        self.define_variable(global);
+    }
 
-            while let Some(current_token) = self.current() {
-                if current_token.t_type.precedence() < precedence {
+    fn function(&mut self, name: &str, function_type: FunctionType) {
+        self.functions
+            .push(FunctionCompiler::new(Some(name.to_string()), function_type));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.current_function().function.arity += 1;
+                let param = self.parse_variable("Expect parameter name");
+                self.define_variable(param);
+                // A trailing comma is allowed.
+                if !self.match_token(TokenType::Comma) || self.check(&TokenType::RightParen) {
                     break;
                 }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+        self.block();
+
+        let function = self.end_function();
+        let constant = self.make_constant(Value::Function(Rc::new(function)));
+        self.emit_instruction_for_last_token(Instruction::Constant(constant));
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_instruction_for_last_token(Instruction::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration",
+        );
 
+        self.define_variable(global);
+    }
+
+    // Returns the name constant for a global, or 0 for a local, which lives
+    // on the stack and needs no name at runtime.
+    fn parse_variable(&mut self, error_msg: &'static str) -> usize {
+        if let Some(token) = self.current() {
+            if let TokenType::Identifier(name) = token.t_type {
                 self.advance();
-                let previous = self.previous().unwrap();
-                self.infix_rule(&previous);
+                if self.current_function().scope_depth > 0 {
+                    self.declare_local(name, &token);
+                    return 0;
+                }
+                return self.identifier_constant(name);
             }
         }
+        self.error_at_current(error_msg);
+        0
     }
 
-    fn prefix_rule(&mut self, token: &Token) {
-        use scanner::TokenType::*;
-        match token.t_type {
-            LeftParen => self.grouping(),
-            Minus => self.unary(token),
-            Number(d) => self.number(d, token),
-            _ => self.error("Expect expression", token),
+    fn declare_local(&mut self, name: &'a str, token: &Token) {
+        let scope_depth = self.current_function().scope_depth;
+        let already_declared = self
+            .current_function()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.map_or(true, |d| d >= scope_depth))
+            .any(|l| l.name == name);
+        if already_declared {
+            self.error("Already a variable with this name in this scope", token);
         }
+
+        self.current_function()
+            .locals
+            .push(Local { name, depth: None });
     }
 
-    fn infix_rule(&mut self, token: &Token) {
-        use scanner::TokenType::*;
-        match token.t_type {
-            Minus => self.binary(token),
-            Plus => self.binary(token),
-            Star => self.binary(token),
-            Slash => self.binary(token),
-            _ => panic!(
-                "Can't invoke infix rule on this token type: {:?}",
-                token.t_type
-            ),
+    fn define_variable(&mut self, global: usize) {
+        if self.current_function().scope_depth > 0 {
+            self.mark_initialized();
+            return;
         }
+
+        self.emit_instruction_for_last_token(Instruction::DefineGlobal(global));
     }
 
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(
-            TokenType::RightParen,
-            "Expect to have ')' at the end of grouping expression",
-        );
+    fn mark_initialized(&mut self) {
+        let function = self.current_function();
+        if function.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = function.locals.last_mut() {
+            local.depth = Some(function.scope_depth);
+        }
     }
 
-    fn unary(&mut self, op_token: &Token) {
-        use scanner::TokenType::*;
+    fn resolve_local(&mut self, name: &str, token: &Token) -> Option<usize> {
+        let found = self
+            .current_function()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, l)| l.name == name)
+            .map(|(slot, l)| (slot, l.depth.is_none()));
+
+        match found {
+            Some((slot, uninitialized)) => {
+                if uninitialized {
+                    self.error("Can't read local variable in its own initializer", token);
+                }
+                Some(slot)
+            }
+            None => None,
+        }
+    }
 
-        self.expression();
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.make_constant(Value::Str(Str::from(name)))
+    }
 
-        match op_token.t_type {
-            Minus => self.emit_instruction(Instruction::Negate, op_token),
-            _ => panic!(
-                "Can not invoke 'unary' for token type: {:?}",
-                op_token.t_type
-            ),
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.chunk().add_constant(value);
+        if constant >= MAX_CONSTANTS {
+            match self.previous() {
+                Some(token) => self.error("Too many constants in one chunk", &token),
+                None => self.error_at_the_end("Too many constants in one chunk"),
+            }
+            return 0;
         }
+        constant
     }
 
-    fn number(&mut self, number_val: f64, token: &Token) {
-        let constant = self.chunk.add_constant(Value::Double(number_val));
-        self.emit_instruction(Instruction::Constant(constant), token);
+    fn statement(&mut self) {
+        // Statements nested in this one are never top-level.
+        let top_level = mem::replace(&mut self.top_level_statement, false);
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(top_level);
+        }
     }
 
-    fn binary(&mut self, token: &Token) {
-        use scanner::TokenType::*;
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value");
+        self.emit_instruction_for_last_token(Instruction::Print);
+    }
 
-        let op_type = &token.t_type;
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
 
-        self.parse_precedence(op_type.precedence().next());
+        let then_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        self.emit_instruction_for_last_token(Instruction::Pop);
+        self.statement();
 
-        match op_type {
-            Plus => self.emit_instruction_for_last_token(Instruction::Add),
-            Minus => self.emit_instruction_for_last_token(Instruction::Subtract),
-            Star => self.emit_instruction_for_last_token(Instruction::Multiply),
-            Slash => self.emit_instruction_for_last_token(Instruction::Divide),
-            _ => panic!("Can not invoke 'binary' for token type: {:?}", op_type),
+        let else_jump = self.emit_jump(Instruction::Jump(0));
+        self.patch_jump(then_jump);
+        self.emit_instruction_for_last_token(Instruction::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
         }
+        self.patch_jump(else_jump);
     }
 
-    fn emit_instruction(&mut self, instruction: Instruction, token: &Token) {
-        self.chunk.add_instruction(instruction, token.line);
+    fn return_statement(&mut self) {
+        if self.current_function().function_type == FunctionType::Script {
+            let token = self.previous().expect("'return' token was just consumed");
+            self.error("Can't return from top-level code", &token);
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_instruction_for_last_token(Instruction::Nil);
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value");
+        }
+        self.emit_instruction_for_last_token(Instruction::Return);
     }
 
-    fn emit_instruction_for_last_token(&mut self, instruction: Instruction) {
-        self.chunk
-            .add_instruction(instruction, self.last_token_line);
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().next_index();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
+
+        let exit_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        self.emit_instruction_for_last_token(Instruction::Pop);
+        self.loop_body(loop_start);
+        self.emit_instruction_for_last_token(Instruction::Loop(loop_start));
+
+        self.patch_jump(exit_jump);
+        self.emit_instruction_for_last_token(Instruction::Pop);
+        self.patch_break_jumps();
     }
 
-    fn consume(&mut self, t_type: TokenType, error_msg: &'static str) {
-        if let Some(current) = self.current() {
-            if current.t_type == t_type {
-                self.advance();
-            } else {
-                self.error(error_msg, &current);
-            }
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
         } else {
-            self.error_at_the_end(error_msg);
+            self.expression_statement(false);
         }
-    }
 
-    fn advance(&mut self) {
-        self.previous = self.current.clone();
+        let mut loop_start = self.chunk().next_index();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
 
-        if let Some(ref t) = self.previous {
-            self.last_token_line = t.line;
+            exit_jump = Some(self.emit_jump(Instruction::JumpIfFalse(0)));
+            self.emit_instruction_for_last_token(Instruction::Pop);
         }
 
-        loop {
-            self.current = self.scanner.next();
-            if let Some(t) = self.current() {
-                match t.t_type {
-                    TokenType::Error(e) => self.error(e, &t),
-                    _ => break,
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(Instruction::Jump(0));
+            let increment_start = self.chunk().next_index();
+            self.expression();
+            self.emit_instruction_for_last_token(Instruction::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses");
+
+            self.emit_instruction_for_last_token(Instruction::Loop(loop_start));
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(loop_start);
+        self.emit_instruction_for_last_token(Instruction::Loop(loop_start));
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_instruction_for_last_token(Instruction::Pop);
+        }
+        self.patch_break_jumps();
+
+        self.end_scope();
+    }
+
+    // The switch value stays on the stack as a nameless local while the cases
+    // compare against it. A matching case runs its statements in their own
+    // scope and jumps to the end; there is no fallthrough.
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases");
+
+        self.begin_scope();
+        let scope_depth = self.current_function().scope_depth;
+        self.current_function().locals.push(Local {
+            name: "",
+            depth: Some(scope_depth),
+        });
+
+        let mut exit_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            if self.match_token(TokenType::Case) {
+                if has_default {
+                    let token = self.previous().expect("'case' token was just consumed");
+                    self.error("Can't have a case after the default case", &token);
                 }
+                self.emit_instruction_for_last_token(Instruction::Duplicate);
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case value");
+                self.emit_instruction_for_last_token(Instruction::Equal);
+                let next_case = self.emit_jump(Instruction::JumpIfFalse(0));
+                self.emit_instruction_for_last_token(Instruction::Pop);
+                self.case_body();
+                exit_jumps.push(self.emit_jump(Instruction::Jump(0)));
+                self.patch_jump(next_case);
+                self.emit_instruction_for_last_token(Instruction::Pop);
+            } else if self.match_token(TokenType::Default) {
+                if has_default {
+                    let token = self.previous().expect("'default' token was just consumed");
+                    self.error("Can't have more than one default case", &token);
+                }
+                has_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'");
+                self.case_body();
             } else {
-                break;
+                self.error_at_current("Expect 'case' or 'default'");
+                self.advance();
             }
         }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases");
+
+        for jump in exit_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
     }
 
-    fn error(&mut self, error_msg: &'static str, token: &Token) {
-        if self.panic_mode {
-            return;
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(&TokenType::Case)
+            && !self.check(&TokenType::Default)
+            && !self.check(&TokenType::RightBrace)
+            && !self.is_at_end()
+        {
+            self.declaration();
         }
+        self.end_scope();
+    }
 
-        self.panic_mode = true;
+    fn loop_body(&mut self, start: usize) {
+        let scope_depth = self.current_function().scope_depth;
+        self.current_function().loops.push(Loop {
+            start,
+            scope_depth,
+            break_jumps: Vec::new(),
+        });
+        self.statement();
+    }
 
-        self.errors.push(Error::new(token.clone(), error_msg));
+    // Must follow the loop's exit `Pop`, since the condition is already
+    // popped when the body runs.
+    fn patch_break_jumps(&mut self) {
+        let innermost = self
+            .current_function()
+            .loops
+            .pop()
+            .expect("Loop stack is empty");
+        for jump in innermost.break_jumps {
+            self.patch_jump(jump);
+        }
     }
 
-    fn error_at_the_end(&mut self, error_msg: &'static str) {
-        if self.panic_mode {
+    fn break_statement(&mut self) {
+        let token = self.previous().expect("'break' token was just consumed");
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'");
+        if self.current_function().loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop", &token);
             return;
         }
 
-        self.panic_mode = true;
-
-        self.errors.push(Error::new_at_the_end(error_msg));
+        self.pop_loop_locals();
+        let jump = self.emit_jump(Instruction::Jump(0));
+        if let Some(innermost) = self.current_function().loops.last_mut() {
+            innermost.break_jumps.push(jump);
+        }
     }
 
-    fn previous(&self) -> Option<Token> {
-        self.previous.clone()
+    fn continue_statement(&mut self) {
+        let token = self.previous().expect("'continue' token was just consumed");
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'");
+        let start = match self.current_function().loops.last() {
+            Some(innermost) => innermost.start,
+            None => {
+                self.error("Can't use 'continue' outside of a loop", &token);
+                return;
+            }
+        };
+
+        self.pop_loop_locals();
+        self.emit_instruction_for_last_token(Instruction::Loop(start));
     }
 
-    fn current(&self) -> Option<Token> {
-        self.current.clone()
+    // Pops the locals declared inside the innermost loop's body without
+    // forgetting them, as the code after `break` or `continue` still sees them.
+    fn pop_loop_locals(&mut self) {
+        let function = self.current_function();
+        let loop_depth = match function.loops.last() {
+            Some(innermost) => innermost.scope_depth,
+            None => return,
+        };
+        let count = function
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.map_or(true, |d| d > loop_depth))
+            .count();
+        self.emit_pops(count);
     }
-}
 
-impl Error {
-    fn new(token: Token, msg: &'static str) -> Error {
-        Error {
-            location: ErrorLocation::Token(token),
-            msg: msg.to_string(),
+    // Pops `count` values, with a single instruction however many there are.
+    fn emit_pops(&mut self, count: usize) {
+        match count {
+            0 => {}
+            1 => self.emit_instruction_for_last_token(Instruction::Pop),
+            n => self.emit_instruction_for_last_token(Instruction::PopN(n)),
         }
     }
 
-    fn new_at_the_end(msg: &'static str) -> Error {
-        Error {
-            location: ErrorLocation::AtTheEnd,
-            msg: msg.to_string(),
-        }
+    fn begin_scope(&mut self) {
+        self.current_function().scope_depth += 1;
     }
-}
 
-impl Precedence {
-    fn next(&self) -> Precedence {
-        use self::Precedence::*;
-        match self {
-            None => Assignment,
-            Assignment => Or,
-            Or => And,
-            And => Equality,
-            Equality => Comparison,
-            Comparison => Term,
-            Term => Factor,
-            Factor => Unary,
-            Unary => Call,
-            Call => Primary,
-            Primary => Primary,
+    fn end_scope(&mut self) {
+        self.current_function().scope_depth -= 1;
+
+        let function = self.current_function();
+        let scope_depth = function.scope_depth;
+        let count = function
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.map_or(true, |d| d > scope_depth))
+            .count();
+        let remaining = function.locals.len() - count;
+        function.locals.truncate(remaining);
+        self.emit_pops(count);
+    }
+
+    fn block(&mut self) {
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            self.declaration();
         }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block");
     }
-}
 
-trait ParseRule {
-    fn precedence(&self) -> Precedence;
-}
+    fn expression_statement(&mut self, top_level: bool) {
+        self.expression();
+        if top_level && self.mode != Mode::Script && self.is_at_end() {
+            match self.mode {
+                Mode::Repl => self.emit_instruction_for_last_token(Instruction::Print),
+                Mode::Eval => self.emit_instruction_for_last_token(Instruction::Return),
+                Mode::Script => unreachable!(),
+            }
+            return;
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after expression");
+        self.emit_instruction_for_last_token(Instruction::Pop);
+    }
 
-impl ParseRule for TokenType {
-    fn precedence(&self) -> Precedence {
-        use self::Precedence::*;
+    fn synchronize(&mut self) {
         use scanner::TokenType::*;
-        match self {
-            LeftParen => Call,
-            Dot => Call,
-            Minus => Term,
-            Plus => Term,
-            Slash => Factor,
-            Star => Factor,
-            BangEqual => Equality,
-            EqualEqual => Equality,
-            Greater => Comparison,
-            GreaterEqual => Comparison,
-            Less => Comparison,
-            LessEqual => Comparison,
-            TokenType::And => Precedence::And,
-            TokenType::Or => Precedence::Or,
-            _ => Precedence::None,
+
+        self.panic_mode = false;
+
+        while let Some(current) = self.current() {
+            if let Some(Token {
+                t_type: Semicolon, ..
+            }) = self.previous
+            {
+                return;
+            }
+            match current.t_type {
+                Class | Fun | Var | For | If | While | Print | Return | Break | Continue
+                | Switch => return,
+                _ => self.advance(),
+            }
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use self::super::*;
-    use common::Instruction::*;
 
-    #[test]
-    fn simple_binary() {
-        check_binary(1.0, 2.0, '+');
-        check_binary(40.0, 32323.12, '-');
-        check_binary(2132.0, 332.0, '/');
-        check_binary(323.323, 0.32, '*');
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Comma);
     }
 
-    #[test]
-    fn binary_assoc() {
-        check_binary_assoc(1.1, 3.2, 4.2, '+');
-        check_binary_assoc(21.3, 23.1, 3.323, '-');
-        check_binary_assoc(323.21, 3244.0, 3656.2, '*');
-        check_binary_assoc(0.324, 345.1, 45.4, '/');
+    // An expression without the comma operator, for places where commas
+    // separate expressions.
+    fn assignment(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
     }
 
-    #[test]
-    fn precedences() {
-        check(
-            "1 - 2 * 3",
-            vec![c(0), c(1), c(2), Multiply, Subtract],
-            vec![1.0, 2.0, 3.0],
-        );
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
 
-        check(
-            "1 + 4 / 2",
-            vec![c(0), c(1), c(2), Divide, Add],
-            vec![1.0, 4.0, 2.0],
-        );
+        if let Some(token) = self.previous() {
+            let can_assign = precedence <= Precedence::Assignment;
+            let rule = parse_rule(&token.t_type);
+            match rule.prefix {
+                Some(prefix) => prefix(self, &token, can_assign),
+                // An operator missing its left operand.
+                None if rule.infix.is_some() => {
+                    let msg = format!("Expect expression before '{}'", token.lexeme);
+                    self.error(&msg, &token);
+                }
+                None => self.error("Expect expression", &token),
+            }
 
-        check(
-            "2 * 3 + 4 / 5",
-            vec![c(0), c(1), Multiply, c(2), c(3), Divide, Add],
-            vec![2.0, 3.0, 4.0, 5.0],
-        );
+            while let Some(current_token) = self.current() {
+                if parse_rule(&current_token.t_type).precedence < precedence {
+                    break;
+                }
+
+                self.advance();
+                let previous = self.previous().unwrap();
+                // Every token binding tighter than `Precedence::None` has an
+                // infix rule.
+                let infix = parse_rule(&previous.t_type)
+                    .infix
+                    .expect("Token with a precedence has no infix rule");
+                infix(self, &previous, can_assign);
+            }
+
+            if can_assign && self.is_assignment_operator() {
+                self.error_at_current("Invalid assignment target");
+            }
+        }
+    }
+
+    fn is_assignment_operator(&self) -> bool {
+        self.check(&TokenType::Equal)
+            || self.check(&TokenType::QuestionQuestionEqual)
+            || self
+                .current()
+                .is_some_and(|token| compound_operator(&token.t_type).is_some())
+    }
+
+    // Consumes a `+=`-like token if assignment is allowed here, returning the
+    // arithmetic it stands for.
+    fn match_compound_operator(&mut self, can_assign: bool) -> Option<Instruction> {
+        if !can_assign {
+            return None;
+        }
+        let operator = compound_operator(&self.current()?.t_type)?;
+        self.advance();
+        Some(operator)
+    }
+
+    fn grouping(&mut self, open_paren: &Token) {
+        self.expression();
+        // Running out of input is reported where the group starts, which is
+        // more helpful than the end of the source.
+        if self.is_at_end() {
+            self.error("Unmatched '(', expect ')' to close it", open_paren);
+            return;
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expect to have ')' at the end of grouping expression",
+        );
+    }
+
+    fn unary(&mut self, op_token: &Token) {
+        use scanner::TokenType::*;
+
+        self.parse_precedence(Precedence::Unary);
+
+        match op_token.t_type {
+            Minus => self.emit_instruction(Instruction::Negate, op_token),
+            Bang => self.emit_instruction(Instruction::Not, op_token),
+            _ => panic!(
+                "Can not invoke 'unary' for token type: {:?}",
+                op_token.t_type
+            ),
+        }
+    }
+
+    // Lox has no unary plus. The operand is still parsed so that the error
+    // points at the `+` rather than at whatever follows it.
+    fn unary_plus(&mut self, token: &Token) {
+        self.error("Unary '+' is not supported", token);
+        self.parse_precedence(Precedence::Unary);
+    }
+
+    // Without closures a function nested in a method can't reach the
+    // method's receiver, so `this` is only valid directly inside a method.
+    fn this(&mut self, token: &Token) {
+        if self.current_function().function_type != FunctionType::Method {
+            self.error("Can't use 'this' outside of a method", token);
+            return;
+        }
+        self.variable("this", false, token);
+    }
+
+    fn number(&mut self, number_val: Value, token: &Token) {
+        let constant = self.make_constant(number_val);
+        self.emit_instruction(Instruction::Constant(constant), token);
+    }
+
+    fn string(&mut self, s: &str, token: &Token) {
+        let constant = self.make_constant(Value::Str(Str::from(s)));
+        self.emit_instruction(Instruction::Constant(constant), token);
+    }
+
+    fn variable(&mut self, name: &str, can_assign: bool, token: &Token) {
+        let (get_op, set_op) = match self.resolve_local(name, token) {
+            Some(slot) => (Instruction::GetLocal(slot), Instruction::SetLocal(slot)),
+            None => {
+                let global = self.identifier_constant(name);
+                (
+                    Instruction::GetGlobal(global),
+                    Instruction::SetGlobal(global),
+                )
+            }
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.assignment();
+            self.emit_instruction(set_op, token);
+        } else if can_assign && self.match_token(TokenType::QuestionQuestionEqual) {
+            self.nil_coalescing_assignment(get_op, set_op, token);
+        } else if let Some(operator) = self.match_compound_operator(can_assign) {
+            // `x += e` is `x = x + e`, reading `x` only once.
+            self.emit_instruction(get_op, token);
+            self.assignment();
+            self.emit_instruction(operator, token);
+            self.emit_instruction(set_op, token);
+        } else if let Some(operator) = self.match_increment() {
+            // `x++` produces the value `x` had before.
+            self.emit_instruction(get_op, token);
+            self.emit_instruction(Instruction::Duplicate, token);
+            self.number(Value::Int(1), token);
+            self.emit_instruction(operator, token);
+            self.emit_instruction(set_op, token);
+            self.emit_instruction(Instruction::Pop, token);
+        } else {
+            self.emit_instruction(get_op, token);
+        }
+    }
+
+    // Consumes a postfix `++` or `--`, returning the arithmetic it stands for.
+    fn match_increment(&mut self) -> Option<Instruction> {
+        let operator = match self.current()?.t_type {
+            TokenType::PlusPlus => Instruction::Add,
+            TokenType::MinusMinus => Instruction::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(operator)
+    }
+
+    // `++x` is `x += 1`. The operand is compiled as usual and must come out
+    // as the single read of a variable, which is then turned into the update.
+    fn prefix_increment(&mut self, op_token: &Token) {
+        let start = self.chunk().len();
+        self.parse_precedence(Precedence::Unary);
+
+        let is_variable = matches!(
+            self.previous(),
+            Some(Token {
+                t_type: TokenType::Identifier(_),
+                ..
+            })
+        );
+        let emitted_one = self.chunk().len() == start + 1;
+        let set_op = match self.chunk().instructions.last() {
+            _ if !is_variable || !emitted_one => None,
+            Some(&Instruction::GetLocal(slot)) => Some(Instruction::SetLocal(slot)),
+            Some(&Instruction::GetGlobal(global)) => Some(Instruction::SetGlobal(global)),
+            _ => None,
+        };
+        let set_op = match set_op {
+            Some(set_op) => set_op,
+            None => {
+                self.error("Invalid assignment target", op_token);
+                return;
+            }
+        };
+
+        self.number(Value::Int(1), op_token);
+        match op_token.t_type {
+            TokenType::PlusPlus => self.emit_instruction(Instruction::Add, op_token),
+            _ => self.emit_instruction(Instruction::Subtract, op_token),
+        }
+        self.emit_instruction(set_op, op_token);
+    }
+
+    // `x ??= e` evaluates and assigns `e` only if `x` is nil. Either way the
+    // expression produces the resulting value of `x`.
+    fn nil_coalescing_assignment(
+        &mut self,
+        get_op: Instruction,
+        set_op: Instruction,
+        token: &Token,
+    ) {
+        self.emit_instruction(get_op.clone(), token);
+        self.emit_instruction(Instruction::Nil, token);
+        self.emit_instruction(Instruction::Equal, token);
+        let not_nil_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        self.emit_instruction(Instruction::Pop, token);
+
+        self.assignment();
+        self.emit_instruction(set_op, token);
+        let end_jump = self.emit_jump(Instruction::Jump(0));
+
+        self.patch_jump(not_nil_jump);
+        self.emit_instruction(Instruction::Pop, token);
+        self.emit_instruction(get_op, token);
+        self.patch_jump(end_jump);
+    }
+
+    fn list(&mut self, token: &Token) {
+        let mut element_count = 0;
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                self.assignment();
+                element_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements");
+        self.emit_instruction(Instruction::BuildList(element_count), token);
+    }
+
+    // `{ key: value, ... }`, keys being any expressions evaluating to strings.
+    fn map(&mut self, token: &Token) {
+        let mut entry_count = 0;
+        if !self.check(&TokenType::RightBrace) {
+            loop {
+                self.assignment();
+                self.consume(TokenType::Colon, "Expect ':' after map key");
+                self.assignment();
+                entry_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries");
+        self.emit_instruction(Instruction::BuildMap(entry_count), token);
+    }
+
+    fn index(&mut self, token: &Token, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.assignment();
+            self.emit_instruction(Instruction::SetIndex, token);
+        } else {
+            self.emit_instruction(Instruction::GetIndex, token);
+        }
+    }
+
+    fn dot(&mut self, token: &Token, can_assign: bool) {
+        let name = match self.current() {
+            Some(Token {
+                t_type: TokenType::Identifier(name),
+                ..
+            }) => name,
+            _ => {
+                self.error_at_current("Expect property name after '.'");
+                return;
+            }
+        };
+        self.advance();
+        let constant = self.identifier_constant(name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.assignment();
+            self.emit_instruction(Instruction::SetProperty(constant), token);
+        } else if let Some(operator) = self.match_compound_operator(can_assign) {
+            // The receiver is kept for the `SetProperty`.
+            self.emit_instruction(Instruction::Duplicate, token);
+            self.emit_instruction(Instruction::GetProperty(constant), token);
+            self.assignment();
+            self.emit_instruction(operator, token);
+            self.emit_instruction(Instruction::SetProperty(constant), token);
+        } else if self.match_token(TokenType::LeftParen) {
+            // Calls the method without making a bound method first.
+            let arg_count = self.argument_list();
+            self.emit_instruction(Instruction::Invoke(constant, arg_count), token);
+        } else {
+            self.emit_instruction(Instruction::GetProperty(constant), token);
+        }
+    }
+
+    fn call(&mut self, token: &Token) {
+        let arg_count = self.argument_list();
+        self.emit_instruction(Instruction::Call(arg_count), token);
+    }
+
+    fn argument_list(&mut self) -> usize {
+        let mut arg_count = 0;
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.assignment();
+                arg_count += 1;
+                // A trailing comma is allowed.
+                if !self.match_token(TokenType::Comma) || self.check(&TokenType::RightParen) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+        arg_count
+    }
+
+    // `a, b` evaluates both and yields `b`.
+    fn comma(&mut self, token: &Token) {
+        self.emit_instruction(Instruction::Pop, token);
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn and(&mut self, token: &Token) {
+        let end_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        self.emit_instruction(Instruction::Pop, token);
+
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    // `condition ? then : else`, where the else branch is right associative.
+    fn conditional(&mut self, token: &Token) {
+        let else_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        self.emit_instruction(Instruction::Pop, token);
+        self.expression();
+
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression",
+        );
+        let end_jump = self.emit_jump(Instruction::Jump(0));
+
+        self.patch_jump(else_jump);
+        self.emit_instruction(Instruction::Pop, token);
+        self.parse_precedence(Precedence::Conditional);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, token: &Token) {
+        let else_jump = self.emit_jump(Instruction::JumpIfFalse(0));
+        let end_jump = self.emit_jump(Instruction::Jump(0));
+
+        self.patch_jump(else_jump);
+        self.emit_instruction(Instruction::Pop, token);
+
+        self.parse_precedence(Precedence::Or);
+
+        self.patch_jump(end_jump);
+    }
+
+    // Right-associative, so the right operand is parsed at the same
+    // precedence and may itself be a power.
+    fn power(&mut self, token: &Token) {
+        self.parse_precedence(Precedence::Power);
+        self.emit_arithmetic(Instruction::Power, token);
+    }
+
+    fn binary(&mut self, token: &Token) {
+        use scanner::TokenType::*;
+
+        let op_type = &token.t_type;
+
+        self.parse_precedence(parse_rule(op_type).precedence.next());
+
+        match op_type {
+            Plus => self.emit_arithmetic(Instruction::Add, token),
+            Minus => self.emit_arithmetic(Instruction::Subtract, token),
+            Star => self.emit_arithmetic(Instruction::Multiply, token),
+            Slash => self.emit_arithmetic(Instruction::Divide, token),
+            Percent => self.emit_arithmetic(Instruction::Modulo, token),
+            BangEqual => {
+                self.emit_instruction(Instruction::Equal, token);
+                self.emit_instruction(Instruction::Not, token);
+            }
+            EqualEqual => self.emit_instruction(Instruction::Equal, token),
+            Greater => self.emit_instruction(Instruction::Greater, token),
+            GreaterEqual => {
+                self.emit_instruction(Instruction::Less, token);
+                self.emit_instruction(Instruction::Not, token);
+            }
+            Less => self.emit_instruction(Instruction::Less, token),
+            LessEqual => {
+                self.emit_instruction(Instruction::Greater, token);
+                self.emit_instruction(Instruction::Not, token);
+            }
+            _ => panic!("Can not invoke 'binary' for token type: {:?}", op_type),
+        }
+    }
+
+    fn emit_arithmetic(&mut self, instruction: Instruction, token: &Token) {
+        if !self.fold_constants || !self.fold_constant_operands(&instruction, token) {
+            self.emit_instruction(instruction, token);
+        }
+    }
+
+    // Replaces `Constant(a); Constant(b)` ending the chunk with the constant
+    // result of applying the operator to the two numbers. Errors such as a
+    // division by zero and NaN results are left to the runtime. Nothing is
+    // folded if a jump lands on the second load, as then the first one may
+    // not run before it.
+    fn fold_constant_operands(&mut self, instruction: &Instruction, token: &Token) -> bool {
+        let len = self.chunk().len();
+        if len < 2 || self.current_function().last_jump_target >= Some(len - 1) {
+            return false;
+        }
+        let (left, right) = match self.chunk().instructions[len - 2..] {
+            [Instruction::Constant(l), Instruction::Constant(r)] => (l, r),
+            _ => return false,
+        };
+
+        let l = self.chunk().read_constant(left).clone();
+        let r = self.chunk().read_constant(right).clone();
+        if !l.is_number() || !r.is_number() {
+            return false;
+        }
+        let result = match instruction {
+            Instruction::Add => l.add(&r),
+            Instruction::Subtract => l.subtract(&r),
+            Instruction::Multiply => l.multiply(&r),
+            Instruction::Divide => l.divide(&r),
+            Instruction::Modulo => l.modulo(&r),
+            Instruction::Power => l.power(&r),
+            _ => return false,
+        };
+        let folded = match result {
+            Ok(Value::Double(d)) if d.is_nan() => return false,
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+
+        self.chunk().pop_instruction();
+        self.chunk().pop_instruction();
+        self.release_constant(right.max(left));
+        self.release_constant(right.min(left));
+        self.number(folded, token);
+        true
+    }
+
+    // Drops a number constant if it is the last in the pool and no
+    // instruction loads it any more. Only `Constant` loads numbers.
+    fn release_constant(&mut self, constant: usize) {
+        let chunk = self.chunk();
+        if constant + 1 != chunk.constants.len() {
+            return;
+        }
+        let used = chunk
+            .instructions
+            .contains(&Instruction::Constant(constant));
+        if !used {
+            chunk.constants.pop();
+        }
+    }
+
+    fn emit_instruction(&mut self, instruction: Instruction, token: &Token) {
+        self.chunk().add_instruction(instruction, token.line);
+    }
+
+    fn emit_instruction_for_last_token(&mut self, instruction: Instruction) {
+        let line = self.last_token_line;
+        self.chunk().add_instruction(instruction, line);
+    }
+
+    fn emit_jump(&mut self, instruction: Instruction) -> usize {
+        self.emit_instruction_for_last_token(instruction);
+        self.chunk().len() - 1
+    }
+
+    fn patch_jump(&mut self, jump: usize) {
+        let target = self.chunk().next_index();
+        let function = self.current_function();
+        function.last_jump_target = function.last_jump_target.max(Some(target));
+        match self.chunk().instructions[jump] {
+            Instruction::Jump(ref mut t) | Instruction::JumpIfFalse(ref mut t) => *t = target,
+            ref i => panic!("Can not patch non-jump instruction: {:?}", i),
+        }
+    }
+
+    fn check(&self, t_type: &TokenType) -> bool {
+        match self.current {
+            Some(ref current) => current.t_type == *t_type,
+            None => false,
+        }
+    }
+
+    fn match_token(&mut self, t_type: TokenType) -> bool {
+        if self.check(&t_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current.is_none()
+    }
+
+    fn consume(&mut self, t_type: TokenType, error_msg: &'static str) {
+        if let Some(current) = self.current() {
+            if current.t_type == t_type {
+                self.advance();
+            } else {
+                self.error(error_msg, &current);
+            }
+        } else {
+            self.error_at_the_end(error_msg);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        if let Some(ref t) = self.previous {
+            self.last_token_line = t.line;
+        }
+
+        loop {
+            self.current = self.scanner.next();
+            if let Some(t) = self.current() {
+                match t.t_type {
+                    TokenType::Error(e) => self.error(e, &t),
+                    _ => break,
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn error(&mut self, error_msg: &str, token: &Token) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+
+        self.errors.push(Error::new(token, error_msg));
+    }
+
+    fn error_at_current(&mut self, error_msg: &'static str) {
+        match self.current() {
+            Some(current) => self.error(error_msg, &current),
+            None => self.error_at_the_end(error_msg),
+        }
+    }
+
+    fn error_at_the_end(&mut self, error_msg: &'static str) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+
+        self.errors
+            .push(Error::new_at_the_end(self.last_token_line, error_msg));
+    }
+
+    fn previous(&self) -> Option<Token<'a>> {
+        self.previous.clone()
+    }
+
+    fn current(&self) -> Option<Token<'a>> {
+        self.current.clone()
+    }
+}
+
+impl Error {
+    // The message followed by the offending line of `source`, with a caret
+    // under the token. `first_line` is the number of the source's first line.
+    pub fn render(&self, source: &str, first_line: usize, color: bool) -> String {
+        let (line, column) = match self.location {
+            ErrorLocation::Token { line, column, .. } => (line, Some(column)),
+            ErrorLocation::ScannerError { line, column } => (line, Some(column)),
+            ErrorLocation::AtTheEnd { line } => {
+                let end =
+                    source_line(source, first_line, line).map(|text| text.chars().count() + 1);
+                (line, end)
+            }
+            ErrorLocation::Internal => return paint_error(&self.to_string(), color),
+        };
+        format!(
+            "{}\n{}",
+            paint_error(&self.to_string(), color),
+            source_context(source, first_line, line, column, color)
+        )
+    }
+
+    pub fn location(&self) -> &ErrorLocation {
+        &self.location
+    }
+
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    fn new(token: &Token, msg: &str) -> Error {
+        let location = match token.t_type {
+            TokenType::Error(_) => ErrorLocation::ScannerError {
+                line: token.line,
+                column: token.column,
+            },
+            _ => ErrorLocation::Token {
+                line: token.line,
+                column: token.column,
+                lexeme: token.lexeme.to_string(),
+            },
+        };
+        Error {
+            location,
+            msg: msg.to_string(),
+        }
+    }
+
+    fn internal(error: &VerifyError) -> Error {
+        Error {
+            location: ErrorLocation::Internal,
+            msg: error.to_string(),
+        }
+    }
+
+    fn new_at_the_end(line: usize, msg: &'static str) -> Error {
+        Error {
+            location: ErrorLocation::AtTheEnd { line },
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            ErrorLocation::ScannerError { line, .. } => {
+                write!(f, "[line {}] Error: {}", line, self.msg)
+            }
+            ErrorLocation::Token {
+                line, ref lexeme, ..
+            } => {
+                write!(f, "[line {}] Error at '{}': {}", line, lexeme, self.msg)
+            }
+            ErrorLocation::AtTheEnd { line } => {
+                write!(f, "[line {}] Error at end: {}", line, self.msg)
+            }
+            ErrorLocation::Internal => write!(f, "Internal compiler error: {}", self.msg),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+fn source_line(source: &str, first_line: usize, line: usize) -> Option<&str> {
+    let source = source.strip_prefix('\u{FEFF}').unwrap_or(source);
+    source.lines().nth(line.checked_sub(first_line)?)
+}
+
+// Shows line `line` of `source`, whose first line is `first_line`, in a
+// gutter like rustc does, with a caret under `column` if there is one. Empty
+// if the source has no such line.
+pub fn source_context(
+    source: &str,
+    first_line: usize,
+    line: usize,
+    column: Option<usize>,
+    color: bool,
+) -> String {
+    let text = match source_line(source, first_line, line) {
+        Some(text) => text,
+        None => return String::new(),
+    };
+    let gutter = paint(
+        &format!("{} |", " ".repeat(line.to_string().len())),
+        BLUE,
+        color,
+    );
+    let numbered = paint(&format!("{} |", line), BLUE, color);
+    let context = format!("{}\n{} {}", gutter, numbered, text);
+    let column = match column {
+        Some(column) => column,
+        None => return context,
+    };
+    // Tabs are kept so the caret lines up however they are displayed.
+    let indent: String = text
+        .chars()
+        .chain(iter::repeat(' '))
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{}\n{} {}{}",
+        context,
+        gutter,
+        indent,
+        paint("^", RED, color)
+    )
+}
+
+// Errors are colored only when stderr is a terminal, so piped output stays
+// plain, and never with `--no-color`.
+pub fn use_color(no_color: bool) -> bool {
+    !no_color && io::stderr().is_terminal()
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+pub(crate) fn paint_error(message: &str, color: bool) -> String {
+    paint(message, RED, color)
+}
+
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+fn compound_operator(t_type: &TokenType) -> Option<Instruction> {
+    match t_type {
+        TokenType::PlusEqual => Some(Instruction::Add),
+        TokenType::MinusEqual => Some(Instruction::Subtract),
+        TokenType::StarEqual => Some(Instruction::Multiply),
+        TokenType::SlashEqual => Some(Instruction::Divide),
+        _ => None,
+    }
+}
+
+impl Precedence {
+    fn next(&self) -> Precedence {
+        use self::Precedence::*;
+        match self {
+            None => Comma,
+            Comma => Assignment,
+            Assignment => Conditional,
+            Conditional => Or,
+            Or => And,
+            And => Equality,
+            Equality => Comparison,
+            Comparison => Term,
+            Term => Factor,
+            Factor => Unary,
+            Unary => Power,
+            Power => Call,
+            Call => Primary,
+            Primary => Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, &Token<'a>, bool);
+
+// How a token parses at the start of an expression and after an operand,
+// and how tightly it binds in the latter case.
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+// The single place that defines how each token takes part in expressions.
+fn parse_rule<'a>(t_type: &TokenType) -> ParseRule<'a> {
+    use self::Precedence as P;
+    use scanner::TokenType::*;
+
+    let unary: ParseFn<'a> = |c, t, _| c.unary(t);
+    let binary: ParseFn<'a> = |c, t, _| c.binary(t);
+
+    let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, _) = match t_type {
+        LeftParen => (
+            Some(|c, t, _| c.grouping(t)),
+            Some(|c, t, _| c.call(t)),
+            P::Call,
+        ),
+        Dot => (None, Some(|c, t, can_assign| c.dot(t, can_assign)), P::Call),
+        LeftBracket => (
+            Some(|c, t, _| c.list(t)),
+            Some(|c, t, can_assign| c.index(t, can_assign)),
+            P::Call,
+        ),
+        LeftBrace => (Some(|c, t, _| c.map(t)), None, P::None),
+        Minus => (Some(unary), Some(binary), P::Term),
+        Plus => (Some(|c, t, _| c.unary_plus(t)), Some(binary), P::Term),
+        Slash => (None, Some(binary), P::Factor),
+        Star => (None, Some(binary), P::Factor),
+        Percent => (None, Some(binary), P::Factor),
+        StarStar => (None, Some(|c, t, _| c.power(t)), P::Power),
+        // Postfix forms are compiled along with their variable, so one
+        // reaching the infix position follows something else.
+        PlusPlus | MinusMinus => (
+            Some(|c, t, _| c.prefix_increment(t)),
+            Some(|c, t, _| c.error("Invalid assignment target", t)),
+            P::Call,
+        ),
+        Bang => (Some(unary), None, P::None),
+        BangEqual => (None, Some(binary), P::Equality),
+        EqualEqual => (None, Some(binary), P::Equality),
+        Greater => (None, Some(binary), P::Comparison),
+        GreaterEqual => (None, Some(binary), P::Comparison),
+        Less => (None, Some(binary), P::Comparison),
+        LessEqual => (None, Some(binary), P::Comparison),
+        And => (None, Some(|c, t, _| c.and(t)), P::And),
+        Or => (None, Some(|c, t, _| c.or(t)), P::Or),
+        Question => (None, Some(|c, t, _| c.conditional(t)), P::Conditional),
+        Comma => (None, Some(|c, t, _| c.comma(t)), P::Comma),
+        Identifier(_) => (
+            Some(|c, t, can_assign| {
+                if let Identifier(name) = t.t_type {
+                    c.variable(name, can_assign, t);
+                }
+            }),
+            None,
+            P::None,
+        ),
+        String(_) => (
+            Some(|c, t, _| {
+                if let String(ref s) = t.t_type {
+                    c.string(s, t);
+                }
+            }),
+            None,
+            P::None,
+        ),
+        Number(_) => (
+            Some(|c, t, _| {
+                if let Number(n) = t.t_type {
+                    c.number(Value::Double(n), t);
+                }
+            }),
+            None,
+            P::None,
+        ),
+        Integer(_) => (
+            Some(|c, t, _| {
+                if let Integer(i) = t.t_type {
+                    c.number(Value::Int(i), t);
+                }
+            }),
+            None,
+            P::None,
+        ),
+        True => (
+            Some(|c, t, _| c.emit_instruction(Instruction::True, t)),
+            None,
+            P::None,
+        ),
+        False => (
+            Some(|c, t, _| c.emit_instruction(Instruction::False, t)),
+            None,
+            P::None,
+        ),
+        Nil => (
+            Some(|c, t, _| c.emit_instruction(Instruction::Nil, t)),
+            None,
+            P::None,
+        ),
+        This => (Some(|c, t, _| c.this(t)), None, P::None),
+        _ => (None, None, P::None),
+    };
+    ParseRule {
+        prefix,
+        infix,
+        precedence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use common::Instruction::*;
+
+    #[test]
+    fn simple_binary() {
+        check_binary(1.0, 2.0, '+');
+        check_binary(40.0, 32323.12, '-');
+        check_binary(2132.0, 332.0, '/');
+        check_binary(323.323, 0.32, '*');
+        check_binary(7.0, 2.0, '%');
+    }
+
+    #[test]
+    fn parse_rule_table_is_consistent() {
+        use scanner::TokenType::*;
+
+        let all_tokens = vec![
+            LeftParen,
+            RightParen,
+            LeftBrace,
+            RightBrace,
+            LeftBracket,
+            RightBracket,
+            Comma,
+            Dot,
+            Minus,
+            Plus,
+            Semicolon,
+            Slash,
+            Star,
+            Percent,
+            Question,
+            Colon,
+            Bang,
+            BangEqual,
+            Equal,
+            EqualEqual,
+            Greater,
+            GreaterEqual,
+            Less,
+            LessEqual,
+            StarStar,
+            PlusEqual,
+            MinusEqual,
+            StarEqual,
+            SlashEqual,
+            PlusPlus,
+            MinusMinus,
+            QuestionQuestionEqual,
+            Identifier("a"),
+            String("s".into()),
+            Number(1.0),
+            And,
+            Break,
+            Case,
+            Class,
+            Continue,
+            Default,
+            Else,
+            False,
+            Fun,
+            For,
+            If,
+            Nil,
+            Or,
+            Print,
+            Return,
+            Super,
+            Switch,
+            This,
+            True,
+            Var,
+            While,
+            Error("e"),
+            Eof,
+        ];
+        for t_type in &all_tokens {
+            let rule = parse_rule(t_type);
+            assert_eq!(
+                rule.precedence != Precedence::None,
+                rule.infix.is_some(),
+                "{:?} has an infix rule only if it has a precedence",
+                t_type
+            );
+        }
+
+        // `%` takes its precedence from the table and its opcode from
+        // `binary`.
+        let rule = parse_rule(&Percent);
+        assert!(rule.prefix.is_none());
+        assert!(rule.precedence == Precedence::Factor);
+        check_unoptimized(
+            "7 % 2 * 3",
+            vec![c(0), c(1), Modulo, c(2), Multiply],
+            vec![7.0, 2.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        check_unoptimized(
+            "2 ** 3 ** 2",
+            vec![c(0), c(1), c(0), Power, Power],
+            vec![2.0, 3.0],
+        );
+        check_unoptimized(
+            "-2 ** 2 * 3",
+            vec![c(0), c(0), Power, Negate, c(1), Multiply],
+            vec![2.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn constant_folding() {
+        let chunk = compile_in_mode("print 2 * 3 + 4;", Mode::Script).unwrap();
+        assert_eq!(vec![c(0), Print, Nil, Return], instructions(&chunk));
+        assert_eq!(vec![Value::Double(10.0)], chunk.constants);
+
+        let chunk = compile_in_mode("print 2 ** 3 - 1 % 3;", Mode::Script).unwrap();
+        assert_eq!(vec![c(0), Print, Nil, Return], instructions(&chunk));
+        assert_eq!(vec![Value::Double(7.0)], chunk.constants);
+
+        let chunk = compile_in_mode("print 1 + 2 * x;", Mode::Script).unwrap();
+        assert_eq!(
+            vec![c(0), c(1), GetGlobal(2), Multiply, Add, Print, Nil, Return],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn constant_folding_follows_precedence_and_associativity() {
+        let folded = |source| {
+            let chunk = compile_in_mode(source, Mode::Script).unwrap();
+            assert_eq!(
+                vec![c(0), Pop, Nil, Return],
+                instructions(&chunk),
+                "{}",
+                source
+            );
+            chunk.constants[0].clone()
+        };
+
+        assert_eq!(Value::Int(-5), folded("1 - 2 * 3;"));
+        assert_eq!(Value::Int(-3), folded("(1 + 2) * (3 - 4);"));
+        assert_eq!(Value::Int(-4), folded("1 - 2 - 3;"));
+        assert_eq!(Value::Int(512), folded("2 ** 3 ** 2;"));
+        assert_eq!(Value::Int(3), folded("7 % 2 * 3;"));
+        assert_eq!(Value::Double(0.5), folded("4 / 2 / 4;"));
+    }
+
+    #[test]
+    fn constant_folding_keeps_runtime_semantics() {
+        let chunk = compile_in_mode("var x; print x * 0;", Mode::Script).unwrap();
+        assert_eq!(
+            vec![
+                Nil,
+                DefineGlobal(0),
+                GetGlobal(0),
+                c(1),
+                Multiply,
+                Print,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+
+        for source in ["print 1 / 0;", "print 1 % 0;", "print \"a\" + \"b\";"] {
+            let folded = compile_in_mode(source, Mode::Script).unwrap();
+            assert_eq!(
+                compile_unoptimized(source, Mode::Script).unwrap(),
+                folded,
+                "{}",
+                source
+            );
+        }
+
+        // NaN results aren't folded.
+        let chunk = compile_in_mode("print (0 - 1) ** 0.5;", Mode::Script).unwrap();
+        assert_eq!(
+            vec![c(0), c(1), Power, Print, Nil, Return],
+            instructions(&chunk)
+        );
+        assert_eq!(
+            vec![Value::Double(-1.0), Value::Double(0.5)],
+            chunk.constants
+        );
+
+        // The else branch jumps to the `3`, so `2 + 3` can't be folded.
+        let chunk = compile_in_mode("print (nil ? 1 : 2) + 3;", Mode::Script).unwrap();
+        assert_eq!(
+            compile_unoptimized("print (nil ? 1 : 2) + 3;", Mode::Script).unwrap(),
+            chunk
+        );
+    }
+
+    #[test]
+    fn binary_assoc() {
+        check_binary_assoc(1.1, 3.2, 4.2, '+');
+        check_binary_assoc(21.3, 23.1, 3.323, '-');
+        check_binary_assoc(323.21, 3244.0, 3656.2, '*');
+        check_binary_assoc(0.324, 345.1, 45.4, '/');
+    }
+
+    #[test]
+    fn precedences() {
+        check_unoptimized(
+            "1 - 2 * 3",
+            vec![c(0), c(1), c(2), Multiply, Subtract],
+            vec![1.0, 2.0, 3.0],
+        );
+
+        check_unoptimized(
+            "1 + 4 / 2",
+            vec![c(0), c(1), c(2), Divide, Add],
+            vec![1.0, 4.0, 2.0],
+        );
+
+        check_unoptimized(
+            "2 * 3 + 4 / 5",
+            vec![c(0), c(1), Multiply, c(2), c(3), Divide, Add],
+            vec![2.0, 3.0, 4.0, 5.0],
+        );
+    }
+
+    #[test]
+    fn groupings() {
+        check_unoptimized(
+            "(1 + 2) * (3 - 4)",
+            vec![c(0), c(1), Add, c(2), c(3), Subtract, Multiply],
+            vec![1.0, 2.0, 3.0, 4.0],
+        );
+
+        check_unoptimized(
+            "(((1 + 3) * 4) + 2) * 5",
+            vec![c(0), c(1), Add, c(2), Multiply, c(3), Add, c(4), Multiply],
+            vec![1.0, 3.0, 4.0, 2.0, 5.0],
+        );
+    }
+
+    #[test]
+    fn unary() {
+        check("-1 + 2", vec![c(0), Negate, c(1), Add], vec![1.0, 2.0]);
+        check("!!true", vec![True, Not, Not], vec![]);
+    }
+
+    #[test]
+    fn operators_are_attributed_to_their_line() {
+        let chunk = compile_in_mode("x +\n2 >=\n3;", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![GetGlobal(0), c(1), Add, c(2), Less, Not, Pop, Nil, Return],
+            instructions(&chunk)
+        );
+        let lines: Vec<usize> = (0..chunk.len()).map(|i| chunk.line_at(i)).collect();
+        assert_eq!(vec![1, 2, 1, 3, 2, 2, 3, 3, 3], lines);
+    }
+
+    #[test]
+    fn comparisons() {
+        check(
+            "1 <= 2 != false",
+            vec![c(0), c(1), Greater, Not, False, Equal, Not],
+            vec![1.0, 2.0],
+        );
+    }
+
+    #[test]
+    fn logical_and() {
+        check(
+            "true and nil and false",
+            vec![True, JumpIfFalse(7), Pop, Nil, JumpIfFalse(7), Pop, False],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn logical_or() {
+        check(
+            "nil or false",
+            vec![Nil, JumpIfFalse(3), Jump(5), Pop, False],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn logical_or_binds_looser_than_and() {
+        check(
+            "nil or true and false",
+            vec![
+                Nil,
+                JumpIfFalse(3),
+                Jump(8),
+                Pop,
+                True,
+                JumpIfFalse(8),
+                Pop,
+                False,
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn comma() {
+        check(
+            "1, 2, 3",
+            vec![c(0), Pop, c(1), Pop, c(2)],
+            vec![1.0, 2.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn commas_separate_arguments() {
+        let chunk = compile_in_mode("f(1, (2, 3));", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                c(1),
+                c(2),
+                Pop,
+                c(3),
+                Call(2),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        check(
+            "true ? 1 : 2",
+            vec![True, JumpIfFalse(5), Pop, c(0), Jump(7), Pop, c(1)],
+            vec![1.0, 2.0],
+        );
     }
 
     #[test]
-    fn groupings() {
+    fn conditional_is_right_associative() {
         check(
-            "(1 + 2) * (3 - 4)",
-            vec![c(0), c(1), Add, c(2), c(3), Subtract, Multiply],
-            vec![1.0, 2.0, 3.0, 4.0],
+            "false ? 1 : true ? 2 : 3",
+            vec![
+                False,
+                JumpIfFalse(5),
+                Pop,
+                c(0),
+                Jump(13),
+                Pop,
+                True,
+                JumpIfFalse(11),
+                Pop,
+                c(1),
+                Jump(13),
+                Pop,
+                c(2),
+            ],
+            vec![1.0, 2.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn conditional_errors() {
+        assert!(compile_in_mode("true ? 1;", Mode::Script).is_err());
+        assert!(compile_in_mode("var a; true ? 1 : a = 2;", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn unary_plus_is_an_error() {
+        let errors = compile_in_mode("print +5;", Mode::Script).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(
+            "[line 1] Error at '+': Unary '+' is not supported",
+            errors[0].to_string()
+        );
+        assert!(compile_in_mode("print 1 + +5;", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn if_statement() {
+        check_program(
+            "if (true) print 1;",
+            vec![True, JumpIfFalse(6), Pop, c(0), Print, Jump(7), Pop],
+            vec![1.0],
+        );
+    }
+
+    #[test]
+    fn if_else_statement() {
+        check_program(
+            "if (nil) print 1; else print 2;",
+            vec![
+                Nil,
+                JumpIfFalse(6),
+                Pop,
+                c(0),
+                Print,
+                Jump(9),
+                Pop,
+                c(1),
+                Print,
+            ],
+            vec![1.0, 2.0],
+        );
+    }
+
+    #[test]
+    fn equal_constants_are_shared() {
+        let chunk = compile_in_mode("print 1; print 1;", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![c(0), Print, c(0), Print, Nil, Return],
+            instructions(&chunk)
+        );
+        assert_eq!(vec![Value::Double(1.0)], chunk.constants);
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let literals: Vec<String> = (0..MAX_CONSTANTS).map(|i| i.to_string()).collect();
+        let source = format!("[{}];", literals.join(", "));
+        assert!(compile_in_mode(&source, Mode::Script).is_ok());
+
+        let source = format!("[{}, {}];", literals.join(", "), MAX_CONSTANTS);
+        assert!(compile_in_mode(&source, Mode::Script).is_err());
+
+        // Arithmetic on literals folds into one constant as it is compiled.
+        let source = format!("{} + {};", literals.join(" + "), MAX_CONSTANTS);
+        let chunk = compile_in_mode(&source, Mode::Script).unwrap();
+        assert_eq!(1, chunk.constants.len());
+    }
+
+    #[test]
+    fn global_variables() {
+        let chunk = compile_in_mode("var a = 1; a = a;", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                c(1),
+                DefineGlobal(0),
+                GetGlobal(0),
+                SetGlobal(0),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+        assert_eq!(Value::Str(Str::from("a")), chunk.constants[0]);
+    }
+
+    #[test]
+    fn nil_coalescing_assignment() {
+        let chunk = compile_in_mode("x ??= 1;", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                Nil,
+                Equal,
+                JumpIfFalse(8),
+                Pop,
+                c(1),
+                SetGlobal(0),
+                Jump(10),
+                Pop,
+                GetGlobal(0),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn local_variables() {
+        let chunk = compile_in_mode("{ var a = 1; { var b = a; b = 2; } }", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                c(0),
+                GetLocal(1),
+                c(1),
+                SetLocal(2),
+                Pop,
+                Pop,
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn scope_end_pops_all_its_locals_at_once() {
+        let chunk = compile_in_mode(
+            "{ var a = 1; { var b = 2; var c = 3; var d = 4; } var e = 5; }",
+            Mode::Script,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![c(0), c(1), c(2), c(3), PopN(3), c(4), PopN(2), Nil, Return],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn local_variable_errors() {
+        assert!(compile_in_mode("{ var a = a; }", Mode::Script).is_err());
+        assert!(compile_in_mode("{ var a; var a; }", Mode::Script).is_err());
+        assert!(compile_in_mode("{ var a; { var a; } }", Mode::Script).is_ok());
+    }
+
+    #[test]
+    fn while_statement() {
+        check_program(
+            "while (false) print 1;",
+            vec![False, JumpIfFalse(6), Pop, c(0), Print, Loop(0), Pop],
+            vec![1.0],
+        );
+    }
+
+    #[test]
+    fn for_statement() {
+        check_program(
+            "for (var i = 0; i < 4; i = i + 1) print i;",
+            vec![
+                c(0),
+                GetLocal(1),
+                c(1),
+                Less,
+                JumpIfFalse(16),
+                Pop,
+                Jump(13),
+                GetLocal(1),
+                c(2),
+                Add,
+                SetLocal(1),
+                Pop,
+                Loop(1),
+                GetLocal(1),
+                Print,
+                Loop(7),
+                Pop,
+                Pop,
+            ],
+            vec![0.0, 4.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn for_statement_without_clauses() {
+        check_program("for (;;) print 1;", vec![c(0), Print, Loop(0)], vec![1.0]);
+    }
+
+    #[test]
+    fn break_statement() {
+        check_program(
+            "while (true) { var a = 1; break; }",
+            vec![
+                True,
+                JumpIfFalse(8),
+                Pop,
+                c(0),
+                Pop,
+                Jump(9),
+                Pop,
+                Loop(0),
+                Pop,
+            ],
+            vec![1.0],
+        );
+    }
+
+    #[test]
+    fn continue_statement() {
+        check_program(
+            "for (var i = 0; i < 2; i = i + 1) { var a = i; continue; }",
+            vec![
+                c(0),
+                GetLocal(1),
+                c(1),
+                Less,
+                JumpIfFalse(18),
+                Pop,
+                Jump(13),
+                GetLocal(1),
+                c(2),
+                Add,
+                SetLocal(1),
+                Pop,
+                Loop(1),
+                GetLocal(1),
+                Pop,
+                Loop(7),
+                Pop,
+                Loop(7),
+                Pop,
+                Pop,
+            ],
+            vec![0.0, 2.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn break_and_continue_outside_of_a_loop_are_errors() {
+        assert!(compile_in_mode("break;", Mode::Script).is_err());
+        assert!(compile_in_mode("{ continue; }", Mode::Script).is_err());
+        assert!(compile_in_mode("while (true) fun f() { break; }", Mode::Script).is_err());
+        assert!(compile_in_mode("while (true) { if (true) break; }", Mode::Script).is_ok());
+    }
+
+    #[test]
+    fn switch_statement() {
+        check_program(
+            "switch (1) { case 1: print 1; case 2: print 2; default: print 3; }",
+            vec![
+                c(0),
+                Duplicate,
+                Duplicate,
+                Equal,
+                JumpIfFalse(9),
+                Pop,
+                c(0),
+                Print,
+                Jump(21),
+                Pop,
+                Duplicate,
+                c(1),
+                Equal,
+                JumpIfFalse(18),
+                Pop,
+                c(1),
+                Print,
+                Jump(21),
+                Pop,
+                c(2),
+                Print,
+                Pop,
+            ],
+            vec![1.0, 2.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn switch_errors() {
+        assert!(compile_in_mode("switch (1) { print 1; }", Mode::Script).is_err());
+        assert!(compile_in_mode("switch (1) { default: case 1: }", Mode::Script).is_err());
+        assert!(compile_in_mode("switch (1) { default: default: }", Mode::Script).is_err());
+        assert!(compile_in_mode("switch (1) {}", Mode::Script).is_ok());
+    }
+
+    #[test]
+    fn function_declaration() {
+        let chunk = compile_in_mode("fun add(a, b) { return a + b; }", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![c(1), DefineGlobal(0), Nil, Return],
+            instructions(&chunk)
+        );
+        let function = match chunk.constants[1] {
+            Value::Function(ref f) => f.clone(),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        };
+        assert_eq!(Some("add".to_string()), function.name);
+        assert_eq!(2, function.arity);
+        assert_eq!(
+            vec![GetLocal(1), GetLocal(2), Add, Return, Nil, Return],
+            instructions(&function.chunk)
+        );
+    }
+
+    #[test]
+    fn function_bodies_are_optimized() {
+        let chunk = compile_in_mode("fun f() { return [1, 1]; }", Mode::Script).unwrap();
+
+        let function = match chunk.constants[1] {
+            Value::Function(ref f) => f.clone(),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        };
+        assert_eq!(
+            vec![c(0), Duplicate, BuildList(2), Return, Nil, Return],
+            instructions(&function.chunk)
+        );
+    }
+
+    #[test]
+    fn class_declaration() {
+        let chunk = compile_in_mode("class A { m() { return 1; } }", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                Class(0),
+                DefineGlobal(0),
+                GetGlobal(0),
+                c(2),
+                Method(1),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+        assert_eq!(Value::Str(Str::from("m")), chunk.constants[1]);
+        let method = match chunk.constants[2] {
+            Value::Function(ref f) => f.clone(),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        };
+        assert_eq!(Some("m".to_string()), method.name);
+        assert_eq!(vec![c(0), Return, Nil, Return], instructions(&method.chunk));
+    }
+
+    #[test]
+    fn this_reads_slot_zero() {
+        let chunk = compile_in_mode("class A { m() { return this.x; } }", Mode::Script).unwrap();
+        let method = match chunk.constants[2] {
+            Value::Function(ref f) => f.clone(),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        };
+
+        assert_eq!(
+            vec![GetLocal(0), GetProperty(0), Return, Nil, Return],
+            instructions(&method.chunk)
+        );
+    }
+
+    #[test]
+    fn this_outside_of_a_method_is_an_error() {
+        let errors = compile_in_mode("print this;", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error at 'this': Can't use 'this' outside of a method",
+            errors[0].to_string()
         );
+        assert!(compile_in_mode("fun f() { return this; }", Mode::Script).is_err());
+        assert!(compile_in_mode("class A { m() { fun f() { this; } } }", Mode::Script).is_err());
+    }
 
+    #[test]
+    fn list_literal() {
         check(
-            "(((1 + 3) * 4) + 2) * 5",
-            vec![c(0), c(1), Add, c(2), Multiply, c(3), Add, c(4), Multiply],
-            vec![1.0, 3.0, 4.0, 2.0, 5.0],
+            "[1, 2, 1]",
+            vec![c(0), c(1), c(0), BuildList(3)],
+            vec![1.0, 2.0],
+        );
+        check("[]", vec![BuildList(0)], vec![]);
+    }
+
+    #[test]
+    fn map_literal() {
+        let chunk = compile_in_mode("var m = { \"a\": 1, \"b\": nil };", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                c(1),
+                c(2),
+                c(3),
+                Nil,
+                BuildMap(2),
+                DefineGlobal(0),
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+        assert_eq!(Value::Str(Str::from("a")), chunk.constants[1]);
+        assert_eq!(Value::Double(1.0), chunk.constants[2]);
+        let chunk = compile_in_mode("print {};", Mode::Script).unwrap();
+        assert_eq!(vec![BuildMap(0), Print, Nil, Return], instructions(&chunk));
+        assert!(compile_in_mode("print { \"a\" 1 };", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn compound_assignment() {
+        check_program(
+            "{ var a = 1; a *= a -= 2; }",
+            vec![
+                c(0),
+                GetLocal(1),
+                GetLocal(1),
+                c(1),
+                Subtract,
+                SetLocal(1),
+                Multiply,
+                SetLocal(1),
+                Pop,
+                Pop,
+            ],
+            vec![1.0, 2.0],
+        );
+
+        let chunk = compile_in_mode("a += 2; o.x /= 3;", Mode::Script).unwrap();
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                c(1),
+                Add,
+                SetGlobal(0),
+                Pop,
+                GetGlobal(2),
+                Duplicate,
+                GetProperty(3),
+                c(4),
+                Divide,
+                SetProperty(3),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn prefix_and_postfix_increment() {
+        check_program(
+            "{ var a = 1; ++a; a--; }",
+            vec![
+                c(0),
+                GetLocal(1),
+                c(0),
+                Add,
+                SetLocal(1),
+                Pop,
+                GetLocal(1),
+                Duplicate,
+                c(0),
+                Subtract,
+                SetLocal(1),
+                Pop,
+                Pop,
+                Pop,
+            ],
+            vec![1.0],
+        );
+
+        let chunk = compile_in_mode("a++;", Mode::Script).unwrap();
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                Duplicate,
+                c(1),
+                Add,
+                SetGlobal(0),
+                Pop,
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn increment_needs_a_variable() {
+        for source in &[
+            "++1;",
+            "1++;",
+            "++a.b;",
+            "a.b++;",
+            "(a)++;",
+            "++(a);",
+            "--a++;",
+            "class A { m() { ++this; } }",
+        ] {
+            let errors = compile_in_mode(source, Mode::Script).unwrap_err();
+            assert_eq!(
+                "Invalid assignment target",
+                errors[0].message(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn compound_assignment_needs_a_variable_or_property() {
+        for source in &["a + b += 1;", "a[0] += 1;", "1 -= 2;"] {
+            let errors = compile_in_mode(source, Mode::Script).unwrap_err();
+            assert_eq!(
+                "Invalid assignment target",
+                errors[0].message(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn index_access() {
+        let chunk = compile_in_mode("a[1] = a[2];", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                c(1),
+                GetGlobal(0),
+                c(2),
+                GetIndex,
+                SetIndex,
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+        assert!(compile_in_mode("a + b[0] = 1;", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn property_access() {
+        let chunk = compile_in_mode("a.b = a.c;", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                GetGlobal(0),
+                GetProperty(2),
+                SetProperty(1),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+        assert!(compile_in_mode("a + b.c = 1;", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn property_chains() {
+        let chunk = compile_in_mode("a.b.c.d();", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![
+                GetGlobal(0),
+                GetProperty(1),
+                GetProperty(2),
+                Invoke(3, 0),
+                Pop,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn return_statements() {
+        let chunk = compile_in_mode("fun f(a) { if (a) return 5; return; }", Mode::Script).unwrap();
+        let function = match chunk.constants[1] {
+            Value::Function(ref f) => f.clone(),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        };
+
+        assert_eq!(
+            vec![
+                GetLocal(1),
+                JumpIfFalse(6),
+                Pop,
+                c(0),
+                Return,
+                Jump(7),
+                Pop,
+                Nil,
+                Return,
+                Nil,
+                Return
+            ],
+            instructions(&function.chunk)
+        );
+        assert_eq!(vec![Value::Double(5.0)], function.chunk.constants);
+    }
+
+    #[test]
+    fn return_at_top_level_is_an_error() {
+        assert!(compile_in_mode("return 1;", Mode::Script).is_err());
+        assert!(compile_in_mode("{ return; }", Mode::Script).is_err());
+        assert!(compile_in_mode("fun f() { { return; } }", Mode::Script).is_ok());
+    }
+
+    #[test]
+    fn function_call() {
+        let chunk = compile_in_mode("f(1, nil);", Mode::Script).unwrap();
+
+        assert_eq!(
+            vec![GetGlobal(0), c(1), Nil, Call(2), Pop, Nil, Return],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn trailing_commas_in_calls_and_parameters() {
+        let chunk = compile_in_mode("f(1, nil,);", Mode::Script).unwrap();
+        assert_eq!(
+            vec![GetGlobal(0), c(1), Nil, Call(2), Pop, Nil, Return],
+            instructions(&chunk)
+        );
+        let chunk = compile_in_mode("a.m(1,);", Mode::Script).unwrap();
+        assert_eq!(
+            vec![GetGlobal(0), c(2), Invoke(1, 1), Pop, Nil, Return],
+            instructions(&chunk)
+        );
+
+        let chunk = compile_in_mode("fun g(a, b,) { return b; }", Mode::Script).unwrap();
+        match chunk.constants[1] {
+            Value::Function(ref g) => assert_eq!(2, g.arity),
+            ref v => panic!("Expected a function constant, got: {:?}", v),
+        }
+        assert!(compile_in_mode("class A { m(a,) {} }", Mode::Script).is_ok());
+    }
+
+    #[test]
+    fn malformed_commas_in_calls_and_parameters() {
+        for source in &["f(,1);", "f(,);", "f(1,,);", "fun g(,) {}", "fun g(a,,) {}"] {
+            assert!(compile_in_mode(source, Mode::Script).is_err(), "{}", source);
+        }
+        let errors = compile_in_mode("f(,1);", Mode::Script).unwrap_err();
+        assert_eq!("Expect expression before ','", errors[0].message());
+        let errors = compile_in_mode("fun g(,) {}", Mode::Script).unwrap_err();
+        assert_eq!("Expect parameter name", errors[0].message());
+    }
+
+    #[test]
+    fn repl_echoes_trailing_expression() {
+        let chunk = compile_in_mode("var a = 1; a + 2", Mode::Repl).unwrap();
+
+        assert_eq!(
+            vec![
+                c(1),
+                DefineGlobal(0),
+                GetGlobal(0),
+                c(2),
+                Add,
+                Print,
+                Nil,
+                Return
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn repl_does_not_echo_statements() {
+        let chunk = compile_in_mode("1;", Mode::Repl).unwrap();
+        assert_eq!(vec![c(0), Pop, Nil, Return], instructions(&chunk));
+
+        assert!(compile_in_mode("{ 1 }", Mode::Repl).is_err());
+        assert!(compile_in_mode("if (true) 1", Mode::Repl).is_err());
+        assert!(compile_in_mode("if (true) 1", Mode::Eval).is_err());
+        assert!(compile_in_mode("1", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn repl_does_not_echo_expressions_nested_in_statements() {
+        let sources = [
+            "if (false) 1; else 2",
+            "while (false) 1",
+            "for (;false;) 1",
+            "switch (1) { case 1: 2 }",
+            "fun f() { 1 }",
+        ];
+        for source in &sources {
+            assert!(compile_in_mode(source, Mode::Repl).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn eval_returns_trailing_expression() {
+        let chunk = compile_in_mode("1 + 2", Mode::Eval).unwrap();
+
+        assert_eq!(vec![c(0), Return, Nil, Return], instructions(&chunk));
+        assert_eq!(vec![Value::Int(3)], chunk.constants);
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        assert!(compile_in_mode("1 = 2;", Mode::Script).is_err());
+        assert!(compile_in_mode("var a; a + 1 = 2;", Mode::Script).is_err());
+        assert!(compile_in_mode("var a; -a ??= 2;", Mode::Script).is_err());
+    }
+
+    #[test]
+    fn leading_infix_operator() {
+        for op in &["*", "/", "<", "%", "==", "and"] {
+            let errors = compile_in_mode(&format!("{} 3;", op), Mode::Script).unwrap_err();
+            assert_eq!(
+                format!(
+                    "[line 1] Error at '{0}': Expect expression before '{0}'",
+                    op
+                ),
+                errors[0].to_string()
+            );
+        }
+
+        let errors = compile_in_mode("print 1 + * 2;", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error at '*': Expect expression before '*'",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn error_display() {
+        let errors = compile_in_mode("print 1 +;", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error at ';': Expect expression",
+            errors[0].to_string()
         );
+
+        let errors = compile_in_mode("var a = 1;\nprint a", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 2] Error at end: Expect ';' after value",
+            errors[0].to_string()
+        );
+        match errors[0].location() {
+            ErrorLocation::AtTheEnd { line: 2 } => {}
+            other => panic!("Expected an error at the end, got: {:?}", other),
+        }
+        assert_eq!("Expect ';' after value", errors[0].message());
+
+        let errors = compile_in_mode("print $;", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error: Unexpected character",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn errors_at_the_end_report_the_last_line() {
+        let errors = compile_in_mode("var a = 1;\n\nprint a(1,\n  2", Mode::Script).unwrap_err();
+        assert_eq!(
+            vec![Error::new_at_the_end(4, "Expect ')' after arguments")],
+            errors
+        );
+        assert_eq!(
+            "[line 4] Error at end: Expect ')' after arguments",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn jumps_past_the_end_fail_compilation() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::False, 1);
+        chunk.add_instruction(Instruction::JumpIfFalse(3), 1);
+        chunk.add_instruction(Instruction::Return, 1);
+
+        let errors = verify(chunk).unwrap_err();
+        assert_eq!(&ErrorLocation::Internal, errors[0].location());
+        assert_eq!(
+            "Internal compiler error: OP_JUMP_IF_FALSE at 0001 jumps to 3, but the chunk has 3 instructions",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn compiled_jumps_pass_verification() {
+        let source = "var a = 0;
+            if (a > 1) print a; else if (a) print -a;
+            while (a < 10) { a = a + 1; if (a == 5) continue; if (a == 8) break; }
+            for (var i = 0; i < 3; i = i + 1) print i and a or nil;
+            fun f(x) { if (x) return 1; while (false) {} }";
+        for mode in &[Mode::Script, Mode::Repl, Mode::Eval] {
+            assert!(compile_unoptimized(source, *mode).is_ok());
+            assert!(compile_in_mode(source, *mode).is_ok());
+        }
+    }
+
+    #[test]
+    fn rendered_errors_point_at_the_token() {
+        let source = "var a = 1;\n1 + * 2;";
+        let errors = compile_in_mode(source, Mode::Script).unwrap_err();
+
+        assert_eq!(
+            "[line 2] Error at '*': Expect expression before '*'\n  |\n2 | 1 + * 2;\n  |     ^",
+            errors[0].render(source, 1, false)
+        );
+    }
+
+    #[test]
+    fn rendered_errors_are_colored_only_when_asked() {
+        let source = "1 + * 2;";
+        let errors = compile_in_mode(source, Mode::Script).unwrap_err();
+
+        assert!(!errors[0].render(source, 1, false).contains('\x1b'));
+        assert_eq!(
+            "\x1b[1;31m[line 1] Error at '*': Expect expression before '*'\x1b[0m\n\
+             \x1b[1;34m  |\x1b[0m\n\x1b[1;34m1 |\x1b[0m 1 + * 2;\n\
+             \x1b[1;34m  |\x1b[0m     \x1b[1;31m^\x1b[0m",
+            errors[0].render(source, 1, true)
+        );
+        assert!(!use_color(true));
+    }
+
+    #[test]
+    fn rendered_errors_at_the_end_point_past_the_line() {
+        let source = "print\t1";
+        let errors = compile_in_mode(source, Mode::Script).unwrap_err();
+
+        assert_eq!(
+            "[line 1] Error at end: Expect ';' after value\n  |\n1 | print\t1\n  |      \t ^",
+            errors[0].render(source, 1, false)
+        );
+
+        let errors = compile_repl_line("print 1", 12).unwrap_err();
+        assert_eq!(
+            "[line 12] Error at end: Expect ';' after value\n   |\n12 | print 1\n   |        ^",
+            errors[0].render("print 1", 12, false)
+        );
+    }
+
+    #[test]
+    fn unterminated_grouping_reports_the_open_paren() {
+        let errors = compile_in_mode("var a = 1;\nprint (1 +\n  2", Mode::Script).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(
+            "[line 2] Error at '(': Unmatched '(', expect ')' to close it",
+            errors[0].to_string()
+        );
+
+        let errors = compile_in_mode("(1 + 2", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error at '(': Unmatched '(', expect ')' to close it",
+            errors[0].to_string()
+        );
+
+        let errors = compile_in_mode("(1 + 2;", Mode::Script).unwrap_err();
+        assert_eq!(
+            "[line 1] Error at ';': Expect to have ')' at the end of grouping expression",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn missing_semicolon_is_an_error() {
+        assert!(compile_in_mode("print 1", Mode::Script).is_err());
+        assert!(compile_in_mode("if (true print 1;", Mode::Script).is_err());
     }
 
     fn check_binary(lhs: f64, rhs: f64, op: char) {
@@ -360,7 +2924,7 @@ mod tests {
         let instructions = vec![c(0), c(1), op_instruction];
         let constants = vec![lhs, rhs];
 
-        check(&source, instructions, constants);
+        check_unoptimized(&source, instructions, constants);
     }
 
     fn instruction_by_char_op(op: char) -> Instruction {
@@ -369,6 +2933,7 @@ mod tests {
             '-' => Subtract,
             '*' => Multiply,
             '/' => Divide,
+            '%' => Modulo,
             _ => panic!("should use binary ops only here"),
         }
     }
@@ -388,7 +2953,7 @@ mod tests {
 
         let constants = vec![n1, n2, n3];
 
-        check(&source, instructions, constants);
+        check_unoptimized(&source, instructions, constants);
     }
 
     fn check(
@@ -396,20 +2961,54 @@ mod tests {
         instructions_without_line: Vec<Instruction>,
         double_constants: Vec<f64>,
     ) {
-        let compiled = compile_to_chunk(source);
+        let mut instructions = instructions_without_line;
+        instructions.push(Pop);
+        check_program(&format!("{};", source), instructions, double_constants);
+    }
 
-        let mut instructions_with_lines = Vec::new();
-        for i in instructions_without_line {
-            instructions_with_lines.push(InstructionWithLine(i, 1));
-        }
-        instructions_with_lines.push(InstructionWithLine(Instruction::Return, 1));
+    fn check_program(
+        source: &str,
+        instructions_without_line: Vec<Instruction>,
+        double_constants: Vec<f64>,
+    ) {
+        let compiled = compile_in_mode(source, Mode::Script).expect("compile error");
+        check_chunk(compiled, instructions_without_line, double_constants);
+    }
+
+    // Like `check`, but without constant folding, for tests of the order the
+    // parser emits operators in.
+    fn check_unoptimized(
+        source: &str,
+        instructions_without_line: Vec<Instruction>,
+        double_constants: Vec<f64>,
+    ) {
+        let compiled =
+            compile_unoptimized(&format!("{};", source), Mode::Script).expect("compile error");
+        let mut instructions = instructions_without_line;
+        instructions.push(Pop);
+        check_chunk(compiled, instructions, double_constants);
+    }
+
+    fn check_chunk(
+        compiled: Chunk,
+        instructions_without_line: Vec<Instruction>,
+        double_constants: Vec<f64>,
+    ) {
+        let mut instructions = instructions_without_line;
+        instructions.push(Instruction::Nil);
+        instructions.push(Instruction::Return);
 
         let constants: Vec<Value> = double_constants.iter().map(|f| Value::Double(*f)).collect();
 
-        assert_eq!(instructions_with_lines, compiled.instructions);
+        assert_eq!(vec![(1, instructions.len())], compiled.lines);
+        assert_eq!(instructions, compiled.instructions);
         assert_eq!(constants, compiled.constants);
     }
 
+    fn instructions(chunk: &Chunk) -> Vec<Instruction> {
+        chunk.instructions.clone()
+    }
+
     fn c(i: usize) -> Instruction {
         Constant(i)
     }
@@ -0,0 +1,130 @@
+use std::fmt;
+
+// What went wrong while running a script, without where. The VM adds the
+// line and the stack trace.
+#[derive(Debug, PartialEq)]
+pub enum RuntimeError {
+    TypeMismatch {
+        op: &'static str,
+        expected: &'static str,
+        got: &'static str,
+    },
+    OperandsMustBeNumbers {
+        left: &'static str,
+        right: &'static str,
+    },
+    OperandsMustBeNumbersOrStrings {
+        left: &'static str,
+        right: &'static str,
+    },
+    UndefinedVariable(String),
+    UndefinedProperty(String),
+    NotAnInstance(&'static str),
+    NotIndexable(&'static str),
+    NotIndexAssignable(&'static str),
+    InvalidIndex(f64),
+    IndexOutOfRange {
+        index: usize,
+        len: usize,
+    },
+    ArityMismatch {
+        expected: usize,
+        got: usize,
+    },
+    // For natives taking a range of argument counts.
+    ArityRangeMismatch {
+        min: usize,
+        max: usize,
+        got: usize,
+    },
+    NotCallable(&'static str),
+    AssertionFailed(String),
+    InvalidNumber(String),
+    NegativeSquareRoot(f64),
+    NotAnInteger(f64),
+    InvalidRange {
+        lo: i64,
+        hi: i64,
+    },
+    DivisionByZero,
+    // An integer result that doesn't fit in 64 bits.
+    IntegerOverflow(&'static str),
+    StackUnderflow,
+    StackOverflow,
+    CallStackOverflow,
+    IpOutOfBounds,
+    // Only a malformed chunk reads a slot past the top of the stack.
+    InvalidLocal(usize),
+    OutputFailed,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RuntimeError::*;
+
+        match self {
+            TypeMismatch { op, expected, got } => {
+                write!(f, "Operand of '{}' must be a {}, got {}", op, expected, got)
+            }
+            OperandsMustBeNumbers { left, right } => {
+                write!(f, "Operands must be numbers, got {} and {}", left, right)
+            }
+            OperandsMustBeNumbersOrStrings { left, right } => write!(
+                f,
+                "Operands must be two numbers or two strings, got {} and {}",
+                left, right
+            ),
+            UndefinedVariable(name) => write!(f, "Undefined variable '{}'", name),
+            UndefinedProperty(name) => write!(f, "Undefined property '{}'", name),
+            NotAnInstance(got) => write!(f, "Only instances have properties, got {}", got),
+            NotIndexable(got) => {
+                write!(
+                    f,
+                    "Only lists, strings and maps can be indexed, got {}",
+                    got
+                )
+            }
+            NotIndexAssignable(got) => write!(
+                f,
+                "Only list elements and map entries can be assigned, got {}",
+                got
+            ),
+            InvalidIndex(index) => {
+                write!(f, "Index must be a non-negative integer, got {}", index)
+            }
+            IndexOutOfRange { index, len } => {
+                write!(f, "Index {} is out of range for length {}", index, len)
+            }
+            ArityMismatch { expected, got } => {
+                write!(f, "Expected {} arguments but got {}", expected, got)
+            }
+            ArityRangeMismatch { min, max, got } if *max == min + 1 => {
+                write!(f, "Expected {} or {} arguments but got {}", min, max, got)
+            }
+            ArityRangeMismatch { min, max, got } => {
+                write!(f, "Expected {} to {} arguments but got {}", min, max, got)
+            }
+            NotCallable(got) => write!(f, "Can only call functions and classes, got {}", got),
+            AssertionFailed(message) if message.is_empty() => write!(f, "Assertion failed"),
+            AssertionFailed(message) => write!(f, "Assertion failed: {}", message),
+            InvalidNumber(s) => write!(f, "Unable to parse '{}' as a number", s),
+            NegativeSquareRoot(x) => {
+                write!(f, "Can't take the square root of negative number {}", x)
+            }
+            NotAnInteger(d) => write!(f, "Expected an integer, got {}", d),
+            InvalidRange { lo, hi } => write!(
+                f,
+                "Invalid range: the lower bound {} is above the upper bound {}",
+                lo, hi
+            ),
+            DivisionByZero => write!(f, "Division by zero"),
+            IntegerOverflow(op) => write!(f, "Integer overflow in {}", op),
+            StackUnderflow => write!(f, "Stack underflow"),
+            StackOverflow => write!(f, "Stack overflow"),
+            CallStackOverflow => write!(f, "Call stack overflow"),
+            IpOutOfBounds => write!(f, "Ran past the end of the bytecode"),
+            InvalidLocal(slot) => write!(f, "No local variable in slot {}", slot),
+            OutputFailed => write!(f, "Unable to write output"),
+        }
+    }
+}
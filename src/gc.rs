@@ -0,0 +1,225 @@
+// Heap values are reference counted, which frees everything except cycles:
+// an instance holding itself in a field, a list containing itself, and so
+// on. The heap keeps a weak reference to every list, map and instance the VM
+// creates. A collection finds the roots by trial deletion: an object with
+// more strong references than the other tracked objects hold is referenced
+// from outside the heap, be it the VM's stack and globals or a value the
+// embedder kept. Everything reachable from the roots is marked, and the rest
+// can only be kept alive by cycles, so emptying them breaks the cycles and
+// lets reference counting free them.
+//
+// The heap also owns the table of interned strings. They cannot form cycles,
+// but each one counts towards the next collection, which drops the ones
+// nothing else refers to any more.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use interner::{Interner, Str};
+use value::{Instance, Map, Value};
+
+// Collections start once this many objects and strings are tracked.
+const INITIAL_THRESHOLD: usize = 1024;
+
+enum Object {
+    Instance(Weak<Instance>),
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<Map>>),
+}
+
+impl Object {
+    fn upgrade(&self) -> Option<Value> {
+        match self {
+            Object::Instance(weak) => weak.upgrade().map(Value::Instance),
+            Object::List(weak) => weak.upgrade().map(Value::List),
+            Object::Map(weak) => weak.upgrade().map(Value::Map),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Object::Instance(weak) => weak.strong_count() > 0,
+            Object::List(weak) => weak.strong_count() > 0,
+            Object::Map(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+pub struct Heap {
+    objects: Vec<Object>,
+    strings: Interner,
+    // Tracking more objects and strings than this triggers a collection.
+    next_collection: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            strings: Interner::new(),
+            next_collection: INITIAL_THRESHOLD,
+        }
+    }
+
+    // Starts tracking the value if it is an object that can be part of a
+    // cycle.
+    pub fn track(&mut self, value: &Value) {
+        let object = match value {
+            Value::Instance(instance) => Object::Instance(Rc::downgrade(instance)),
+            Value::List(list) => Object::List(Rc::downgrade(list)),
+            Value::Map(map) => Object::Map(Rc::downgrade(map)),
+            _ => return,
+        };
+        self.objects.push(object);
+    }
+
+    // The interned string equal to `s`, which becomes it if there is none.
+    pub fn intern(&mut self, s: Str) -> Str {
+        self.strings.intern(s)
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.tracked() > self.next_collection
+    }
+
+    fn tracked(&self) -> usize {
+        self.objects.len() + self.strings.len()
+    }
+
+    // Frees the objects that nothing outside the heap can reach, stops
+    // tracking the ones already freed and releases the unused strings.
+    pub fn collect(&mut self) {
+        let objects: Vec<Value> = self.objects.iter().filter_map(Object::upgrade).collect();
+
+        // The references each object gets from the other tracked objects.
+        let mut internal: HashMap<usize, usize> = HashMap::new();
+        for object in &objects {
+            for child in children(object) {
+                if let Some(child) = object_address(&child) {
+                    *internal.entry(child).or_insert(0) += 1;
+                }
+            }
+        }
+
+        // `objects` holds one reference to each object itself.
+        let mut gray: Vec<Value> = objects
+            .iter()
+            .filter(|object| {
+                let address = object_address(object).expect("Only objects are tracked");
+                let internal = internal.get(&address).cloned().unwrap_or(0);
+                strong_count(object) > internal + 1
+            })
+            .cloned()
+            .collect();
+        let mut marked = HashSet::new();
+        while let Some(value) = gray.pop() {
+            mark(&value, &mut marked, &mut gray);
+        }
+
+        let unreachable: Vec<Value> = objects
+            .into_iter()
+            .filter(|object| {
+                let address = object_address(object).expect("Only objects are tracked");
+                !marked.contains(&address)
+            })
+            .collect();
+        for value in &unreachable {
+            clear(value);
+        }
+        drop(unreachable);
+
+        self.objects.retain(Object::is_alive);
+        self.strings.release_unused();
+        self.next_collection = INITIAL_THRESHOLD.max(self.tracked() * 2);
+    }
+
+    // How many tracked objects are still allocated, plus the interned
+    // strings still in use.
+    pub fn live_count(&self) -> usize {
+        self.objects.iter().filter(|o| o.is_alive()).count() + self.strings.in_use()
+    }
+
+    pub fn interned_count(&self) -> usize {
+        self.strings.len()
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+// The address of a value the heap can track.
+fn object_address(value: &Value) -> Option<usize> {
+    match value {
+        Value::Instance(instance) => Some(address(instance)),
+        Value::List(list) => Some(address(list)),
+        Value::Map(map) => Some(address(map)),
+        _ => None,
+    }
+}
+
+fn strong_count(value: &Value) -> usize {
+    match value {
+        Value::Instance(instance) => Rc::strong_count(instance),
+        Value::List(list) => Rc::strong_count(list),
+        Value::Map(map) => Rc::strong_count(map),
+        _ => 0,
+    }
+}
+
+// The values an object holds directly.
+fn children(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Instance(instance) => instance.fields.borrow().values().cloned().collect(),
+        Value::List(list) => list.borrow().clone(),
+        Value::Map(map) => map
+            .borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Marks the value and queues the values it holds, once per object.
+fn mark(value: &Value, marked: &mut HashSet<usize>, gray: &mut Vec<Value>) {
+    if let Value::BoundMethod(bound) = value {
+        gray.push(bound.receiver.clone());
+        return;
+    }
+    let object = match object_address(value) {
+        Some(object) => object,
+        None => return,
+    };
+    if marked.insert(object) {
+        gray.extend(children(value));
+    }
+}
+
+// Drops what the object holds. The contents are moved out first, so values
+// freed along the way never see the object borrowed.
+fn clear(value: &Value) {
+    match value {
+        Value::Instance(instance) => {
+            let fields = mem::take(&mut *instance.fields.borrow_mut());
+            drop(fields);
+        }
+        Value::List(list) => {
+            let elements = mem::take(&mut *list.borrow_mut());
+            drop(elements);
+        }
+        Value::Map(map) => {
+            let entries = mem::take(&mut *map.borrow_mut());
+            drop(entries);
+        }
+        _ => {}
+    }
+}
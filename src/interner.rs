@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+// An immutable string. Strings a VM creates or loads with a chunk are
+// interned, so equal ones share one allocation and compare by pointer; the
+// others, such as those built to look a name up from Rust, fall back to
+// comparing their contents.
+#[derive(Clone)]
+pub struct Str(Rc<str>);
+
+// The strings a VM created or loaded, one allocation per distinct string.
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<Str>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    // The interned string equal to `s`, which becomes it if there is none.
+    pub fn intern(&mut self, s: Str) -> Str {
+        if let Some(interned) = self.strings.get(&s) {
+            return interned.clone();
+        }
+        self.strings.insert(s.clone());
+        s
+    }
+
+    // Drops the strings nothing but the interner refers to.
+    pub fn release_unused(&mut self) {
+        self.strings.retain(|s| Rc::strong_count(&s.0) > 1)
+    }
+
+    // How many strings something besides the interner refers to.
+    pub fn in_use(&self) -> usize {
+        self.strings
+            .iter()
+            .filter(|s| Rc::strong_count(&s.0) > 1)
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+impl Str {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for Str {
+    fn from(s: &'a str) -> Str {
+        Str(Rc::from(s))
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Str {
+        Str(Rc::from(s))
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Str) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Str {}
+
+impl Hash for Str {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl Deref for Str {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", &*self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+
+    #[test]
+    fn equal_strings_share_one_allocation_once_interned() {
+        let mut interner = Interner::new();
+        let a = interner.intern(Str::from("hello"));
+        let b = interner.intern(Str::from(format!("hel{}", "lo")));
+
+        assert!(Rc::ptr_eq(&a.0, &b.0));
+        assert_eq!(1, interner.len());
+        assert_ne!(a, interner.intern(Str::from("world")));
+    }
+
+    #[test]
+    fn strings_compare_by_contents() {
+        let a = Str::from("hello");
+        let b = Str::from(format!("hel{}", "lo"));
+
+        assert!(!Rc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, b);
+        assert_ne!(a, Str::from("world"));
+        let strings: HashSet<Str> = vec![a, b].into_iter().collect();
+        assert_eq!(1, strings.len());
+    }
+
+    #[test]
+    fn release_unused_keeps_strings_in_use() {
+        let mut interner = Interner::new();
+        let kept = interner.intern(Str::from("kept"));
+        interner.intern(Str::from("dropped"));
+
+        assert_eq!(1, interner.in_use());
+        interner.release_unused();
+
+        assert_eq!(1, interner.len());
+        assert!(Rc::ptr_eq(&kept.0, &interner.intern(Str::from("kept")).0));
+    }
+
+    #[test]
+    fn strings_read_like_strings() {
+        let s = Str::from("abc");
+
+        assert_eq!("abc", s.as_str());
+        assert_eq!(3, s.len());
+        assert_eq!("abc", s.to_string());
+        assert_eq!("\"abc\"", format!("{:?}", s));
+    }
+}
@@ -1,7 +1,17 @@
 pub mod common;
 pub mod compiler;
+pub mod error;
+pub mod gc;
+pub mod interner;
+pub mod natives;
+pub mod optimizer;
 pub mod scanner;
+pub mod serialization;
 pub mod value;
 pub mod vm;
 
 pub mod util;
+
+pub use scanner::{tokens, Token, TokenType};
+pub use value::Value;
+pub use vm::{eval, InterpretError, RuntimeError, RuntimeErrorWithLine};
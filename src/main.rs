@@ -9,12 +9,33 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     let running_mode = parse_args_for_running_mode(&args).unwrap_or_else(|err| {
-        println!("Error: invalid arguments {}", err);
-        process::exit(1);
+        println!("Error: {}", err);
+        println!("{}", USAGE);
+        process::exit(err.exit_code());
     });
 
-    match running_mode {
-        RunningMode::Script(file_name) => run_file(file_name),
-        RunningMode::Repl => run_repl(),
+    let result = match running_mode {
+        RunningMode::Scripts(scripts, options) => run_files(&scripts, options),
+        RunningMode::DumpBytecode(script, options) => dump_bytecode(script, options),
+        RunningMode::DumpTokens(script) => dump_tokens(script),
+        RunningMode::Repl(options) => {
+            run_repl(options);
+            Ok(())
+        }
+        RunningMode::Help => {
+            println!("{}", HELP);
+            Ok(())
+        }
+        RunningMode::Version => {
+            println!("rvlox {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    };
+
+    if let Err(err) = result {
+        if let RunError::Io(ref io_err) = err {
+            println!("Unable to read script file: {}", io_err);
+        }
+        process::exit(err.exit_code());
     }
 }
@@ -0,0 +1,400 @@
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use error::RuntimeError;
+use interner::Str;
+use value::*;
+
+// Each native with the number of arguments it takes.
+pub fn all() -> Vec<(&'static str, RangeInclusive<u8>, NativeFn)> {
+    // Starts `clock` before any script can call it.
+    start();
+    vec![
+        ("abs", 1..=1, abs),
+        ("assert", 1..=2, assert),
+        ("ceil", 1..=1, ceil),
+        ("clock", 0..=0, clock),
+        ("floor", 1..=1, floor),
+        ("join", 2..=2, join),
+        ("len", 1..=1, len),
+        ("lower", 1..=1, lower),
+        ("max", 2..=2, max),
+        ("min", 2..=2, min),
+        ("num", 1..=1, num),
+        ("randint", 2..=2, randint),
+        ("random", 0..=0, random),
+        ("split", 2..=2, split),
+        ("sqrt", 1..=1, sqrt),
+        ("str", 1..=1, str),
+        ("substr", 3..=3, substr),
+        ("trim", 1..=1, trim),
+        ("upper", 1..=1, upper),
+    ]
+}
+
+// Fails with the message, if one is given, when the condition is falsey.
+pub fn assert(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (condition, message) = match args {
+        [condition] => (condition, String::new()),
+        [condition, message] => (condition, message.to_string()),
+        _ => {
+            return Err(RuntimeError::ArityRangeMismatch {
+                min: 1,
+                max: 2,
+                got: args.len(),
+            })
+        }
+    };
+    if condition.is_truthy() {
+        Ok(Value::Nil)
+    } else {
+        Err(RuntimeError::AssertionFailed(message))
+    }
+}
+
+// Seconds since the process started. The clock is monotonic, so the
+// difference between two calls is never negative.
+pub fn clock(_context: &mut NativeContext, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Double(start().elapsed().as_secs_f64()))
+}
+
+fn start() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
+}
+
+// The number of elements in a list or map, or characters in a string.
+pub fn len(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match args {
+        [Value::List(list)] => list.borrow().len(),
+        [Value::Str(s)] => s.chars().count(),
+        [Value::Map(map)] => map.borrow().len(),
+        [v] => {
+            return Err(RuntimeError::TypeMismatch {
+                op: "len",
+                expected: "list, string or map",
+                got: v.type_name(),
+            })
+        }
+        _ => {
+            return Err(RuntimeError::ArityMismatch {
+                expected: 1,
+                got: args.len(),
+            })
+        }
+    };
+    Ok(Value::Int(len as i64))
+}
+
+// The value as `print` would show it.
+pub fn str(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [Value::Str(s)] => Ok(Value::Str(s.clone())),
+        [v] => Ok(Value::Str(Str::from(v.to_string()))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+// Parses a number, ignoring surrounding whitespace. Fails on anything that
+// isn't a finite number.
+pub fn num(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [s] => {
+            let s = s.as_string("num")?;
+            if let Ok(i) = s.trim().parse::<i64>() {
+                return Ok(Value::Int(i));
+            }
+            match s.trim().parse::<f64>() {
+                Ok(n) if n.is_finite() => Ok(Value::Double(n)),
+                _ => Err(RuntimeError::InvalidNumber(s.to_string())),
+            }
+        }
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+// The `count` characters of the string starting at character `start`.
+pub fn substr(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, start, count) = match args {
+        [s, start, count] => (
+            s.as_string("substr")?,
+            count_arg("substr", start)?,
+            count_arg("substr", count)?,
+        ),
+        _ => {
+            return Err(RuntimeError::ArityMismatch {
+                expected: 3,
+                got: args.len(),
+            })
+        }
+    };
+    let len = s.chars().count();
+    if start > len {
+        return Err(RuntimeError::IndexOutOfRange { index: start, len });
+    }
+    match start.checked_add(count) {
+        Some(end) if end <= len => {}
+        // An end that overflows is out of range for any string.
+        end => {
+            return Err(RuntimeError::IndexOutOfRange {
+                index: end.unwrap_or(usize::MAX),
+                len,
+            })
+        }
+    }
+    let substring: String = s.chars().skip(start).take(count).collect();
+    Ok(Value::Str(Str::from(substring)))
+}
+
+pub fn upper(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [s] => Ok(Value::Str(Str::from(s.as_string("upper")?.to_uppercase()))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+pub fn lower(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [s] => Ok(Value::Str(Str::from(s.as_string("lower")?.to_lowercase()))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+// The string without leading and trailing whitespace.
+pub fn trim(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [s] => Ok(Value::Str(Str::from(s.as_string("trim")?.trim()))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+// The parts of the string between separators, as a list. An empty string has
+// one empty part, and an empty separator splits out each character.
+pub fn split(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, separator) = match args {
+        [s, separator] => (s.as_string("split")?, separator.as_string("split")?),
+        _ => {
+            return Err(RuntimeError::ArityMismatch {
+                expected: 2,
+                got: args.len(),
+            })
+        }
+    };
+    let parts = if separator.is_empty() {
+        s.chars()
+            .map(|c| Value::Str(Str::from(&*c.encode_utf8(&mut [0; 4]))))
+            .collect()
+    } else {
+        s.split(&**separator)
+            .map(|part| Value::Str(Str::from(part)))
+            .collect()
+    };
+    Ok(Value::list(parts))
+}
+
+// The strings in the list, with the separator between each pair.
+pub fn join(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (list, separator) = match args {
+        [Value::List(list), separator] => (list, separator.as_string("join")?),
+        [v, _] => {
+            return Err(RuntimeError::TypeMismatch {
+                op: "join",
+                expected: "list",
+                got: v.type_name(),
+            })
+        }
+        _ => {
+            return Err(RuntimeError::ArityMismatch {
+                expected: 2,
+                got: args.len(),
+            })
+        }
+    };
+    let mut joined = String::new();
+    for (i, element) in list.borrow().iter().enumerate() {
+        if i > 0 {
+            joined.push_str(separator);
+        }
+        joined.push_str(element.as_string("join")?);
+    }
+    Ok(Value::Str(Str::from(joined)))
+}
+
+// Integers stay integers; a double argument makes the result a double.
+pub fn min(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [Value::Int(a), Value::Int(b)] => Ok(Value::Int(*a.min(b))),
+        _ => binary_math("min", args, f64::min),
+    }
+}
+
+pub fn max(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [Value::Int(a), Value::Int(b)] => Ok(Value::Int(*a.max(b))),
+        _ => binary_math("max", args, f64::max),
+    }
+}
+
+// The smallest integer has no positive counterpart, so its absolute value
+// overflows.
+pub fn abs(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args {
+        [Value::Int(i)] => i
+            .checked_abs()
+            .map(Value::Int)
+            .ok_or(RuntimeError::IntegerOverflow("abs")),
+        _ => unary_math("abs", args, f64::abs),
+    }
+}
+
+pub fn floor(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    unary_math("floor", args, f64::floor)
+}
+
+pub fn ceil(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    unary_math("ceil", args, f64::ceil)
+}
+
+// A negative number has no square root, which is an error rather than NaN,
+// as dividing by zero is.
+pub fn sqrt(_context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    if let [x] = args {
+        let x = x.as_number("sqrt")?;
+        if x < 0.0 {
+            return Err(RuntimeError::NegativeSquareRoot(x));
+        }
+    }
+    unary_math("sqrt", args, f64::sqrt)
+}
+
+fn unary_math(op: &'static str, args: &[Value], f: fn(f64) -> f64) -> Result<Value, RuntimeError> {
+    match args {
+        [x] => Ok(Value::Double(f(x.as_number(op)?))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 1,
+            got: args.len(),
+        }),
+    }
+}
+
+fn binary_math(
+    op: &'static str,
+    args: &[Value],
+    f: fn(f64, f64) -> f64,
+) -> Result<Value, RuntimeError> {
+    match args {
+        [a, b] => Ok(Value::Double(f(a.as_number(op)?, b.as_number(op)?))),
+        _ => Err(RuntimeError::ArityMismatch {
+            expected: 2,
+            got: args.len(),
+        }),
+    }
+}
+
+// A random number in [0, 1).
+pub fn random(context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args.is_empty() {
+        return Err(RuntimeError::ArityMismatch {
+            expected: 0,
+            got: args.len(),
+        });
+    }
+    // The top 53 bits, as many as a double holds exactly.
+    Ok(Value::Double(
+        (context.rng.next() >> 11) as f64 / (1u64 << 53) as f64,
+    ))
+}
+
+// A random integer from `lo` to `hi`, both included.
+pub fn randint(context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (lo, hi) = match args {
+        [lo, hi] => (integer_arg("randint", lo)?, integer_arg("randint", hi)?),
+        _ => {
+            return Err(RuntimeError::ArityMismatch {
+                expected: 2,
+                got: args.len(),
+            })
+        }
+    };
+    if lo > hi {
+        return Err(RuntimeError::InvalidRange { lo, hi });
+    }
+    let span = (hi as i128 - lo as i128 + 1) as u128;
+    let offset = (context.rng.next() as u128 % span) as i128;
+    Ok(Value::Int((lo as i128 + offset) as i64))
+}
+
+// The state natives share, lent to them by the VM for each call.
+#[derive(Debug)]
+pub struct NativeContext {
+    // Backs `random` and `randint`.
+    pub rng: Rng,
+}
+
+// A xorshift64* generator. Each VM has its own, so a seeded VM always makes
+// the same numbers.
+#[derive(Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Mixes the seed so similar seeds start far apart. Xorshift never
+        // leaves a zero state, so that one is avoided.
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Rng {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    // Seeded from the clock, or a fixed seed if the clock is before the
+    // Unix epoch.
+    pub fn from_time() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+        Rng::new(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+// A whole number argument.
+fn integer_arg(op: &'static str, arg: &Value) -> Result<i64, RuntimeError> {
+    let d = arg.as_number(op)?;
+    arg.as_integer().ok_or(RuntimeError::NotAnInteger(d))
+}
+
+// A non-negative integer argument that fits in a `usize`.
+fn count_arg(op: &'static str, arg: &Value) -> Result<usize, RuntimeError> {
+    let d = arg.as_number(op)?;
+    arg.as_integer()
+        .and_then(|i| usize::try_from(i).ok())
+        .ok_or(RuntimeError::InvalidIndex(d))
+}
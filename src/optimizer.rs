@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use common::*;
+use value::Value;
+
+// Optimizes the chunk and the chunks of the functions declared in it.
+pub fn optimize(chunk: &mut Chunk) {
+    coalesce_constant_loads(chunk);
+    for constant in chunk.constants.iter_mut() {
+        // Only a function nothing else holds yet can be rewritten, as is every
+        // function straight out of the compiler.
+        if let Value::Function(function) = constant {
+            if let Some(function) = Rc::get_mut(function) {
+                optimize(&mut function.chunk);
+            }
+        }
+    }
+}
+
+// Rewrites `Constant(i); Constant(i)` into `Constant(i); Duplicate`. The rewrite
+// replaces instructions one to one, so no jump operands need fixing up, but
+// a jump target can be reached without executing the load before it, so
+// targets are never rewritten.
+pub fn coalesce_constant_loads(chunk: &mut Chunk) {
+    let targets = jump_targets(chunk);
+    let mut top_constant = None;
+    for (i, inst) in chunk.instructions.iter_mut().enumerate() {
+        if targets.contains(&i) {
+            top_constant = None;
+        }
+        match *inst {
+            Instruction::Constant(c) if top_constant == Some(c) => *inst = Instruction::Duplicate,
+            Instruction::Constant(c) => top_constant = Some(c),
+            Instruction::Duplicate => {}
+            _ => top_constant = None,
+        }
+    }
+}
+
+fn jump_targets(chunk: &Chunk) -> HashSet<usize> {
+    chunk
+        .instructions
+        .iter()
+        .filter_map(|inst| match *inst {
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::Loop(target) => Some(target),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use common::Instruction::*;
+    use value::Function;
+
+    #[test]
+    fn repeated_constant_becomes_dup() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(Constant(c), 1);
+        chunk.add_instruction(Constant(c), 1);
+        chunk.add_instruction(Add, 1);
+        chunk.add_instruction(Return, 1);
+
+        coalesce_constant_loads(&mut chunk);
+
+        assert_eq!(
+            vec![Constant(c), Duplicate, Add, Return],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn runs_of_the_same_constant_become_dups() {
+        let mut chunk = Chunk::new();
+        let c0 = chunk.add_constant(Value::Double(1.0));
+        let c1 = chunk.add_constant(Value::Double(2.0));
+        chunk.add_instruction(Constant(c0), 1);
+        chunk.add_instruction(Constant(c1), 1);
+        chunk.add_instruction(Constant(c1), 1);
+        chunk.add_instruction(Constant(c1), 1);
+        chunk.add_instruction(Constant(c0), 1);
+
+        coalesce_constant_loads(&mut chunk);
+
+        assert_eq!(
+            vec![
+                Constant(c0),
+                Constant(c1),
+                Duplicate,
+                Duplicate,
+                Constant(c0)
+            ],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn jump_targets_are_not_rewritten() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(False, 1);
+        chunk.add_instruction(JumpIfFalse(3), 1);
+        chunk.add_instruction(Constant(c), 1);
+        chunk.add_instruction(Constant(c), 1);
+        chunk.add_instruction(Constant(c), 1);
+
+        coalesce_constant_loads(&mut chunk);
+
+        assert_eq!(
+            vec![False, JumpIfFalse(3), Constant(c), Constant(c), Duplicate],
+            instructions(&chunk)
+        );
+    }
+
+    #[test]
+    fn functions_are_optimized_too() {
+        let mut inner = Function::new(Some("inner".to_string()));
+        let c = inner.chunk.add_constant(Value::Double(1.0));
+        inner.chunk.add_instruction(Constant(c), 1);
+        inner.chunk.add_instruction(Constant(c), 1);
+        let mut outer = Function::new(Some("outer".to_string()));
+        let f = outer.chunk.add_constant(Value::Function(Rc::new(inner)));
+        outer.chunk.add_instruction(Constant(f), 1);
+        let mut chunk = Chunk::new();
+        let f = chunk.add_constant(Value::Function(Rc::new(outer)));
+        chunk.add_instruction(Constant(f), 1);
+
+        optimize(&mut chunk);
+
+        let inner = match chunk.read_constant(f) {
+            Value::Function(outer) => match outer.chunk.read_constant(0) {
+                Value::Function(inner) => inner.clone(),
+                v => panic!("Expected a function, got: {:?}", v),
+            },
+            v => panic!("Expected a function, got: {:?}", v),
+        };
+        assert_eq!(vec![Constant(c), Duplicate], instructions(&inner.chunk));
+    }
+
+    fn instructions(chunk: &Chunk) -> Vec<Instruction> {
+        chunk.instructions.clone()
+    }
+}
@@ -1,27 +1,48 @@
-use std::iter::Peekable;
-use std::str::Chars;
+use std::borrow::Cow;
+use std::fmt;
 
+pub const DEFAULT_MAX_TOKEN_LENGTH: usize = 64 * 1024;
+
+// Walks the source by bytes. `start` and `current` are byte offsets of the
+// token being scanned, so lexemes are slices of the source. Everything the
+// scanner matches on is ASCII; other chars only show up inside strings and
+// as unexpected characters. Cloning is cheap: it copies the offsets, so a
+// clone can scan ahead while the original stays where it was.
+#[derive(Clone)]
 pub struct Scanner<'a> {
-    start: Chars<'a>,
-    current: Peekable<Chars<'a>>,
-    look_ahead: Option<char>,
-    cur_len: usize,
+    source: &'a str,
+    start: usize,
+    current: usize,
     line: usize,
+    // Byte offset of the first char of the current line.
+    line_start: usize,
+    // The column of `start`, counted in chars from 1.
+    column: usize,
+    // Where `column` was last counted up to, so each char is counted once.
+    column_offset: usize,
+    max_token_length: usize,
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct Token {
-    pub t_type: TokenType,
+pub struct Token<'a> {
+    pub t_type: TokenType<'a>,
     pub line: usize,
+    // The column of the token's first char, counted in chars from 1.
+    pub column: usize,
+    // The token's text, sliced from the source.
+    pub lexeme: &'a str,
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum TokenType {
+// Identifiers and strings borrow their text from the source.
+pub enum TokenType<'a> {
     // Single-character tokens.
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -29,6 +50,9 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Question,
+    Colon,
 
     // One or two character tokens.
     Bang,
@@ -39,15 +63,31 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PlusPlus,
+    MinusMinus,
+
+    // Three character tokens.
+    QuestionQuestionEqual,
 
     // Literals.
-    Identifier(String),
-    String(String),
+    Identifier(&'a str),
+    // Borrowed unless escapes had to be decoded.
+    String(Cow<'a, str>),
     Number(f64),
+    Integer(i64),
 
     // Keywords.
     And,
+    Break,
+    Case,
     Class,
+    Continue,
+    Default,
     Else,
     False,
     Fun,
@@ -58,112 +98,214 @@ pub enum TokenType {
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
     While,
 
     Error(&'static str),
+    // Only produced by `tokens`; the scanner simply runs out.
+    Eof,
+}
+
+// The variant name, without the literal value.
+impl<'a> fmt::Display for TokenType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TokenType::*;
+
+        let name = match self {
+            LeftParen => "LeftParen",
+            RightParen => "RightParen",
+            LeftBrace => "LeftBrace",
+            RightBrace => "RightBrace",
+            LeftBracket => "LeftBracket",
+            RightBracket => "RightBracket",
+            Comma => "Comma",
+            Dot => "Dot",
+            Minus => "Minus",
+            Plus => "Plus",
+            Semicolon => "Semicolon",
+            Slash => "Slash",
+            Star => "Star",
+            Percent => "Percent",
+            Question => "Question",
+            Colon => "Colon",
+            Bang => "Bang",
+            BangEqual => "BangEqual",
+            Equal => "Equal",
+            EqualEqual => "EqualEqual",
+            Greater => "Greater",
+            GreaterEqual => "GreaterEqual",
+            Less => "Less",
+            LessEqual => "LessEqual",
+            StarStar => "StarStar",
+            PlusEqual => "PlusEqual",
+            MinusEqual => "MinusEqual",
+            StarEqual => "StarEqual",
+            SlashEqual => "SlashEqual",
+            PlusPlus => "PlusPlus",
+            MinusMinus => "MinusMinus",
+            QuestionQuestionEqual => "QuestionQuestionEqual",
+            Identifier(_) => "Identifier",
+            String(_) => "String",
+            Number(_) => "Number",
+            Integer(_) => "Integer",
+            And => "And",
+            Break => "Break",
+            Case => "Case",
+            Class => "Class",
+            Continue => "Continue",
+            Default => "Default",
+            Else => "Else",
+            False => "False",
+            Fun => "Fun",
+            For => "For",
+            If => "If",
+            Nil => "Nil",
+            Or => "Or",
+            Print => "Print",
+            Return => "Return",
+            Super => "Super",
+            Switch => "Switch",
+            This => "This",
+            True => "True",
+            Var => "Var",
+            While => "While",
+            Error(_) => "Error",
+            Eof => "Eof",
+        };
+        f.write_str(name)
+    }
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Scanner<'a> {
+        Scanner::with_max_token_length(source, DEFAULT_MAX_TOKEN_LENGTH)
+    }
+
+    // Counts lines from `start_line`, for sources that continue earlier
+    // input, such as the lines of a REPL session.
+    pub fn with_starting_line(source: &'a str, start_line: usize) -> Scanner<'a> {
         Scanner {
-            start: source.chars(),
-            current: source.chars().peekable(),
-            look_ahead: None,
-            cur_len: 0,
-            line: 1,
+            line: start_line,
+            ..Scanner::new(source)
         }
     }
 
-    fn advance(&mut self) -> Option<char> {
-        if let Some(la) = self.look_ahead {
-            self.look_ahead = None;
-            self.cur_len += 1;
-            return Some(la);
+    // `max_token_length` is in bytes.
+    pub fn with_max_token_length(source: &'a str, max_token_length: usize) -> Scanner<'a> {
+        // Files saved as UTF-8 with a byte order mark start with it; it is
+        // not part of the program.
+        let source = source.strip_prefix('\u{FEFF}').unwrap_or(source);
+        Scanner {
+            source,
+            start: 0,
+            current: 0,
+            line: 1,
+            line_start: 0,
+            column: 1,
+            column_offset: 0,
+            max_token_length,
         }
-        let next = self.current.next();
+    }
+
+    // The line the scanner has reached; once the tokens run out, the line
+    // of the end of the source.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let next = self.peek();
         if next.is_some() {
-            self.cur_len += 1;
+            self.current += 1;
         }
         next
     }
 
-    fn make_token(&mut self, t_type: TokenType) -> Token {
+    fn make_token(&mut self, t_type: TokenType<'a>) -> Token<'a> {
         Token {
             t_type,
             line: self.line,
+            column: self.column,
+            lexeme: self.lexeme(),
         }
     }
 
-    fn error_token(&self, msg: &'static str) -> Token {
-        return Token {
+    fn error_token(&self, msg: &'static str) -> Token<'a> {
+        Token {
             t_type: TokenType::Error(msg),
             line: self.line,
-        };
+            column: self.column,
+            lexeme: self.lexeme(),
+        }
     }
 
-    fn scan_lexeme(&mut self) -> String {
-        let mut lexeme = String::new();
-        while self.cur_len > 0 {
-            lexeme.push(self.start.next().unwrap());
-            self.cur_len -= 1;
-        }
-        lexeme
+    fn is_too_long(&self) -> bool {
+        self.current - self.start > self.max_token_length
     }
 
-    fn scan_str_lexeme(&mut self) -> String {
-        let mut str_lexeme = String::new();
-        let _ = self.start.next();
-        for _ in 1..(self.cur_len - 1) {
-            str_lexeme.push(self.start.next().unwrap());
-        }
-        let _ = self.start.next();
-        self.cur_len = 0;
+    fn too_long_token(&mut self) -> Token<'a> {
+        self.error_token("Token too long")
+    }
 
-        str_lexeme
+    fn lexeme(&self) -> &'a str {
+        &self.source[self.start..self.current]
     }
 
-    fn match_char(&mut self, c: char) -> Token {
+    fn match_char(&mut self, c: u8) -> Token<'a> {
         use self::TokenType::*;
 
         match c {
-            '(' => self.make_token(LeftParen),
-            ')' => self.make_token(RightParen),
-            '{' => self.make_token(LeftBrace),
-            '}' => self.make_token(RightBrace),
-            ';' => self.make_token(Semicolon),
-            ',' => self.make_token(Comma),
-            '.' => self.make_token(Dot),
-            '-' => self.make_token(Minus),
-            '+' => self.make_token(Plus),
-            '/' => self.make_token(Slash),
-            '*' => self.make_token(Star),
-            '!' => self.possible_two_char_token(Bang, '=', BangEqual),
-            '=' => self.possible_two_char_token(Equal, '=', EqualEqual),
-            '>' => self.possible_two_char_token(Greater, '=', GreaterEqual),
-            '<' => self.possible_two_char_token(Less, '=', LessEqual),
-            '"' => self.string(),
-            c if c.is_digit(10) => self.number(),
+            b'(' => self.make_token(LeftParen),
+            b')' => self.make_token(RightParen),
+            b'{' => self.make_token(LeftBrace),
+            b'}' => self.make_token(RightBrace),
+            b'[' => self.make_token(LeftBracket),
+            b']' => self.make_token(RightBracket),
+            b';' => self.make_token(Semicolon),
+            b':' => self.make_token(Colon),
+            b',' => self.make_token(Comma),
+            b'.' => self.make_token(Dot),
+            b'-' => self.operator(b'-', Minus, MinusMinus, MinusEqual),
+            b'+' => self.operator(b'+', Plus, PlusPlus, PlusEqual),
+            b'/' => self.possible_two_char_token(Slash, b'=', SlashEqual),
+            b'%' => self.make_token(Percent),
+            b'*' => self.operator(b'*', Star, StarStar, StarEqual),
+            b'!' => self.possible_two_char_token(Bang, b'=', BangEqual),
+            b'=' => self.possible_two_char_token(Equal, b'=', EqualEqual),
+            b'>' => self.possible_two_char_token(Greater, b'=', GreaterEqual),
+            b'<' => self.possible_two_char_token(Less, b'=', LessEqual),
+            b'?' => self.question(),
+            b'"' => self.string(),
+            c if c.is_ascii_digit() => self.number(),
             c if Self::is_allowed_for_identifier(c) => self.identifier(),
-            _ => self.make_token(Error("Unexpected character")),
+            _ => {
+                self.skip_rest_of_char();
+                self.make_token(Error("Unexpected character"))
+            }
         }
     }
 
-    fn is_allowed_for_identifier(c: char) -> bool {
-        match c {
-            'a'..='z' | 'A'..='Z' | '_' => true,
-            _ => false,
+    fn is_allowed_for_identifier(c: u8) -> bool {
+        matches!(c, b'a'..=b'z' | b'A'..=b'Z' | b'_')
+    }
+
+    // Steps over the continuation bytes of a multi-byte char, so an
+    // unexpected char gives one error rather than one per byte.
+    fn skip_rest_of_char(&mut self) {
+        while !self.source.is_char_boundary(self.current) {
+            self.current += 1;
         }
     }
 
     fn possible_two_char_token(
         &mut self,
-        cur_type: TokenType,
-        char_to_match: char,
-        possible_type: TokenType,
-    ) -> Token {
+        cur_type: TokenType<'a>,
+        char_to_match: u8,
+        possible_type: TokenType<'a>,
+    ) -> Token<'a> {
         let t_type = if self.next_matches(char_to_match) {
             possible_type
         } else {
@@ -172,13 +314,49 @@ impl<'a> Scanner<'a> {
         self.make_token(t_type)
     }
 
-    fn string(&mut self) -> Token {
+    // `c`, doubled as `cc`, or followed by `=`.
+    fn operator(
+        &mut self,
+        c: u8,
+        single: TokenType<'a>,
+        doubled: TokenType<'a>,
+        assigning: TokenType<'a>,
+    ) -> Token<'a> {
+        if self.next_matches(c) {
+            self.make_token(doubled)
+        } else if self.next_matches(b'=') {
+            self.make_token(assigning)
+        } else {
+            self.make_token(single)
+        }
+    }
+
+    fn question(&mut self) -> Token<'a> {
+        if !self.next_matches(b'?') {
+            self.make_token(TokenType::Question)
+        } else if self.next_matches(b'=') {
+            self.make_token(TokenType::QuestionQuestionEqual)
+        } else {
+            self.error_token("Unexpected character")
+        }
+    }
+
+    fn string(&mut self) -> Token<'a> {
         while let Some(c) = self.peek() {
-            if c == '"' {
+            if c == b'"' {
                 break;
             }
-            if c == '\n' {
-                self.line += 1;
+            if c == b'\\' {
+                self.advance();
+                // An escaped quote or backslash doesn't end the string or
+                // start another escape.
+                if let Some(b'"') | Some(b'\\') = self.peek() {
+                    self.advance();
+                }
+                continue;
+            }
+            if self.is_line_break(c) {
+                self.new_line();
             }
             self.advance();
         }
@@ -187,23 +365,83 @@ impl<'a> Scanner<'a> {
             return self.error_token("Unterminated string");
         }
         self.advance();
-        let str_lexeme = self.scan_str_lexeme();
-        self.make_token(TokenType::String(str_lexeme))
+        if self.is_too_long() {
+            return self.too_long_token();
+        }
+        let str_lexeme = &self.source[self.start + 1..self.current - 1];
+        if !str_lexeme.contains('\\') {
+            return self.make_token(TokenType::String(Cow::Borrowed(str_lexeme)));
+        }
+        match Self::unescape(str_lexeme) {
+            Ok(s) => self.make_token(TokenType::String(Cow::Owned(s))),
+            Err(msg) => self.error_token(msg),
+        }
+    }
+
+    // Decodes `\"`, `\\`, `\n`, `\r`, `\t` and `\u{...}` with one to six hex
+    // digits naming a unicode scalar value.
+    fn unescape(raw: &str) -> Result<String, &'static str> {
+        let mut decoded = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                decoded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('"') => decoded.push('"'),
+                Some('\\') => decoded.push('\\'),
+                Some('n') => decoded.push('\n'),
+                Some('r') => decoded.push('\r'),
+                Some('t') => decoded.push('\t'),
+                Some('u') => {
+                    if chars.next() != Some('{') {
+                        return Err("Invalid unicode escape");
+                    }
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or("Invalid unicode escape")?;
+                    let digits = &rest[..end];
+                    chars = rest[end + 1..].chars();
+                    if digits.is_empty()
+                        || digits.len() > 6
+                        || !digits.bytes().all(|b| b.is_ascii_hexdigit())
+                    {
+                        return Err("Invalid unicode escape");
+                    }
+                    let c = u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or("Invalid unicode escape")?;
+                    decoded.push(c);
+                }
+                _ => return Err("Invalid escape sequence"),
+            }
+        }
+        Ok(decoded)
     }
 
-    fn number(&mut self) -> Token {
+    fn number(&mut self) -> Token<'a> {
         self.advance_while_digit();
 
-        if let Some('.') = self.peek() {
-            if let Some('0'..='9') = self.peek_next() {
+        if let Some(b'.') = self.peek() {
+            if let Some(b'0'..=b'9') = self.peek_next() {
                 self.advance();
 
                 self.advance_while_digit();
             }
         }
 
-        let num_lexeme = self.scan_lexeme();
-        let num: f64 = num_lexeme
+        if self.is_too_long() {
+            return self.too_long_token();
+        }
+
+        // Literals without a decimal point are integers, unless too big for
+        // one.
+        if let Ok(i) = self.lexeme().parse() {
+            return self.make_token(TokenType::Integer(i));
+        }
+        let num: f64 = self
+            .lexeme()
             .parse()
             .unwrap_or_else(|e| panic!("Illegally parsed number: {}", e));
 
@@ -211,12 +449,12 @@ impl<'a> Scanner<'a> {
     }
 
     fn advance_while_digit(&mut self) {
-        while let Some('0'..='9') = self.peek() {
+        while let Some(b'0'..=b'9') = self.peek() {
             self.advance();
         }
     }
 
-    fn identifier(&mut self) -> Token {
+    fn identifier(&mut self) -> Token<'a> {
         while let Some(c) = self.peek() {
             if !Self::is_allowed_for_identifier(c) {
                 break;
@@ -224,12 +462,16 @@ impl<'a> Scanner<'a> {
             self.advance();
         }
 
+        if self.is_too_long() {
+            return self.too_long_token();
+        }
+
         self.keyword_or_identifier()
     }
 
-    fn keyword_or_identifier(&mut self) -> Token {
-        let lexeme = self.scan_lexeme();
-        let keyword = Self::check_if_keyword(&lexeme);
+    fn keyword_or_identifier(&mut self) -> Token<'a> {
+        let lexeme = self.lexeme();
+        let keyword = Self::check_if_keyword(lexeme);
 
         if let Some(keyword) = keyword {
             self.make_token(keyword)
@@ -238,64 +480,37 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn check_if_keyword(lexeme: &str) -> Option<TokenType> {
+    fn check_if_keyword(lexeme: &str) -> Option<TokenType<'a>> {
         use self::TokenType::*;
 
-        let bs = lexeme.as_bytes();
-        match bs[0] {
-            b'a' => Self::check_suffix(1, bs, "nd", And),
-            b'c' => Self::check_suffix(1, bs, "lass", Class),
-            b'e' => Self::check_suffix(1, bs, "lse", Else),
-            b'i' => Self::check_suffix(1, bs, "f", If),
-            b'n' => Self::check_suffix(1, bs, "il", Nil),
-            b'o' => Self::check_suffix(1, bs, "r", Or),
-            b'p' => Self::check_suffix(1, bs, "rint", Print),
-            b'r' => Self::check_suffix(1, bs, "eturn", Return),
-            b's' => Self::check_suffix(1, bs, "uper", Super),
-            b'v' => Self::check_suffix(1, bs, "ar", Var),
-            b'w' => Self::check_suffix(1, bs, "hile", While),
-            b't' => {
-                if bs.len() > 1 {
-                    match bs[1] {
-                        b'h' => Self::check_suffix(2, bs, "is", This),
-                        b'r' => Self::check_suffix(2, bs, "ue", True),
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            }
-            b'f' => {
-                if bs.len() > 1 {
-                    match bs[1] {
-                        b'a' => Self::check_suffix(2, bs, "lse", False),
-                        b'o' => Self::check_suffix(2, bs, "r", For),
-                        b'u' => Self::check_suffix(2, bs, "n", Fun),
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        }
-    }
-
-    fn check_suffix(
-        from: usize,
-        lexeme_bytes: &[u8],
-        suffix: &str,
-        t_type: TokenType,
-    ) -> Option<TokenType> {
-        let actual_suffix = &lexeme_bytes[from..];
-        if actual_suffix == suffix.as_bytes() {
-            Some(t_type)
-        } else {
-            None
-        }
+        let keyword = match lexeme {
+            "and" => And,
+            "break" => Break,
+            "case" => Case,
+            "class" => Class,
+            "continue" => Continue,
+            "default" => Default,
+            "else" => Else,
+            "false" => False,
+            "for" => For,
+            "fun" => Fun,
+            "if" => If,
+            "nil" => Nil,
+            "or" => Or,
+            "print" => Print,
+            "return" => Return,
+            "super" => Super,
+            "switch" => Switch,
+            "this" => This,
+            "true" => True,
+            "var" => Var,
+            "while" => While,
+            _ => return None,
+        };
+        Some(keyword)
     }
 
-    fn next_matches(&mut self, c: char) -> bool {
+    fn next_matches(&mut self, c: u8) -> bool {
         match self.peek() {
             Some(n) if c == n => {
                 self.advance();
@@ -306,34 +521,56 @@ impl<'a> Scanner<'a> {
     }
 
     fn skip_whitespaces(&mut self) {
-        loop {
-            if let Some(c) = self.peek() {
-                match c {
-                    ' ' | '\r' | '\t' => {
-                        self.advance();
-                    }
-                    '\n' => {
-                        self.line += 1;
-                        self.advance();
+        while let Some(c) = self.peek() {
+            match c {
+                b' ' | b'\t' => {
+                    self.advance();
+                }
+                b'\r' | b'\n' => {
+                    if self.is_line_break(c) {
+                        self.new_line();
                     }
-                    '/' => {
-                        if !self.skip_if_comment() {
-                            break;
-                        }
+                    self.advance();
+                }
+                b'/' => {
+                    if !self.skip_if_comment() {
+                        break;
                     }
-                    _ => break,
                 }
-            } else {
-                break;
+                _ => break,
             }
         }
-        self.sync_start();
+        self.start = self.current;
+
+        if self.column_offset < self.line_start {
+            self.column = 1;
+            self.column_offset = self.line_start;
+        }
+        self.column += self.source[self.column_offset..self.start].chars().count();
+        self.column_offset = self.start;
+    }
+
+    // Called on the peeked line break, before advancing past it.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
+    // `c` is the peeked byte. A `\r` counts as a line break only when it is
+    // not the first half of a `\r\n`, so CRLF and CR-only sources both count
+    // one line per break.
+    fn is_line_break(&self, c: u8) -> bool {
+        match c {
+            b'\n' => true,
+            b'\r' => self.peek_next() != Some(b'\n'),
+            _ => false,
+        }
     }
 
     fn skip_if_comment(&mut self) -> bool {
-        if let Some('/') = self.peek_next() {
+        if let Some(b'/') = self.peek_next() {
             while let Some(cc) = self.peek() {
-                if cc == '\n' {
+                if cc == b'\n' || cc == b'\r' {
                     break;
                 }
                 self.advance();
@@ -344,39 +581,31 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn sync_start(&mut self) {
-        while self.cur_len > 0 {
-            let _ = self.start.next();
-            self.cur_len -= 1;
-        }
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.current).copied()
     }
 
-    fn peek(&mut self) -> Option<char> {
-        if let Some(la) = self.look_ahead {
-            return Some(la);
-        }
-        self.current.peek().map(|c| *c)
+    fn peek_next(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.current + 1).copied()
     }
+}
 
-    fn peek_next(&mut self) -> Option<char> {
-        if self.look_ahead.is_some() {
-            return self.current.peek().map(|c| *c);
-        }
-        self.look_ahead = self.current.next();
-        self.current.peek().map(|c| *c)
-    }
+// Scans the whole source, ending with an `Eof` token on its last line. The
+// tokens borrow their text from the source.
+pub fn tokens(source: &str) -> Vec<Token<'_>> {
+    let mut scanner = Scanner::new(source);
+    let mut tokens: Vec<Token> = scanner.by_ref().collect();
+    tokens.push(scanner.make_token(TokenType::Eof));
+    tokens
 }
 
 impl<'a> Iterator for Scanner<'a> {
-    type Item = Token;
+    type Item = Token<'a>;
 
-    fn next(&mut self) -> Option<Token> {
+    fn next(&mut self) -> Option<Token<'a>> {
         self.skip_whitespaces();
         let c = self.advance();
-        match c {
-            Some(c) => Some(self.match_char(c)),
-            None => None,
-        }
+        c.map(|c| self.match_char(c))
     }
 }
 
@@ -385,9 +614,67 @@ mod tests {
     use self::super::*;
     use super::TokenType::*;
 
+    #[test]
+    fn token_type_display() {
+        assert_eq!("Number", Number(1.0).to_string());
+        assert_eq!("Integer", Integer(1).to_string());
+        assert_eq!("Identifier", Identifier("a").to_string());
+        assert_eq!("QuestionQuestionEqual", QuestionQuestionEqual.to_string());
+    }
+
+    #[test]
+    fn tokens_end_with_eof() {
+        let token = |t_type, column, lexeme| Token {
+            t_type,
+            line: 1,
+            column,
+            lexeme,
+        };
+
+        assert_eq!(
+            vec![
+                token(Integer(1), 1, "1"),
+                token(Plus, 2, "+"),
+                token(Integer(2), 3, "2"),
+                token(Eof, 4, ""),
+            ],
+            tokens("1+2")
+        );
+        assert_eq!(3, tokens("1\n2 // two\n").last().unwrap().line);
+    }
+
+    #[test]
+    fn starting_line() {
+        let mut scanner = Scanner::with_starting_line("a\nb", 10);
+
+        assert_eq!(t(Identifier("a"), 10), scanner.next());
+        assert_eq!(t(Identifier("b"), 11), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn clone_scans_independently() {
+        let mut scanner = Scanner::new("a + b\n* c");
+        assert_eq!(t(ident("a"), 1), scanner.next());
+
+        let mut ahead = scanner.clone();
+        assert_eq!(t(Plus, 1), ahead.next());
+        assert_eq!(t(ident("b"), 1), ahead.next());
+        assert_eq!(t(Star, 2), ahead.next());
+        assert_eq!(2, ahead.line());
+
+        assert_eq!(1, scanner.line());
+        assert_eq!(t(Plus, 1), scanner.next());
+        assert_eq!(t(ident("b"), 1), scanner.next());
+        assert_eq!(t(Star, 2), scanner.next());
+        assert_eq!(t(ident("c"), 2), scanner.next());
+        assert_eq!(t(ident("c"), 2), ahead.next());
+        assert_eq!(None, scanner.next());
+    }
+
     #[test]
     fn punctuation_scan() {
-        let source = "/* != = +\n <  (){}\n!";
+        let source = "/* != = +\n <  (){}[]\n!";
         let mut scanner = Scanner::new(source);
 
         assert_eq!(t(Slash, 1), scanner.next());
@@ -401,11 +688,80 @@ mod tests {
         assert_eq!(t(RightParen, 2), scanner.next());
         assert_eq!(t(LeftBrace, 2), scanner.next());
         assert_eq!(t(RightBrace, 2), scanner.next());
+        assert_eq!(t(LeftBracket, 2), scanner.next());
+        assert_eq!(t(RightBracket, 2), scanner.next());
         assert_eq!(t(Bang, 3), scanner.next());
 
         assert_eq!(None, scanner.next());
     }
 
+    #[test]
+    fn star_star_and_percent() {
+        let mut scanner = Scanner::new("% * ** ***");
+
+        assert_eq!(t(Percent, 1), scanner.next());
+        assert_eq!(t(Star, 1), scanner.next());
+        assert_eq!(t(StarStar, 1), scanner.next());
+        assert_eq!(t(StarStar, 1), scanner.next());
+        assert_eq!(t(Star, 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn compound_assignment_operators() {
+        let mut scanner = Scanner::new("+= -= *= /= **= + =");
+
+        assert_eq!(t(PlusEqual, 1), scanner.next());
+        assert_eq!(t(MinusEqual, 1), scanner.next());
+        assert_eq!(t(StarEqual, 1), scanner.next());
+        assert_eq!(t(SlashEqual, 1), scanner.next());
+        assert_eq!(t(StarStar, 1), scanner.next());
+        assert_eq!(t(Equal, 1), scanner.next());
+        assert_eq!(t(Plus, 1), scanner.next());
+        assert_eq!(t(Equal, 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let mut scanner = Scanner::new("++a b-- ---c +++=");
+
+        assert_eq!(t(PlusPlus, 1), scanner.next());
+        assert_eq!(t(Identifier("a"), 1), scanner.next());
+        assert_eq!(t(Identifier("b"), 1), scanner.next());
+        assert_eq!(t(MinusMinus, 1), scanner.next());
+        assert_eq!(t(MinusMinus, 1), scanner.next());
+        assert_eq!(t(Minus, 1), scanner.next());
+        assert_eq!(t(Identifier("c"), 1), scanner.next());
+        assert_eq!(t(PlusPlus, 1), scanner.next());
+        assert_eq!(t(PlusEqual, 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn conditional_operator() {
+        let source = "a ? b : c";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(Identifier("a"), 1), scanner.next());
+        assert_eq!(t(Question, 1), scanner.next());
+        assert_eq!(t(Identifier("b"), 1), scanner.next());
+        assert_eq!(t(Colon, 1), scanner.next());
+        assert_eq!(t(Identifier("c"), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn nil_coalescing_assignment() {
+        let source = "??= ?? =";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(QuestionQuestionEqual, 1), scanner.next());
+        assert_eq!(t(Error("Unexpected character"), 1), scanner.next());
+        assert_eq!(t(Equal, 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
     #[test]
     fn comments_scan() {
         let source = "+ // fr2f34f23f24;\n//\n/\n///";
@@ -428,21 +784,127 @@ mod tests {
         assert_eq!(None, scanner.next());
     }
 
+    #[test]
+    fn crlf_line_endings() {
+        let source = "1\r\n2 // c\r\n\"a\r\nb\"\r\n3";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(Integer(1), 1), scanner.next());
+        assert_eq!(t(Integer(2), 2), scanner.next());
+        assert_eq!(t(string("a\r\nb"), 4), scanner.next());
+        assert_eq!(t(Integer(3), 5), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn cr_line_endings() {
+        let source = "1\r2 // c\r\"a\rb\"\r\r3";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(Integer(1), 1), scanner.next());
+        assert_eq!(t(Integer(2), 2), scanner.next());
+        assert_eq!(t(string("a\rb"), 4), scanner.next());
+        assert_eq!(t(Integer(3), 6), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
     #[test]
     fn numbers() {
         let source = "456 326.3 644..";
         let mut scanner = Scanner::new(source);
 
-        assert_eq!(t(Number(456.0), 1), scanner.next());
+        assert_eq!(t(Integer(456), 1), scanner.next());
         assert_eq!(t(Number(326.3), 1), scanner.next());
-        assert_eq!(t(Number(644.0), 1), scanner.next());
+        assert_eq!(t(Integer(644), 1), scanner.next());
+        assert_eq!(t(Dot, 1), scanner.next());
+        assert_eq!(t(Dot, 1), scanner.next());
+    }
+
+    #[test]
+    fn integers_too_big_for_i64_are_doubles() {
+        let mut scanner = Scanner::new("9223372036854775807 9223372036854775808");
+
+        assert_eq!(t(Integer(i64::MAX), 1), scanner.next());
+        assert_eq!(t(Number(9_223_372_036_854_775_808.0), 1), scanner.next());
+    }
+
+    #[test]
+    fn numbers_with_several_dots() {
+        let source = "1.2.3 4. .5";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(Number(1.2), 1), scanner.next());
         assert_eq!(t(Dot, 1), scanner.next());
+        assert_eq!(t(Integer(3), 1), scanner.next());
+        assert_eq!(t(Integer(4), 1), scanner.next());
         assert_eq!(t(Dot, 1), scanner.next());
+        assert_eq!(t(Dot, 1), scanner.next());
+        assert_eq!(t(Integer(5), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn leading_byte_order_mark() {
+        let mut scanner = Scanner::new("\u{FEFF}1 + 2");
+
+        assert_eq!(t(Integer(1), 1), scanner.next());
+        assert_eq!(t(Plus, 1), scanner.next());
+        assert_eq!(t(Integer(2), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+
+        let mut scanner = Scanner::new("1 \u{FEFF}");
+        assert_eq!(t(Integer(1), 1), scanner.next());
+        assert_eq!(t(Error("Unexpected character"), 1), scanner.next());
+    }
+
+    #[test]
+    fn peek_next_twice() {
+        let mut scanner = Scanner::new("abc");
+
+        assert_eq!(Some(b'a'), scanner.peek());
+        assert_eq!(Some(b'b'), scanner.peek_next());
+        assert_eq!(Some(b'b'), scanner.peek_next());
+        assert_eq!(Some(b'a'), scanner.peek());
+        assert_eq!(Some(b'a'), scanner.advance());
+        assert_eq!(Some(b'b'), scanner.peek());
+        assert_eq!(Some(b'c'), scanner.peek_next());
+        assert_eq!(Some(b'b'), scanner.advance());
+        assert_eq!(Some(b'c'), scanner.advance());
+        assert_eq!(None, scanner.peek());
+        assert_eq!(None, scanner.peek_next());
+        assert_eq!(None, scanner.advance());
+        assert_eq!(3, scanner.current);
+    }
+
+    #[test]
+    fn peek_next_at_the_end() {
+        let mut scanner = Scanner::new("a");
+
+        assert_eq!(None, scanner.peek_next());
+        assert_eq!(Some(b'a'), scanner.peek());
+        assert_eq!(None, scanner.peek_next());
+        assert_eq!(Some(b'a'), scanner.advance());
+        assert_eq!(None, scanner.peek());
+        assert_eq!(None, scanner.advance());
+    }
+
+    #[test]
+    fn comment_after_look_ahead() {
+        let source = "1.a // 2\n/3";
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(Integer(1), 1), scanner.next());
+        assert_eq!(t(Dot, 1), scanner.next());
+        assert_eq!(t(ident("a"), 1), scanner.next());
+        assert_eq!(t(Slash, 2), scanner.next());
+        assert_eq!(t(Integer(3), 2), scanner.next());
+        assert_eq!(None, scanner.next());
     }
 
     #[test]
     fn keywords_and_identifiers() {
-        let source = "this falsefied false t that bad class";
+        let source =
+            "this falsefied false t that bad class break continue con switch case default s";
         let mut scanner = Scanner::new(source);
 
         assert_eq!(t(This, 1), scanner.next());
@@ -452,18 +914,189 @@ mod tests {
         assert_eq!(t(ident("that"), 1), scanner.next());
         assert_eq!(t(ident("bad"), 1), scanner.next());
         assert_eq!(t(Class, 1), scanner.next());
+        assert_eq!(t(Break, 1), scanner.next());
+        assert_eq!(t(Continue, 1), scanner.next());
+        assert_eq!(t(ident("con"), 1), scanner.next());
+        assert_eq!(t(Switch, 1), scanner.next());
+        assert_eq!(t(Case, 1), scanner.next());
+        assert_eq!(t(Default, 1), scanner.next());
+        assert_eq!(t(ident("s"), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn every_keyword() {
+        let keywords = [
+            ("and", And),
+            ("break", Break),
+            ("case", Case),
+            ("class", Class),
+            ("continue", Continue),
+            ("default", Default),
+            ("else", Else),
+            ("false", False),
+            ("for", For),
+            ("fun", Fun),
+            ("if", If),
+            ("nil", Nil),
+            ("or", Or),
+            ("print", Print),
+            ("return", Return),
+            ("super", Super),
+            ("switch", Switch),
+            ("this", This),
+            ("true", True),
+            ("var", Var),
+            ("while", While),
+        ];
+        for (lexeme, keyword) in keywords.iter().cloned() {
+            assert_eq!(t(keyword, 1), Scanner::new(lexeme).next(), "{}", lexeme);
+
+            // One character short or long of a keyword is an identifier.
+            let short = &lexeme[..lexeme.len() - 1];
+            let long = format!("{}s", lexeme);
+            for near_miss in &[short, &long, &lexeme.to_uppercase()] {
+                match Scanner::new(near_miss).next() {
+                    Some(Token {
+                        t_type: Identifier(_),
+                        line: 1,
+                        ..
+                    }) => {}
+                    other => panic!(
+                        "Expected '{}' to be an identifier, got {:?}",
+                        near_miss, other
+                    ),
+                }
+            }
+        }
+        assert_eq!(t(ident("thatch"), 1), Scanner::new("thatch").next());
+        assert_eq!(t(ident("_if"), 1), Scanner::new("_if").next());
+    }
+
+    #[test]
+    fn too_long_tokens() {
+        let source = "abcd abcdefgh \"abcdefgh\" 1234 123456789 +";
+        let mut scanner = Scanner::with_max_token_length(source, 4);
+
+        assert_eq!(t(ident("abcd"), 1), scanner.next());
+        assert_eq!(t(Error("Token too long"), 1), scanner.next());
+        assert_eq!(t(Error("Token too long"), 1), scanner.next());
+        assert_eq!(t(Integer(1234), 1), scanner.next());
+        assert_eq!(t(Error("Token too long"), 1), scanner.next());
+        assert_eq!(t(Plus, 1), scanner.next());
         assert_eq!(None, scanner.next());
     }
 
-    fn t(t_type: TokenType, line: usize) -> Option<Token> {
-        Some(Token { t_type, line })
+    #[test]
+    fn multi_byte_chars() {
+        let mut scanner = Scanner::new("\"héllo 🌍\" é a");
+
+        assert_eq!(t(string("héllo 🌍"), 1), scanner.next());
+        assert_eq!(t(Error("Unexpected character"), 1), scanner.next());
+        assert_eq!(t(ident("a"), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn lexemes_are_the_source_text() {
+        let source = "\"héllo\\n 🌍\" 1.50 ünï";
+        let lexemes: Vec<_> = Scanner::new(source).map(|token| token.lexeme).collect();
+
+        assert_eq!(vec!["\"héllo\\n 🌍\"", "1.50", "ü", "n", "ï"], lexemes);
+    }
+
+    #[test]
+    fn identifiers_and_strings_borrow_from_the_source() {
+        let source = "alpha beta_gamma \"delta\" ".repeat(1000);
+        let range = source.as_bytes().as_ptr_range();
+
+        for token in Scanner::new(&source) {
+            let lexeme = match token.t_type {
+                Identifier(name) => name,
+                String(Cow::Borrowed(s)) => s,
+                other => panic!("Unexpected token: {:?}", other),
+            };
+            assert!(range.contains(&lexeme.as_ptr()));
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        let source = r#""\u{41}" "\u{1F600}!" "a\"b\\" "\n\t" "\u{110000}" "\u{}" "\q" "\u{41""#;
+        let mut scanner = Scanner::new(source);
+
+        assert_eq!(t(string("A"), 1), scanner.next());
+        assert_eq!(t(string("😀!"), 1), scanner.next());
+        assert_eq!(t(string("a\"b\\"), 1), scanner.next());
+        assert_eq!(t(string("\n\t"), 1), scanner.next());
+        assert_eq!(t(Error("Invalid unicode escape"), 1), scanner.next());
+        assert_eq!(t(Error("Invalid unicode escape"), 1), scanner.next());
+        assert_eq!(t(Error("Invalid escape sequence"), 1), scanner.next());
+        assert_eq!(t(Error("Invalid unicode escape"), 1), scanner.next());
+        assert_eq!(None, scanner.next());
+    }
+
+    #[test]
+    fn columns_count_chars_from_the_start_of_the_line() {
+        let columns: Vec<_> = Scanner::new("var é = 1;\n  x\r\n\t\"ab\" y")
+            .map(|token| (token.line, token.column))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (3, 2),
+                (3, 7)
+            ],
+            columns
+        );
+    }
+
+    #[test]
+    fn large_source() {
+        let line = "var abc = \"text\" + 12.5 * (x - y); // comment\n";
+        let source = line.repeat(20_000);
+
+        let scanner = Scanner::new(&source);
+        let mut count = 0;
+        let mut last_line = 0;
+        for token in scanner {
+            assert!(!matches!(token.t_type, Error(_)));
+            count += 1;
+            last_line = token.line;
+        }
+
+        assert_eq!(13 * 20_000, count);
+        assert_eq!(20_000, last_line);
+    }
+
+    // A token's type and line. Columns are checked on their own.
+    #[derive(Debug)]
+    struct Expected<'a>(TokenType<'a>, usize);
+
+    impl<'a> PartialEq<Option<Token<'a>>> for Expected<'a> {
+        fn eq(&self, token: &Option<Token<'a>>) -> bool {
+            match token {
+                Some(token) => self.0 == token.t_type && self.1 == token.line,
+                None => false,
+            }
+        }
+    }
+
+    fn t(t_type: TokenType, line: usize) -> Expected {
+        Expected(t_type, line)
     }
 
-    fn string(lexeme: &'static str) -> TokenType {
-        String(lexeme.to_string())
+    fn string(lexeme: &'static str) -> TokenType<'static> {
+        String(Cow::Borrowed(lexeme))
     }
 
-    fn ident(lexeme: &'static str) -> TokenType {
-        Identifier(lexeme.to_string())
+    fn ident(lexeme: &'static str) -> TokenType<'static> {
+        Identifier(lexeme)
     }
 }
@@ -0,0 +1,477 @@
+// Binary encoding of compiled chunks, so scripts can be compiled ahead of
+// time. All integers are little-endian `u32`s unless noted otherwise.
+//
+//   chunk    := magic version instructions lines constants
+//   magic    := "RVLX"
+//   version  := u8
+//   instructions := count (opcode u8 operand*)*, see `Instruction::opcode`
+//   lines    := count (line count)*
+//   constants := count constant*
+//   constant := 0 (nil) | 1 u8 (bool) | 2 u64 (f64 bits) | 3 string
+//             | 4 u8 [string] arity chunk (function, optional name)
+//             | 5 u64 (i64)
+//   string   := length utf-8-bytes
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use common::*;
+use interner::Str;
+use value::*;
+
+pub const MAGIC: &[u8; 4] = b"RVLX";
+pub const VERSION: u8 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_DOUBLE: u8 = 2;
+const TAG_STR: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+const TAG_INT: u8 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    UnknownOpcode(u8),
+    UnknownConstantTag(u8),
+    InvalidUtf8,
+    TrailingBytes,
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "Not an rvlox bytecode file"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {}", v),
+            DecodeError::UnexpectedEnd => write!(f, "Unexpected end of bytecode"),
+            DecodeError::UnknownOpcode(o) => write!(f, "Unknown opcode {}", o),
+            DecodeError::UnknownConstantTag(t) => write!(f, "Unknown constant tag {}", t),
+            DecodeError::InvalidUtf8 => write!(f, "Invalid UTF-8 in a string constant"),
+            DecodeError::TrailingBytes => write!(f, "Unexpected bytes after the chunk"),
+            DecodeError::Invalid(error) => write!(f, "Invalid bytecode: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    // A count or length that doesn't fit in the format's `u32`.
+    TooLarge(usize),
+    // A constant of a type only created at runtime, such as a native.
+    UnsupportedConstant(&'static str),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooLarge(n) => write!(f, "{} is too large for the bytecode format", n),
+            EncodeError::UnsupportedConstant(type_name) => {
+                write!(f, "Can not serialize a {} constant", type_name)
+            }
+        }
+    }
+}
+
+impl Chunk {
+    pub fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_chunk(self, &mut out)?;
+        Ok(out)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, DecodeError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.read_bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let chunk = reader.read_chunk()?;
+        if reader.position != bytes.len() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        // The bytes may come from anywhere. Verifying catches operands out
+        // of range; the VM reports what it can't check, such as local slots,
+        // as runtime errors.
+        chunk.verify().map_err(DecodeError::Invalid)?;
+        Ok(chunk)
+    }
+}
+
+fn write_chunk(chunk: &Chunk, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    write_u32(chunk.len(), out)?;
+    for instruction in &chunk.instructions {
+        out.push(instruction.opcode());
+        for operand in instruction.operands() {
+            write_u32(operand, out)?;
+        }
+    }
+
+    write_u32(chunk.lines.len(), out)?;
+    for &(line, count) in &chunk.lines {
+        write_u32(line, out)?;
+        write_u32(count, out)?;
+    }
+
+    write_u32(chunk.constants.len(), out)?;
+    for constant in &chunk.constants {
+        write_constant(constant, out)?;
+    }
+    Ok(())
+}
+
+fn write_constant(constant: &Value, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    match constant {
+        Value::Nil => out.push(TAG_NIL),
+        Value::Bool(b) => {
+            out.push(TAG_BOOL);
+            out.push(*b as u8);
+        }
+        Value::Double(d) => {
+            out.push(TAG_DOUBLE);
+            out.extend_from_slice(&d.to_bits().to_le_bytes());
+        }
+        Value::Int(i) => {
+            out.push(TAG_INT);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Str(s) => {
+            out.push(TAG_STR);
+            write_str(s, out)?;
+        }
+        Value::Function(function) => {
+            out.push(TAG_FUNCTION);
+            match function.name {
+                Some(ref name) => {
+                    out.push(1);
+                    write_str(name, out)?;
+                }
+                None => out.push(0),
+            }
+            write_u32(function.arity, out)?;
+            write_chunk(&function.chunk, out)?;
+        }
+        Value::Native(_)
+        | Value::Class(_)
+        | Value::Instance(_)
+        | Value::BoundMethod(_)
+        | Value::List(_)
+        | Value::Map(_) => return Err(EncodeError::UnsupportedConstant(constant.type_name())),
+    }
+    Ok(())
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    write_u32(s.len(), out)?;
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn write_u32(n: usize, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    let n = u32::try_from(n).map_err(|_| EncodeError::TooLarge(n))?;
+    out.extend_from_slice(&n.to_le_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_chunk(&mut self) -> Result<Chunk, DecodeError> {
+        let mut chunk = Chunk::new();
+
+        for _ in 0..self.read_u32()? {
+            let instruction = self.read_instruction()?;
+            chunk.instructions.push(instruction);
+        }
+
+        for _ in 0..self.read_u32()? {
+            let line = self.read_u32()?;
+            let count = self.read_u32()?;
+            chunk.lines.push((line, count));
+        }
+
+        for _ in 0..self.read_u32()? {
+            let constant = self.read_constant()?;
+            chunk.constants.push(constant);
+        }
+
+        Ok(chunk)
+    }
+
+    fn read_instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let opcode = self.read_u8()?;
+        let operand_count =
+            Instruction::operand_count_of(opcode).ok_or(DecodeError::UnknownOpcode(opcode))?;
+        let mut operands = Vec::with_capacity(operand_count);
+        for _ in 0..operand_count {
+            operands.push(self.read_u32()?);
+        }
+        Instruction::from_opcode_and_operands(opcode, &operands)
+            .ok_or(DecodeError::UnknownOpcode(opcode))
+    }
+
+    fn read_constant(&mut self) -> Result<Value, DecodeError> {
+        let tag = self.read_u8()?;
+        let constant = match tag {
+            TAG_NIL => Value::Nil,
+            TAG_BOOL => Value::Bool(self.read_u8()? != 0),
+            TAG_DOUBLE => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(self.read_bytes(8)?);
+                Value::Double(f64::from_bits(u64::from_le_bytes(bits)))
+            }
+            TAG_INT => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.read_bytes(8)?);
+                Value::Int(i64::from_le_bytes(bytes))
+            }
+            TAG_STR => Value::Str(Str::from(self.read_str()?)),
+            TAG_FUNCTION => {
+                let name = match self.read_u8()? {
+                    0 => None,
+                    _ => Some(self.read_str()?),
+                };
+                let mut function = Function::new(name);
+                function.arity = self.read_u32()?;
+                function.chunk = self.read_chunk()?;
+                Value::Function(Rc::new(function))
+            }
+            _ => return Err(DecodeError::UnknownConstantTag(tag)),
+        };
+        Ok(constant)
+    }
+
+    fn read_str(&mut self) -> Result<String, DecodeError> {
+        let len = self.read_u32()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn read_u32(&mut self) -> Result<usize, DecodeError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.position < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use compiler::compile;
+    use natives;
+    use vm::{InterpretResult, RuntimeError, RuntimeErrorWithLine, VM};
+
+    #[test]
+    fn serialize_header() {
+        let bytes = Chunk::new().serialize().unwrap();
+
+        assert_eq!(b"RVLX", &bytes[0..4]);
+        assert_eq!(VERSION, bytes[4]);
+        // Three empty sections.
+        assert_eq!(5 + 3 * 4, bytes.len());
+    }
+
+    #[test]
+    fn serialize_chunk() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.5));
+        let s = chunk.add_constant(Value::Str(Str::from("ab")));
+        chunk.add_instruction(Instruction::Constant(c), 1);
+        chunk.add_instruction(Instruction::Print, 1);
+        chunk.add_instruction(Instruction::Nil, 2);
+        chunk.add_instruction(Instruction::Return, 2);
+        assert_eq!(1, s);
+
+        let bytes = chunk.serialize().unwrap();
+
+        let header = 5;
+        let instructions = 4 + (1 + 4) + 3;
+        let lines = 4 + 2 * 8;
+        let constants = 4 + (1 + 8) + (1 + 4 + 2);
+        assert_eq!(header + instructions + lines + constants, bytes.len());
+        assert_eq!(&[1, 0, 0, 0, 0], &bytes[9..14]);
+    }
+
+    #[test]
+    fn serialize_function_constants() {
+        let mut function = Function::new(Some("f".to_string()));
+        function.arity = 2;
+        function.chunk.add_instruction(Instruction::Return, 1);
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Function(Rc::new(function)));
+
+        let bytes = chunk.serialize().unwrap();
+
+        let nested = 4 + 1 + 4 + 8 + 4;
+        let function = 1 + 1 + (4 + 1) + 4 + nested;
+        assert_eq!(5 + 4 + 4 + 4 + function, bytes.len());
+    }
+
+    #[test]
+    fn deserialized_chunks_with_bad_slots_fail_at_run_time() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::GetLocal(99), 1);
+        chunk.add_instruction(Instruction::Return, 1);
+        let decoded = Chunk::deserialize(&chunk.serialize().unwrap()).unwrap();
+
+        match VM::new().interpret(&decoded) {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::InvalidLocal(99),
+                _,
+                _,
+            )) => {}
+            other => panic!("Expected an invalid local, got: {:?}", other),
+        }
+
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::Str(Str::from("m")));
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Nil, 1);
+        chunk.add_instruction(Instruction::Method(name), 1);
+        chunk.add_instruction(Instruction::Return, 1);
+        let decoded = Chunk::deserialize(&chunk.serialize().unwrap()).unwrap();
+
+        match VM::new().interpret(&decoded) {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::TypeMismatch { op: "method", .. },
+                _,
+                _,
+            )) => {}
+            other => panic!("Expected a type mismatch, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_round_trip() {
+        let chunk = compile(
+            "fun f(a, b) { if (a > b) return a; return \"b\"; } var x = nil; print f(1.5, true);",
+        )
+        .expect("compile error");
+
+        let decoded = Chunk::deserialize(&chunk.serialize().unwrap()).unwrap();
+
+        // Function constants compare by identity, so the disassembly, which
+        // covers their chunks, is compared instead.
+        assert_eq!(
+            chunk.disassemble_to_string(),
+            decoded.disassemble_to_string()
+        );
+        assert_eq!(chunk.len(), decoded.len());
+    }
+
+    #[test]
+    fn deserialize_bad_magic() {
+        let mut bytes = Chunk::new().serialize().unwrap();
+        bytes[0] = b'X';
+
+        assert_eq!(Err(DecodeError::BadMagic), Chunk::deserialize(&bytes));
+        assert_eq!(Err(DecodeError::BadMagic), Chunk::deserialize(b"RV"));
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        let mut bytes = Chunk::new().serialize().unwrap();
+        bytes[4] = 99;
+        assert_eq!(
+            Err(DecodeError::UnsupportedVersion(99)),
+            Chunk::deserialize(&bytes)
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Nil, 1);
+        let mut bytes = chunk.serialize().unwrap();
+        bytes[9] = 200;
+        assert_eq!(
+            Err(DecodeError::UnknownOpcode(200)),
+            Chunk::deserialize(&bytes)
+        );
+
+        let bytes = chunk.serialize().unwrap();
+        assert_eq!(
+            Err(DecodeError::UnexpectedEnd),
+            Chunk::deserialize(&bytes[..bytes.len() - 1])
+        );
+    }
+
+    #[test]
+    fn serialize_rejects_runtime_constants() {
+        let mut chunk = Chunk::new();
+        let class = Class::new("A".to_string());
+        chunk.add_constant(Value::Class(Rc::new(class)));
+        let error = chunk.serialize().unwrap_err();
+        assert_eq!(EncodeError::UnsupportedConstant("class"), error);
+        assert_eq!("Can not serialize a class constant", error.to_string());
+
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Native(Native::new(natives::clock, 0..=0)));
+        assert_eq!(
+            Err(EncodeError::UnsupportedConstant("function")),
+            chunk.serialize()
+        );
+    }
+
+    #[test]
+    fn serialize_rejects_counts_past_u32() {
+        let mut out = Vec::new();
+        let too_large = u32::MAX as usize + 1;
+
+        assert_eq!(
+            Err(EncodeError::TooLarge(too_large)),
+            write_u32(too_large, &mut out)
+        );
+        assert!(out.is_empty());
+        assert_eq!(Ok(()), write_u32(u32::MAX as usize, &mut out));
+    }
+
+    #[test]
+    fn deserialize_verifies_the_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.add_instruction(Instruction::Jump(5), 1);
+        assert_eq!(
+            Err(DecodeError::Invalid(VerifyError::JumpOutOfRange {
+                at: 0,
+                name: "OP_JUMP",
+                target: 5,
+                len: 1,
+            })),
+            Chunk::deserialize(&chunk.serialize().unwrap())
+        );
+
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Nil);
+        chunk.add_instruction(Instruction::GetGlobal(c), 1);
+        assert_eq!(
+            Err(DecodeError::Invalid(VerifyError::NotAName {
+                at: 0,
+                name: "OP_GET_GLOBAL",
+                index: 0,
+            })),
+            Chunk::deserialize(&chunk.serialize().unwrap())
+        );
+    }
+}
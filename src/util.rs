@@ -1,10 +1,14 @@
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::io::{self};
+use std::io::{self, BufRead, Write};
 use std::process;
 
-use vm::interpret_source;
-use vm::InterpretResult;
+use compiler::{compile, use_color};
+use scanner::{tokens, TokenType};
+use vm::DEFAULT_STACK_MAX;
+use vm::VM;
+use vm::{InterpretResult, RuntimeErrorWithLine};
 
 pub fn read_file_to_string(file_name: &str) -> io::Result<String> {
     let mut file = File::open(file_name)?;
@@ -13,38 +17,669 @@ pub fn read_file_to_string(file_name: &str) -> io::Result<String> {
     Ok(source)
 }
 
+// `-` on the command line names standard input.
+#[derive(Debug, PartialEq)]
+pub enum ScriptSource {
+    File(String),
+    Stdin,
+}
+
+impl ScriptSource {
+    fn from_arg(arg: &str) -> ScriptSource {
+        if arg == "-" {
+            ScriptSource::Stdin
+        } else {
+            ScriptSource::File(arg.to_string())
+        }
+    }
+}
+
+pub fn read_script<R: Read>(script: &ScriptSource, mut stdin: R) -> io::Result<String> {
+    match script {
+        ScriptSource::File(file_name) => read_file_to_string(file_name),
+        ScriptSource::Stdin => {
+            let mut source = String::new();
+            stdin.read_to_string(&mut source)?;
+            Ok(source)
+        }
+    }
+}
+
+// Flags that change how a script is run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub trace: bool,
+    // The number of values the stack may hold before calls fail.
+    pub stack_size: Option<usize>,
+    // Print how long each script took to compile and run.
+    pub time: bool,
+    // Never color errors, even on a terminal.
+    pub no_color: bool,
+}
+
 #[derive(Debug)]
 pub enum RunningMode {
-    Script(String),
-    Repl,
+    Scripts(Vec<ScriptSource>, Options),
+    DumpBytecode(ScriptSource, Options),
+    DumpTokens(ScriptSource),
+    Repl(Options),
+    Help,
+    Version,
+}
+
+pub const USAGE: &str =
+    "Usage: rlox [--dump-bytecode] [--tokens] [--trace] [--time] [--stack-size N] [--no-color] [script...]";
+
+pub const HELP: &str = "Usage: rlox [options] [script...]
+
+Runs the scripts one after another in the same VM, so globals defined by
+one script are visible to the next. Starts a repl when no script is given.
+A script named - is read from standard input.
+
+Options:
+  --dump-bytecode  Print the compiled bytecode instead of running the script
+  --tokens         Print the tokens of the script instead of running it
+  --trace          Print the stack and each instruction as the script runs
+  --time           Print how long each script took to compile and run
+  --stack-size N   Let the stack hold up to N values, for deep recursion
+  --no-color       Don't color errors, which are only colored on a terminal
+  -h, --help       Print this help
+  -V, --version    Print the version";
+
+#[derive(Debug, PartialEq)]
+pub enum ArgError {
+    // A dump was asked for more than one script.
+    TooManyArgs,
+    UnknownFlag(String),
+    // A flag that only applies to scripts was given without one.
+    MissingScriptForFlag,
+    // Both `--dump-bytecode` and `--tokens` were given.
+    ConflictingFlags,
+    MissingValueForFlag(String),
+    InvalidValueForFlag { flag: String, value: String },
+    // Stdin can only be read once.
+    RepeatedStdin,
+}
+
+impl ArgError {
+    // EX_USAGE from sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        64
+    }
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::TooManyArgs => write!(f, "Only a single script can be dumped"),
+            ArgError::UnknownFlag(flag) => write!(f, "Unknown flag '{}'", flag),
+            ArgError::MissingScriptForFlag => write!(f, "Expect a script after the flags"),
+            ArgError::ConflictingFlags => {
+                write!(f, "--dump-bytecode and --tokens can't be used together")
+            }
+            ArgError::MissingValueForFlag(flag) => write!(f, "Expect a value after '{}'", flag),
+            ArgError::InvalidValueForFlag { flag, value } => {
+                write!(f, "Invalid value '{}' for '{}'", value, flag)
+            }
+            ArgError::RepeatedStdin => write!(f, "'-' can only be given once"),
+        }
+    }
+}
+
+pub fn parse_args_for_running_mode(args: &[String]) -> Result<RunningMode, ArgError> {
+    let mut dump_bytecode = false;
+    let mut dump_tokens = false;
+    let mut options = Options::default();
+    let mut scripts = Vec::new();
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(RunningMode::Help),
+            "--version" | "-V" => return Ok(RunningMode::Version),
+            "--dump-bytecode" => dump_bytecode = true,
+            "--tokens" => dump_tokens = true,
+            "--trace" => options.trace = true,
+            "--time" => options.time = true,
+            "--no-color" => options.no_color = true,
+            flag @ "--stack-size" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ArgError::MissingValueForFlag(flag.to_string()))?;
+                match value.parse() {
+                    Ok(size) if size > 0 => options.stack_size = Some(size),
+                    _ => {
+                        return Err(ArgError::InvalidValueForFlag {
+                            flag: flag.to_string(),
+                            value: value.to_string(),
+                        })
+                    }
+                }
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(ArgError::UnknownFlag(flag.to_string()))
+            }
+            "-" if scripts.contains(&ScriptSource::Stdin) => return Err(ArgError::RepeatedStdin),
+            _ => scripts.push(ScriptSource::from_arg(arg)),
+        }
+    }
+
+    // Only `--no-color` also applies to the repl.
+    if scripts.is_empty() {
+        let repl_options = Options {
+            no_color: options.no_color,
+            ..Options::default()
+        };
+        return if dump_bytecode || dump_tokens || options != repl_options {
+            Err(ArgError::MissingScriptForFlag)
+        } else {
+            Ok(RunningMode::Repl(options))
+        };
+    }
+
+    // The dumps work on a single script.
+    match (dump_bytecode, dump_tokens) {
+        (false, false) => Ok(RunningMode::Scripts(scripts, options)),
+        (true, true) => Err(ArgError::ConflictingFlags),
+        _ if scripts.len() > 1 => Err(ArgError::TooManyArgs),
+        (true, false) => Ok(RunningMode::DumpBytecode(scripts.remove(0), options)),
+        (false, true) => Ok(RunningMode::DumpTokens(scripts.remove(0))),
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Io(io::Error),
+    Compile,
+    Runtime,
+}
+
+impl RunError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Runtime => 1,
+            RunError::Io(_) | RunError::Compile => 2,
+        }
+    }
 }
 
-pub fn parse_args_for_running_mode(args: &Vec<String>) -> Result<RunningMode, &'static str> {
-    if args.len() > 2 {
-        return Err("Usage: rlox [script]");
+impl From<io::Error> for RunError {
+    fn from(err: io::Error) -> RunError {
+        RunError::Io(err)
     }
+}
+
+pub fn run_files(scripts: &[ScriptSource], options: Options) -> Result<(), RunError> {
+    run_scripts(scripts, options, &mut io::stdout().lock())
+}
+
+// Runs the scripts in order in one VM. Stops at the first script that can't
+// be read or fails.
+pub fn run_scripts<W: Write>(
+    scripts: &[ScriptSource],
+    options: Options,
+    output: &mut W,
+) -> Result<(), RunError> {
+    run_scripts_timed(scripts, options, io::stdin(), output, &mut io::stderr())
+}
+
+// Like `run_scripts`, reading `-` from `stdin` and writing the durations
+// `--time` asks for to `timing`.
+fn run_scripts_timed<R: Read, W: Write, T: Write>(
+    scripts: &[ScriptSource],
+    options: Options,
+    mut stdin: R,
+    output: &mut W,
+    timing: &mut T,
+) -> Result<(), RunError> {
+    let color = use_color(options.no_color);
+    let mut vm = VM::new()
+        .with_stack_max(options.stack_size.unwrap_or(DEFAULT_STACK_MAX))
+        .with_trace(options.trace)
+        .with_color(color);
+    for script in scripts {
+        let source = read_script(script, &mut stdin)?;
+
+        let (result, timings) = vm.run_source_timed(&source, output);
+        report(&result, &source, 1, color);
+        if options.time {
+            writeln!(
+                timing,
+                "Compiled in {:?}, ran in {:?}",
+                timings.compile, timings.run
+            )?;
+        }
+        match result {
+            InterpretResult::Ok(_) => {}
+            InterpretResult::RuntimeError(_) => return Err(RunError::Runtime),
+            InterpretResult::CompileError(_) => return Err(RunError::Compile),
+        }
+    }
+    Ok(())
+}
+
+// Compiles the script and prints its bytecode without running it.
+pub fn dump_bytecode(script: ScriptSource, options: Options) -> Result<(), RunError> {
+    let source = read_script(&script, io::stdin())?;
+
+    match compile(&source) {
+        Ok(chunk) => {
+            chunk.disassemble();
+            Ok(())
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error.render(&source, 1, use_color(options.no_color)));
+            }
+            Err(RunError::Compile)
+        }
+    }
+}
+
+// Scans the script and prints its tokens without compiling it.
+pub fn dump_tokens(script: ScriptSource) -> Result<(), RunError> {
+    let source = read_script(&script, io::stdin())?;
+
+    write_tokens(&source, &mut io::stdout().lock())?;
+    Ok(())
+}
 
-    if args.len() > 1 {
-        let script_file_name = args[1].clone();
-        Ok(RunningMode::Script(script_file_name))
-    } else {
-        Ok(RunningMode::Repl)
+// One token per line: its line, type and lexeme, ending with an `Eof` on the
+// last line of the source.
+pub fn write_tokens<W: Write>(source: &str, output: &mut W) -> io::Result<()> {
+    for token in tokens(source) {
+        match token.t_type {
+            TokenType::Error(msg) => writeln!(output, "{:4} Error {}", token.line, msg)?,
+            TokenType::Eof => writeln!(output, "{:4} Eof", token.line)?,
+            ref t_type => writeln!(output, "{:4} {} {}", token.line, t_type, token.lexeme)?,
+        }
     }
+    Ok(())
 }
 
-pub fn run_file(file_name: String) {
-    let source = read_file_to_string(&file_name).unwrap_or_else(|err| {
-        println!("Unable to read script file: {}", err);
+pub fn run_repl(options: Options) {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let vm = VM::new().with_color(use_color(options.no_color));
+    if let Err(err) = repl(vm, stdin.lock(), &mut stdout.lock()) {
+        println!("Error: {}", err);
         process::exit(2);
-    });
+    }
+}
+
+// Runs each input line in the same VM, so globals outlive the line that
+// defined them. Stops at the end of input or on `exit`.
+pub fn repl<R: BufRead, W: Write>(mut vm: VM, mut input: R, output: &mut W) -> io::Result<()> {
+    writeln!(output, "=== Rvlox repl ===")?;
+
+    let mut line_number = 0;
+    loop {
+        write!(output, "> ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim() == "exit" {
+            return Ok(());
+        }
+
+        line_number += 1;
+        vm.reset();
+        let result = vm.run_repl_line(&line, line_number, output);
+        report(&result, &line, line_number, vm.uses_color());
+    }
+}
 
-    match interpret_source(&source) {
-        InterpretResult::Ok => process::exit(0),
-        InterpretResult::RuntimeError => process::exit(1),
-        InterpretResult::CompileError => process::exit(2),
+// Prints what went wrong running `source`, if anything, to stderr.
+// `first_line` is the number of the source's first line.
+fn report(result: &InterpretResult, source: &str, first_line: usize, color: bool) {
+    match result {
+        InterpretResult::Ok(_) => {}
+        InterpretResult::CompileError(errors) => {
+            for error in errors {
+                eprintln!("{}", error.render(source, first_line, color));
+            }
+        }
+        InterpretResult::RuntimeError(RuntimeErrorWithLine(_, _, report)) => {
+            eprintln!("{}", report)
+        }
     }
 }
 
-pub fn run_repl() {
-    println!("=== Rvlox repl ===");
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+
+    #[test]
+    fn runtime_errors_fail_the_run() {
+        let result = run_scripts_timed(
+            &[ScriptSource::Stdin],
+            Options::default(),
+            "print -nil;".as_bytes(),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        );
+
+        match result {
+            Err(RunError::Runtime) => {}
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn time_flag_reports_durations() {
+        let mut timed = Vec::new();
+        let options = Options {
+            time: true,
+            ..Options::default()
+        };
+        run_scripts_timed(
+            &[ScriptSource::Stdin],
+            options,
+            "print 1;".as_bytes(),
+            &mut Vec::new(),
+            &mut timed,
+        )
+        .unwrap();
+
+        let mut untimed = Vec::new();
+        run_scripts_timed(
+            &[ScriptSource::Stdin],
+            Options::default(),
+            "print 1;".as_bytes(),
+            &mut Vec::new(),
+            &mut untimed,
+        )
+        .unwrap();
+
+        let timed = String::from_utf8(timed).unwrap();
+        assert!(timed.starts_with("Compiled in "), "{}", timed);
+        assert!(timed.contains(", ran in "), "{}", timed);
+        assert!(untimed.is_empty());
+    }
+
+    #[test]
+    fn time_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--time", "a.lox"])) {
+            Ok(RunningMode::Scripts(_, Options { time: true, .. })) => {}
+            other => panic!("Expected a timed script, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_file_reports_missing_files() {
+        let script = ScriptSource::File("does/not/exist.lox".to_string());
+        let result = run_files(&[script], Options::default());
+
+        match result {
+            Err(ref err @ RunError::Io(_)) => assert_eq!(2, err.exit_code()),
+            other => panic!("Expected an io error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_arguments_start_the_repl() {
+        match parse_args_for_running_mode(&args(&["rvlox"])) {
+            Ok(RunningMode::Repl(_)) => {}
+            other => panic!("Expected the repl, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn script_argument() {
+        match parse_args_for_running_mode(&args(&["rvlox", "a.lox"])) {
+            Ok(RunningMode::Scripts(ref scripts, ref options))
+                if *scripts == files(&["a.lox"]) && *options == Options::default() => {}
+            other => panic!("Expected a script, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn two_script_arguments() {
+        match parse_args_for_running_mode(&args(&["rvlox", "a.lox", "b.lox"])) {
+            Ok(RunningMode::Scripts(ref scripts, _)) if *scripts == files(&["a.lox", "b.lox"]) => {}
+            other => panic!("Expected two scripts, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn three_script_arguments() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--trace", "a.lox", "b.lox", "c.lox"])) {
+            Ok(RunningMode::Scripts(ref scripts, Options { trace: true, .. }))
+                if *scripts == files(&["a.lox", "b.lox", "c.lox"]) => {}
+            other => panic!("Expected three scripts, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stack_size_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--stack-size", "1048576", "a.lox"])) {
+            Ok(RunningMode::Scripts(
+                _,
+                Options {
+                    stack_size: Some(1048576),
+                    ..
+                },
+            )) => {}
+            other => panic!("Expected a stack size, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stack_size_flag_requires_a_value() {
+        assert_eq!(
+            Some(ArgError::MissingValueForFlag("--stack-size".to_string())),
+            parse_args_for_running_mode(&args(&["rvlox", "a.lox", "--stack-size"])).err()
+        );
+    }
+
+    #[test]
+    fn stack_size_flag_requires_a_positive_number() {
+        for value in &["big", "0", "-1"] {
+            assert_eq!(
+                Some(ArgError::InvalidValueForFlag {
+                    flag: "--stack-size".to_string(),
+                    value: value.to_string(),
+                }),
+                parse_args_for_running_mode(&args(&["rvlox", "--stack-size", value, "a.lox"]))
+                    .err()
+            );
+        }
+    }
+
+    #[test]
+    fn dump_bytecode_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--dump-bytecode", "a.lox"])) {
+            Ok(RunningMode::DumpBytecode(ScriptSource::File(ref name), _)) if name == "a.lox" => {}
+            other => panic!("Expected a bytecode dump, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tokens_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--tokens", "a.lox"])) {
+            Ok(RunningMode::DumpTokens(ScriptSource::File(ref name))) if name == "a.lox" => {}
+            other => panic!("Expected a token dump, got: {:?}", other),
+        }
+        assert_eq!(
+            Some(ArgError::MissingScriptForFlag),
+            parse_args_for_running_mode(&args(&["rvlox", "--tokens"])).err()
+        );
+    }
+
+    #[test]
+    fn write_tokens_lists_each_token() {
+        let mut output = Vec::new();
+        write_tokens("1 + 2", &mut output).unwrap();
+
+        assert_eq!(
+            "   1 Integer 1\n   1 Plus +\n   1 Integer 2\n   1 Eof\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn trace_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--trace", "a.lox"])) {
+            Ok(RunningMode::Scripts(_, Options { trace: true, .. })) => {}
+            other => panic!("Expected a traced script, got: {:?}", other),
+        }
+        assert_eq!(
+            Some(ArgError::MissingScriptForFlag),
+            parse_args_for_running_mode(&args(&["rvlox", "--trace"])).err()
+        );
+    }
+
+    #[test]
+    fn no_color_flag() {
+        match parse_args_for_running_mode(&args(&["rvlox", "--no-color", "a.lox"])) {
+            Ok(RunningMode::Scripts(_, Options { no_color: true, .. })) => {}
+            other => panic!("Expected an uncolored script, got: {:?}", other),
+        }
+        match parse_args_for_running_mode(&args(&["rvlox", "--no-color"])) {
+            Ok(RunningMode::Repl(Options { no_color: true, .. })) => {}
+            other => panic!("Expected an uncolored repl, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dash_reads_the_script_from_stdin() {
+        match parse_args_for_running_mode(&args(&["rvlox", "-"])) {
+            Ok(RunningMode::Scripts(ref scripts, _)) if *scripts == [ScriptSource::Stdin] => {}
+            other => panic!("Expected a script from stdin, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stdin_is_read_once() {
+        assert_eq!(
+            Some(ArgError::RepeatedStdin),
+            parse_args_for_running_mode(&args(&["rvlox", "-", "a.lox", "-"])).err()
+        );
+    }
+
+    #[test]
+    fn read_script_from_stdin() {
+        let source = read_script(&ScriptSource::Stdin, "print 1;".as_bytes()).unwrap();
+
+        assert_eq!("print 1;", source);
+    }
+
+    #[test]
+    fn dump_bytecode_requires_a_script() {
+        assert_eq!(
+            Some(ArgError::MissingScriptForFlag),
+            parse_args_for_running_mode(&args(&["rvlox", "--dump-bytecode"])).err()
+        );
+    }
+
+    #[test]
+    fn help_flags() {
+        for flag in &["--help", "-h"] {
+            match parse_args_for_running_mode(&args(&["rvlox", flag])) {
+                Ok(RunningMode::Help) => {}
+                other => panic!("Expected help for {}, got: {:?}", flag, other),
+            }
+        }
+    }
+
+    #[test]
+    fn version_flags() {
+        for flag in &["--version", "-V"] {
+            match parse_args_for_running_mode(&args(&["rvlox", flag])) {
+                Ok(RunningMode::Version) => {}
+                other => panic!("Expected the version for {}, got: {:?}", flag, other),
+            }
+        }
+    }
+
+    #[test]
+    fn dumps_take_a_single_script() {
+        for flag in &["--dump-bytecode", "--tokens"] {
+            assert_eq!(
+                Some(ArgError::TooManyArgs),
+                parse_args_for_running_mode(&args(&["rvlox", flag, "a.lox", "b.lox"])).err()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        assert_eq!(
+            Some(ArgError::UnknownFlag("--tarce".to_string())),
+            parse_args_for_running_mode(&args(&["rvlox", "--tarce", "a.lox"])).err()
+        );
+        assert_eq!(
+            Some(ArgError::UnknownFlag("-x".to_string())),
+            parse_args_for_running_mode(&args(&["rvlox", "a.lox", "-x"])).err()
+        );
+    }
+
+    #[test]
+    fn dumps_cannot_be_combined() {
+        assert_eq!(
+            Some(ArgError::ConflictingFlags),
+            parse_args_for_running_mode(&args(&["rvlox", "--tokens", "--dump-bytecode", "a.lox"]))
+                .err()
+        );
+    }
+
+    #[test]
+    fn arg_errors_display() {
+        assert_eq!(
+            "Unknown flag '--tarce'",
+            ArgError::UnknownFlag("--tarce".to_string()).to_string()
+        );
+        assert_eq!(
+            "Expect a script after the flags",
+            ArgError::MissingScriptForFlag.to_string()
+        );
+    }
+
+    #[test]
+    fn repl_keeps_globals_between_lines() {
+        let output = run_repl_with_input("var a = 1;\nprint a;\n");
+
+        assert_eq!("=== Rvlox repl ===\n> > 1\n> ", output);
+    }
+
+    #[test]
+    fn repl_echoes_expressions() {
+        let output = run_repl_with_input("7 * 6\nvar x = 1;\nx\n");
+
+        assert_eq!("=== Rvlox repl ===\n> 42\n> > 1\n> ", output);
+    }
+
+    #[test]
+    fn repl_continues_after_errors() {
+        let output = run_repl_with_input("print;\nprint undefined;\nprint 2;\n");
+
+        assert_eq!("=== Rvlox repl ===\n> > > 2\n> ", output);
+    }
+
+    #[test]
+    fn repl_stops_on_exit() {
+        let output = run_repl_with_input("print 1;\nexit\nprint 2;\n");
+
+        assert_eq!("=== Rvlox repl ===\n> 1\n> ", output);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn files(names: &[&str]) -> Vec<ScriptSource> {
+        names
+            .iter()
+            .map(|name| ScriptSource::File(name.to_string()))
+            .collect()
+    }
+
+    fn run_repl_with_input(input: &str) -> String {
+        let mut output = Vec::new();
+        repl(VM::new(), input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
 }
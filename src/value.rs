@@ -1,32 +1,925 @@
-#[derive(Debug, Clone, PartialEq)]
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use common::Chunk;
+use error::RuntimeError;
+use interner::Str;
+use natives::NativeContext;
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Double(f64),
+    // Whole numbers are kept exact until they overflow or mix with doubles.
+    Int(i64),
+    Bool(bool),
+    Nil,
+    Str(Str),
+    Function(Rc<Function>),
+    Native(Native),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+}
+
+pub type NativeFn = fn(&mut NativeContext, &[Value]) -> Result<Value, RuntimeError>;
+
+// The VM checks the argument count against the arity before calling the
+// function.
+#[derive(Debug, Clone, Copy)]
+pub struct Native {
+    pub function: NativeFn,
+    pub min_arity: u8,
+    pub max_arity: u8,
+}
+
+impl Native {
+    pub fn new(function: NativeFn, arity: RangeInclusive<u8>) -> Native {
+        Native {
+            function,
+            min_arity: *arity.start(),
+            max_arity: *arity.end(),
+        }
+    }
+}
+
+// The identity map keys and constants use: whole numbers compare as
+// integers, so an integer equals the double with the same value, and other
+// doubles by their bits, so NaN equals itself and `Eq` holds. Lox's `==`
+// follows IEEE arithmetic instead; see `equal`.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        if let (Some(l), Some(r)) = (self.as_integer(), other.as_integer()) {
+            return l == r;
+        }
+        match (self, other) {
+            (Value::Double(l), Value::Double(r)) => l.to_bits() == r.to_bits(),
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Str(l), Value::Str(r)) => l == r,
+            (Value::Function(l), Value::Function(r)) => Rc::ptr_eq(l, r),
+            (Value::Native(l), Value::Native(r)) => l.function as usize == r.function as usize,
+            (Value::Class(l), Value::Class(r)) => Rc::ptr_eq(l, r),
+            (Value::Instance(l), Value::Instance(r)) => Rc::ptr_eq(l, r),
+            (Value::BoundMethod(l), Value::BoundMethod(r)) => Rc::ptr_eq(l, r),
+            (Value::List(l), Value::List(r)) => Rc::ptr_eq(l, r),
+            (Value::Map(l), Value::Map(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+// Hashes what `PartialEq` compares: whole numbers as integers, other
+// numbers by bits and objects by identity.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(i) = self.as_integer() {
+            mem::discriminant(&Value::Int(0)).hash(state);
+            return i.hash(state);
+        }
+        mem::discriminant(self).hash(state);
+        match self {
+            Value::Double(d) => d.to_bits().hash(state),
+            Value::Int(i) => i.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Nil => {}
+            Value::Str(s) => s.hash(state),
+            Value::Function(function) => Rc::as_ptr(function).hash(state),
+            Value::Native(native) => (native.function as usize).hash(state),
+            Value::Class(class) => Rc::as_ptr(class).hash(state),
+            Value::Instance(instance) => Rc::as_ptr(instance).hash(state),
+            Value::BoundMethod(method) => Rc::as_ptr(method).hash(state),
+            Value::List(list) => Rc::as_ptr(list).hash(state),
+            Value::Map(map) => Rc::as_ptr(map).hash(state),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    // `None` for the top-level script.
+    pub name: Option<String>,
+    pub arity: usize,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Function {
+        Function {
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+// Methods are added one by one by `Method` instructions after the class is
+// created, hence the `RefCell`.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<Str, Rc<Function>>>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<Str, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+// A string-keyed map that iterates in insertion order. Assigning to an
+// existing key keeps its original position.
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: Vec<(Str, Value)>,
+    positions: HashMap<Str, usize>,
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+
+    pub fn get(&self, key: &Str) -> Option<&Value> {
+        self.positions.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, key: Str, value: Value) {
+        match self.positions.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Str, Value)> {
+        self.entries.iter()
+    }
 }
 
-macro_rules! binary_operator {
+// A method read off an instance, which becomes `this` when it is called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Function>,
+}
+
+// Significant digits `print` shows, as with C's `%g`.
+const NUMBER_PRECISION: usize = 6;
+
+// Formats like clox's `printf("%g")`: six significant digits without
+// trailing zeros, switching to an exponent for very large or small numbers.
+// Unlike `%g`, exact integers keep all their digits.
+fn format_number(d: f64) -> String {
+    if d.is_nan() {
+        return "nan".to_string();
+    }
+    if d.is_infinite() {
+        return if d > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if d == 0.0 {
+        return if d.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+    // Rounding to the precision first can carry into the next power of ten,
+    // so the exponent is taken from the rounded number.
+    let scientific = format!("{:.*e}", NUMBER_PRECISION - 1, d);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+
+    if exponent < -4 || exponent >= NUMBER_PRECISION as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (NUMBER_PRECISION as i32 - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, d)).to_string()
+    }
+}
+
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &mut HashSet::new())
+    }
+}
+
+impl Value {
+    // `printing` holds the lists and maps being printed, so one containing
+    // itself prints as `[...]` or `{...}` instead of recursing forever.
+    fn write(&self, f: &mut fmt::Formatter, printing: &mut HashSet<usize>) -> fmt::Result {
+        match self {
+            Value::Double(d) => write!(f, "{}", format_number(*d)),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::List(list) => {
+                let address = Rc::as_ptr(list) as usize;
+                if !printing.insert(address) {
+                    return write!(f, "[...]");
+                }
+                write!(f, "[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.write(f, printing)?;
+                }
+                printing.remove(&address);
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                let address = Rc::as_ptr(map) as usize;
+                if !printing.insert(address) {
+                    return write!(f, "{{...}}");
+                }
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    value.write(f, printing)?;
+                }
+                printing.remove(&address);
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+macro_rules! comparison_operator {
     (
         $sel:ident, $name:ident, $op: tt
     ) => {
-        pub fn $name(&$sel, other: &Value) -> Value {
-            match ($sel, &other) {
-                (Value::Double(l), Value::Double(r)) => Value::Double(l $op r)
-            }
+        pub fn $name(&$sel, other: &Value) -> Result<Value, RuntimeError> {
+            let ordering = match ($sel, other) {
+                (Value::Int(l), Value::Int(r)) => return Ok(Value::Bool(l $op r)),
+                (Value::Double(l), Value::Double(r)) => return Ok(Value::Bool(l $op r)),
+                (Value::Int(l), Value::Double(r)) => compare_int_to_double(*l, *r),
+                (Value::Double(l), Value::Int(r)) => {
+                    compare_int_to_double(*r, *l).map(Ordering::reverse)
+                }
+                _ => return Err($sel.operands_must_be_numbers(other)),
+            };
+            // Nothing compares to NaN.
+            Ok(Value::Bool(ordering.map_or(false, |o| o $op Ordering::Equal)))
         }
-    }
+    };
 }
 
 impl Value {
-    pub fn negate(&self) -> Value {
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Double(d) => Value::Double(-d),
+            Value::Double(_) | Value::Int(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Nil => "nil",
+            Value::Str(_) => "string",
+            Value::Function(_) | Value::Native(_) | Value::BoundMethod(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    // The number inside, or an error saying `op` expects a number.
+    pub fn as_number(&self, op: &'static str) -> Result<f64, RuntimeError> {
+        match *self {
+            Value::Double(d) => Ok(d),
+            Value::Int(i) => Ok(i as f64),
+            _ => Err(self.type_mismatch(op, "number")),
+        }
+    }
+
+    // The whole number the value holds, if it is an integer or a double
+    // with no fractional part that fits in one.
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i),
+            Value::Double(d) => exact_integer(d),
+            _ => None,
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Double(_) | Value::Int(_))
+    }
+
+    fn to_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Double(d) => Some(d),
+            Value::Int(i) => Some(i as f64),
+            _ => None,
         }
     }
 
-    binary_operator!(self, add, -);
+    pub fn as_bool(&self, op: &'static str) -> Result<bool, RuntimeError> {
+        match *self {
+            Value::Bool(b) => Ok(b),
+            _ => Err(self.type_mismatch(op, "boolean")),
+        }
+    }
 
-    binary_operator!(self, subtract, -);
+    pub fn as_string(&self, op: &'static str) -> Result<&Str, RuntimeError> {
+        match self {
+            Value::Str(s) => Ok(s),
+            _ => Err(self.type_mismatch(op, "string")),
+        }
+    }
+
+    fn type_mismatch(&self, op: &'static str, expected: &'static str) -> RuntimeError {
+        RuntimeError::TypeMismatch {
+            op,
+            expected,
+            got: self.type_name(),
+        }
+    }
 
-    binary_operator!(self, multiply, *);
+    pub fn list(elements: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(elements)))
+    }
 
-    binary_operator!(self, divide, /);
+    pub fn not(&self) -> Value {
+        Value::Bool(!self.is_truthy())
+    }
+
+    // Only `nil` and `false` are falsey; `0` and `""` are truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // Lox's `==`, which is `PartialEq` except that NaN is unequal to itself.
+    pub fn equal(&self, other: &Value) -> Value {
+        let equal = match (self, other) {
+            (Value::Double(l), Value::Double(r)) => l == r,
+            _ => self == other,
+        };
+        Value::Bool(equal)
+    }
+
+    // Adds numbers or concatenates strings.
+    pub fn add(&self, other: &Value) -> Result<Value, RuntimeError> {
+        match (self, other) {
+            _ if self.is_number() && other.is_number() => {
+                self.arithmetic(other, i64::checked_add, |l, r| l + r)
+            }
+            (Value::Str(l), Value::Str(r)) => {
+                let mut s = String::with_capacity(l.len() + r.len());
+                s.push_str(l);
+                s.push_str(r);
+                Ok(Value::Str(Str::from(s)))
+            }
+            _ => Err(RuntimeError::OperandsMustBeNumbersOrStrings {
+                left: self.type_name(),
+                right: other.type_name(),
+            }),
+        }
+    }
+
+    pub fn subtract(&self, other: &Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(other, i64::checked_sub, |l, r| l - r)
+    }
+
+    pub fn multiply(&self, other: &Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(other, i64::checked_mul, |l, r| l * r)
+    }
+
+    // Dividing by zero is an error rather than producing an infinity or NaN.
+    // Integers stay integers only when they divide evenly.
+    pub fn divide(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_number() && other.to_f64() == Some(0.0) {
+            return Err(RuntimeError::DivisionByZero);
+        }
+        self.arithmetic(
+            other,
+            |l, r| match l.checked_rem(r)? {
+                0 => l.checked_div(r),
+                _ => None,
+            },
+            |l, r| l / r,
+        )
+    }
+
+    // The remainder has the sign of the dividend, as with `%` on Rust numbers.
+    pub fn modulo(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_number() && other.to_f64() == Some(0.0) {
+            return Err(RuntimeError::DivisionByZero);
+        }
+        // `i64::MIN % -1` overflows only in the division it takes, the
+        // remainder is 0.
+        self.arithmetic(other, |l, r| Some(l.wrapping_rem(r)), |l, r| l % r)
+    }
+
+    // Negative exponents make fractions, so only others stay integers.
+    pub fn power(&self, other: &Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(
+            other,
+            |l, r| l.checked_pow(u32::try_from(r).ok()?),
+            f64::powf,
+        )
+    }
+
+    // Applies `int_op` to two integers, falling back to `double_op` when
+    // either operand is a double or the integer result would overflow.
+    fn arithmetic(
+        &self,
+        other: &Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        double_op: fn(f64, f64) -> f64,
+    ) -> Result<Value, RuntimeError> {
+        if let (Value::Int(l), Value::Int(r)) = (self, other) {
+            if let Some(result) = int_op(*l, *r) {
+                return Ok(Value::Int(result));
+            }
+        }
+        match (self.to_f64(), other.to_f64()) {
+            (Some(l), Some(r)) => Ok(Value::Double(double_op(l, r))),
+            _ => Err(self.operands_must_be_numbers(other)),
+        }
+    }
+
+    fn operands_must_be_numbers(&self, other: &Value) -> RuntimeError {
+        RuntimeError::OperandsMustBeNumbers {
+            left: self.type_name(),
+            right: other.type_name(),
+        }
+    }
+
+    comparison_operator!(self, greater, >);
+
+    comparison_operator!(self, less, <);
+}
+
+// 2^63, the first double past `i64::MAX`.
+const I64_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+// Orders an integer against a double without rounding the integer to the
+// nearest double, `None` if the double is NaN.
+fn compare_int_to_double(i: i64, d: f64) -> Option<Ordering> {
+    if d.is_nan() {
+        return None;
+    }
+    if d >= I64_LIMIT {
+        return Some(Ordering::Less);
+    }
+    if d < -I64_LIMIT {
+        return Some(Ordering::Greater);
+    }
+    // In range, the whole part of the double is exactly an `i64`.
+    let whole = d.trunc();
+    match i.cmp(&(whole as i64)) {
+        Ordering::Equal => whole.partial_cmp(&d),
+        ordering => Some(ordering),
+    }
+}
+
+// The double as an integer, if it is whole and in range. `-0` is `0`.
+fn exact_integer(d: f64) -> Option<i64> {
+    if d.fract() == 0.0 && (-I64_LIMIT..I64_LIMIT).contains(&d) {
+        Some(d as i64)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    #[test]
+    fn equal_values_hash_equally() {
+        assert_eq!(hash(&Value::Double(1.5)), hash(&Value::Double(1.5)));
+        assert_eq!(Value::Double(0.0), Value::Double(-0.0));
+        assert_eq!(hash(&Value::Double(0.0)), hash(&Value::Double(-0.0)));
+        assert_eq!(hash(&Value::Nil), hash(&Value::Nil));
+        assert_ne!(hash(&Value::Bool(false)), hash(&Value::Nil));
+        let list = Value::list(vec![]);
+        assert_eq!(hash(&list), hash(&list.clone()));
+    }
+
+    #[test]
+    // Objects hash by identity, so their interior mutability can't change a
+    // key's hash.
+    #[allow(clippy::mutable_key_type)]
+    fn values_as_map_keys() {
+        let mut map = HashMap::new();
+        map.insert(Value::Nil, 1);
+        map.insert(Value::Str(Str::from("key")), 2);
+        map.insert(Value::Str(Str::from(format!("{}y", "ke"))), 3);
+        map.insert(Value::Double(2.0), 4);
+
+        assert_eq!(Some(&1), map.get(&Value::Nil));
+        assert_eq!(Some(&3), map.get(&Value::Str(Str::from("key"))));
+        assert_eq!(Some(&4), map.get(&Value::Double(2.0)));
+        assert_eq!(3, map.len());
+        assert_eq!(None, map.get(&Value::Double(f64::NAN)));
+    }
+
+    fn hash(value: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn display_numbers() {
+        assert_eq!("3", Value::Double(3.0).to_string());
+        assert_eq!("3.5", Value::Double(3.5).to_string());
+        assert_eq!("-0.25", Value::Double(-0.25).to_string());
+        assert_eq!("100000", Value::Double(1e5).to_string());
+        assert_eq!("-42", Value::Double(-42.0).to_string());
+        assert_eq!("0.1", Value::Double(0.1).to_string());
+        assert_eq!("0.3", Value::Double(0.1 + 0.2).to_string());
+        assert_eq!("0.333333", Value::Double(1.0 / 3.0).to_string());
+        assert_eq!("0.0001", Value::Double(1e-4).to_string());
+    }
+
+    #[test]
+    fn display_numbers_with_an_exponent() {
+        assert_eq!("1.23457e+06", Value::Double(1234567.5).to_string());
+        assert_eq!("1e+06", Value::Double(1e6).to_string());
+        // Whole doubles round like any other; integers print in full.
+        assert_eq!("1.23457e+08", Value::Double(123456789.0).to_string());
+        assert_eq!("1.23457e+08", Value::Double(123456789.5).to_string());
+        assert_eq!("123456789", Value::Int(123456789).to_string());
+        assert_eq!("1e+16", Value::Double(1e16).to_string());
+        assert_eq!("1e+100", Value::Double(1e100).to_string());
+        assert_eq!("-2.5e-05", Value::Double(-0.000025).to_string());
+        // 999999.5 rounds up to the next power of ten.
+        assert_eq!("1e+06", Value::Double(999999.5).to_string());
+    }
+
+    #[test]
+    fn display_special_numbers() {
+        assert_eq!("0", Value::Double(0.0).to_string());
+        assert_eq!("-0", Value::Double(-0.0).to_string());
+        assert_eq!("inf", Value::Double(f64::INFINITY).to_string());
+        assert_eq!("-inf", Value::Double(f64::NEG_INFINITY).to_string());
+        assert_eq!("nan", Value::Double(f64::NAN).to_string());
+    }
+
+    #[test]
+    fn typed_accessors() {
+        assert_eq!(Ok(3.0), Value::Double(3.0).as_number("+"));
+        assert_eq!(Ok(true), Value::Bool(true).as_bool("!"));
+        assert_eq!(
+            Ok(&Str::from("a")),
+            Value::Str(Str::from("a")).as_string("len")
+        );
+    }
+
+    #[test]
+    fn typed_accessors_name_the_expected_type() {
+        let error = Value::Nil.as_number("-").unwrap_err();
+        assert_eq!(
+            RuntimeError::TypeMismatch {
+                op: "-",
+                expected: "number",
+                got: "nil",
+            },
+            error
+        );
+        assert_eq!(
+            "Operand of '-' must be a number, got nil",
+            error.to_string()
+        );
+        assert!(Value::Double(0.0).as_bool("!").is_err());
+        assert!(Value::Bool(false).as_string("upper").is_err());
+    }
+
+    #[test]
+    fn display_other_values() {
+        assert_eq!("true", Value::Bool(true).to_string());
+        assert_eq!("false", Value::Bool(false).to_string());
+        assert_eq!("nil", Value::Nil.to_string());
+        assert_eq!("abc", Value::Str(Str::from("abc")).to_string());
+    }
+
+    #[test]
+    fn type_names() {
+        assert_eq!("number", Value::Double(1.0).type_name());
+        assert_eq!("boolean", Value::Bool(false).type_name());
+        assert_eq!("nil", Value::Nil.type_name());
+        assert_eq!("string", Value::Str(Str::from("")).type_name());
+        let function = Value::Function(Rc::new(Function::new(None)));
+        assert_eq!("function", function.type_name());
+        let native: NativeFn = |_, _| Ok(Value::Nil);
+        assert_eq!(
+            "function",
+            Value::Native(Native::new(native, 0..=0)).type_name()
+        );
+    }
+
+    #[test]
+    fn truthiness() {
+        assert!(!Value::Nil.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Double(0.0).is_truthy());
+        assert!(Value::Double(1.5).is_truthy());
+        assert!(Value::Str(Str::from("")).is_truthy());
+        assert!(Value::Function(Rc::new(Function::new(None))).is_truthy());
+        let native: NativeFn = |_, _| Ok(Value::Nil);
+        assert!(Value::Native(Native::new(native, 0..=0)).is_truthy());
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(
+            Ok(Value::Double(2.5)),
+            Value::Double(5.0).divide(&Value::Double(2.0))
+        );
+        assert_eq!(
+            Err(RuntimeError::DivisionByZero),
+            Value::Double(1.0).divide(&Value::Double(0.0))
+        );
+        assert_eq!(
+            Err(RuntimeError::DivisionByZero),
+            Value::Double(0.0).divide(&Value::Double(-0.0))
+        );
+        assert_eq!(
+            Err(RuntimeError::OperandsMustBeNumbers {
+                left: "number",
+                right: "nil"
+            }),
+            Value::Double(1.0).divide(&Value::Nil)
+        );
+    }
+
+    #[test]
+    fn addition() {
+        assert_eq!(
+            Ok(Value::Double(3.0)),
+            Value::Double(1.0).add(&Value::Double(2.0))
+        );
+        assert_eq!(
+            Ok(Value::Str(Str::from("ab"))),
+            Value::Str(Str::from("a")).add(&Value::Str(Str::from("b")))
+        );
+        assert_eq!(
+            Err(RuntimeError::OperandsMustBeNumbersOrStrings {
+                left: "number",
+                right: "string"
+            }),
+            Value::Double(1.0).add(&Value::Str(Str::from("b")))
+        );
+    }
+
+    #[test]
+    fn modulo() {
+        assert_eq!(
+            Ok(Value::Double(1.0)),
+            Value::Double(7.0).modulo(&Value::Double(3.0))
+        );
+        assert_eq!(
+            Ok(Value::Double(-1.5)),
+            Value::Double(-7.5).modulo(&Value::Double(2.0))
+        );
+        assert_eq!(
+            Err(RuntimeError::DivisionByZero),
+            Value::Double(1.0).modulo(&Value::Double(0.0))
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        let int = Value::Int;
+        assert!(matches!(int(2).add(&int(2)), Ok(Value::Int(4))));
+        assert!(matches!(int(4).divide(&int(2)), Ok(Value::Int(2))));
+        assert!(matches!(int(2).divide(&int(4)), Ok(Value::Double(d)) if d == 0.5));
+        assert!(matches!(int(2).add(&Value::Double(0.5)), Ok(Value::Double(d)) if d == 2.5));
+        assert!(matches!(int(2).power(&int(-1)), Ok(Value::Double(d)) if d == 0.5));
+        assert_eq!(Err(RuntimeError::DivisionByZero), int(1).divide(&int(0)));
+
+        // Exact past 2^53, where doubles start skipping integers.
+        assert!(matches!(
+            int(9_007_199_254_740_993).add(&int(1)),
+            Ok(Value::Int(9_007_199_254_740_994))
+        ));
+        assert!(matches!(
+            int(i64::MAX).add(&int(1)),
+            Ok(Value::Double(d)) if d == 9_223_372_036_854_775_808.0
+        ));
+        assert!(matches!(
+            int(i64::MIN).divide(&int(-1)),
+            Ok(Value::Double(_))
+        ));
+        assert!(matches!(int(i64::MIN).modulo(&int(-1)), Ok(Value::Int(0))));
+        assert!(matches!(int(-7).modulo(&int(2)), Ok(Value::Int(-1))));
+    }
+
+    #[test]
+    fn integers_compare_exactly_with_doubles() {
+        let int = Value::Int;
+        let double = Value::Double;
+        let t = Value::Bool(true);
+        let f = Value::Bool(false);
+
+        // 2^53 + 1 rounds to 2^53 as a double.
+        assert_eq!(
+            Ok(t.clone()),
+            int(9_007_199_254_740_993).greater(&double(9_007_199_254_740_992.0))
+        );
+        assert_eq!(
+            Ok(t.clone()),
+            double(9_007_199_254_740_992.0).less(&int(9_007_199_254_740_993))
+        );
+        // `i64::MAX` rounds up to 2^63.
+        assert_eq!(
+            Ok(t.clone()),
+            int(i64::MAX).less(&double(9_223_372_036_854_775_808.0))
+        );
+        assert_eq!(
+            Ok(f.clone()),
+            int(i64::MIN).less(&double(-9_223_372_036_854_775_808.0))
+        );
+        assert_eq!(Ok(t.clone()), int(2).less(&double(2.5)));
+        assert_eq!(Ok(t.clone()), int(-2).greater(&double(-2.5)));
+        assert_eq!(Ok(f.clone()), int(2).greater(&double(2.0)));
+        assert_eq!(Ok(t.clone()), int(i64::MAX).less(&double(f64::INFINITY)));
+        assert_eq!(Ok(f.clone()), int(1).less(&double(f64::NAN)));
+        assert_eq!(Ok(f), double(f64::NAN).greater(&int(1)));
+    }
+
+    #[test]
+    fn integers_equal_doubles_with_the_same_value() {
+        assert_eq!(Value::Int(2), Value::Double(2.0));
+        assert_eq!(Value::Int(0), Value::Double(-0.0));
+        assert_ne!(Value::Int(2), Value::Double(2.5));
+        assert_ne!(
+            Value::Int(9_007_199_254_740_993),
+            Value::Double(9_007_199_254_740_992.0)
+        );
+        assert_eq!(hash(&Value::Int(2)), hash(&Value::Double(2.0)));
+        assert_eq!(Some(3), Value::Double(3.0).as_integer());
+        assert_eq!(None, Value::Double(f64::INFINITY).as_integer());
+        assert_eq!("2", Value::Int(2).to_string());
+    }
+
+    #[test]
+    fn comparisons_require_numbers() {
+        assert_eq!(
+            Err(RuntimeError::OperandsMustBeNumbers {
+                left: "string",
+                right: "number"
+            }),
+            Value::Str(Str::from("a")).less(&Value::Double(1.0))
+        );
+        assert_eq!(
+            Value::Bool(false),
+            Value::Double(1.0).equal(&Value::Str(Str::from("1")))
+        );
+        assert_eq!(Value::Bool(true), Value::Nil.equal(&Value::Nil));
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn nan_is_only_equal_to_itself_as_a_key() {
+        let nan = Value::Double(f64::NAN);
+        assert_eq!(nan, nan.clone());
+        assert_eq!(Value::Bool(false), nan.equal(&nan));
+        assert_eq!(Value::Int(0), Value::Double(-0.0));
+        assert_eq!(
+            Value::Bool(true),
+            Value::Double(0.0).equal(&Value::Double(-0.0))
+        );
+
+        let mut map = HashMap::new();
+        map.insert(nan.clone(), Value::Nil);
+        assert!(map.contains_key(&nan));
+    }
+
+    #[test]
+    fn functions_compare_by_identity() {
+        let f = Value::Function(Rc::new(Function::new(Some("f".to_string()))));
+        let g = Value::Function(Rc::new(Function::new(Some("f".to_string()))));
+        assert_eq!(f, f.clone());
+        assert_ne!(f, g);
+        assert_eq!(Value::Bool(false), f.equal(&g));
+    }
+
+    #[test]
+    fn display_functions() {
+        let function = Function::new(Some("f".to_string()));
+        assert_eq!("<fn f>", Value::Function(Rc::new(function)).to_string());
+        let script = Function::new(None);
+        assert_eq!("<script>", Value::Function(Rc::new(script)).to_string());
+    }
+
+    #[test]
+    fn display_lists() {
+        assert_eq!("[]", Value::list(vec![]).to_string());
+        let nested = Value::list(vec![Value::Nil]);
+        let list = Value::list(vec![Value::Double(1.0), Value::Str(Str::from("a")), nested]);
+        assert_eq!("[1, a, [nil]]", list.to_string());
+    }
+
+    #[test]
+    fn display_lists_containing_themselves() {
+        let list = Value::list(vec![Value::Int(1)]);
+        let shared = Value::list(vec![list.clone(), list.clone()]);
+        if let Value::List(ref l) = list {
+            l.borrow_mut().push(list.clone());
+        }
+
+        assert_eq!("[1, [...]]", list.to_string());
+        assert_eq!("[[1, [...]], [1, [...]]]", shared.to_string());
+    }
+
+    #[test]
+    fn maps_keep_insertion_order() {
+        let mut map = Map::new();
+        map.insert(Str::from("b"), Value::Double(1.0));
+        map.insert(Str::from("a"), Value::Double(2.0));
+        map.insert(Str::from("b"), Value::Double(3.0));
+
+        assert_eq!(2, map.len());
+        assert_eq!(Some(&Value::Double(3.0)), map.get(&Str::from("b")));
+        assert_eq!(None, map.get(&Str::from("c")));
+        let map = Value::Map(Rc::new(RefCell::new(map)));
+        assert_eq!("{b: 3, a: 2}", map.to_string());
+    }
+
+    #[test]
+    fn display_maps_containing_themselves() {
+        let map = Rc::new(RefCell::new(Map::new()));
+        let value = Value::Map(map.clone());
+        map.borrow_mut().insert(Str::from("self"), value.clone());
+        map.borrow_mut()
+            .insert(Str::from("list"), Value::list(vec![value.clone()]));
+
+        assert_eq!("{self: {...}, list: [{...}]}", value.to_string());
+    }
+
+    #[test]
+    fn display_classes_and_instances() {
+        let class = Rc::new(Class::new("Point".to_string()));
+        let instance = Instance::new(class.clone());
+
+        assert_eq!("Point", Value::Class(class).to_string());
+        assert_eq!(
+            "Point instance",
+            Value::Instance(Rc::new(instance)).to_string()
+        );
+    }
 }
@@ -1,71 +1,833 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
 use common::*;
-use compiler::compile;
+use compiler;
+use compiler::{compile, compile_eval, compile_repl_line, paint_error, source_context};
+use gc::Heap;
+use interner::Str;
+use natives::{self, NativeContext, Rng};
+use value;
 use value::*;
 
+pub use error::RuntimeError;
+
+pub const DEFAULT_STACK_CAPACITY: usize = 256;
+// Calls fail once the stack holds this many values.
+pub const DEFAULT_STACK_MAX: usize = 64 * 1024;
+// Unless set explicitly, calls may nest one frame deep for this many values
+// the stack may hold, so `--stack-size` bounds recursion too.
+const STACK_VALUES_PER_FRAME: usize = 4;
+// Longer stack traces print only this many innermost and outermost frames.
+const TRACE_EDGE: usize = 10;
+
+// How long a script took to compile and to run, for `--time`.
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    pub compile: Duration,
+    pub run: Duration,
+}
+
 pub struct VM {
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: HashMap<Str, Value>,
+    stack_max: usize,
+    frames_max: Option<usize>,
+    // Frees the lists, maps and instances kept alive only by cycles.
+    heap: Heap,
+    // Print the stack and each instruction before executing it.
+    trace: bool,
+    // Color the errors reported on stderr.
+    color: bool,
+    // Lent to natives as they are called.
+    native_context: NativeContext,
 }
 
+struct CallFrame {
+    function: Rc<Function>,
+    ip: usize,
+    // Index of the stack slot holding the called function, locals follow it.
+    slot_base: usize,
+}
+
+impl CallFrame {
+    // The line of the instruction executed last, 0 if none was.
+    fn line(&self) -> usize {
+        match self.ip {
+            0 => 0,
+            ip => self.function.chunk.line_at(ip - 1),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+    // The value the script returned, nil unless it was compiled for `eval`.
+    Ok(Value),
+    CompileError(Vec<compiler::Error>),
+    RuntimeError(RuntimeErrorWithLine),
+}
+
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(Vec<compiler::Error>),
+    Runtime(RuntimeErrorWithLine),
 }
 
+// A runtime error, the line of the instruction that raised it, and the
+// report for the user: the message, the stack trace and the source line.
+#[derive(Debug, PartialEq)]
+pub struct RuntimeErrorWithLine(pub RuntimeError, pub usize, pub String);
+
 macro_rules! binary_stack_op {
     ($sel:ident, $name:ident) => {
         if let Some(r) = $sel.stack_pop() {
             if let Some(l) = $sel.stack_pop() {
-                $sel.stack_push(l.$name(&r))
+                match l.$name(&r) {
+                    Ok(result) => $sel.push_object(result),
+                    Err(error) => return Err($sel.runtime_error(error)),
+                }
             } else {
-                return RuntimeError;
+                return Err($sel.runtime_error(RuntimeError::StackUnderflow));
             }
         } else {
-            return RuntimeError;
+            return Err($sel.runtime_error(RuntimeError::StackUnderflow));
         }
     };
 }
 
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
-        VM {
-            ip: 0,
-            stack: Vec::new(),
+        VM::with_capacity(DEFAULT_STACK_CAPACITY)
+    }
+
+    // Preallocates room for `capacity` values on the stack.
+    pub fn with_capacity(capacity: usize) -> VM {
+        let mut vm = VM {
+            frames: Vec::new(),
+            stack: Vec::with_capacity(capacity),
+            globals: HashMap::new(),
+            stack_max: DEFAULT_STACK_MAX,
+            frames_max: None,
+            heap: Heap::new(),
+            trace: false,
+            color: false,
+            native_context: NativeContext {
+                rng: Rng::from_time(),
+            },
+        };
+        for (name, arity, function) in natives::all() {
+            vm.define_native(name, arity, function);
         }
+        vm
+    }
+
+    pub fn with_stack_max(mut self, stack_max: usize) -> VM {
+        self.stack_max = stack_max;
+        self
+    }
+
+    pub fn with_frames_max(mut self, frames_max: usize) -> VM {
+        self.frames_max = Some(frames_max);
+        self
+    }
+
+    fn frames_max(&self) -> usize {
+        self.frames_max
+            .unwrap_or(self.stack_max / STACK_VALUES_PER_FRAME)
+    }
+
+    pub fn with_trace(mut self, trace: bool) -> VM {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_color(mut self, color: bool) -> VM {
+        self.color = color;
+        self
+    }
+
+    // Makes `random` and `randint` repeat the same numbers on every run.
+    pub fn with_seed(mut self, seed: u64) -> VM {
+        self.native_context.rng = Rng::new(seed);
+        self
+    }
+
+    // Drops whatever a previous run left on the stack and in the call frames,
+    // keeping the globals and the allocated stack.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    // Frees the objects kept alive only by reference cycles, and the
+    // interned strings no longer in use. Runs on its own as the number of
+    // objects and strings grows.
+    pub fn collect_garbage(&mut self) {
+        self.heap.collect();
+    }
+
+    // The lists, maps and instances still allocated, plus the strings this
+    // VM interned that are still in use.
+    pub fn live_object_count(&self) -> usize {
+        self.heap.live_count()
+    }
+
+    // Pushes a value that may be a newly created object or string, collecting
+    // garbage if enough of them were created since the last collection.
+    fn push_object(&mut self, value: Value) {
+        let value = match value {
+            Value::Str(s) => Value::Str(self.heap.intern(s)),
+            value => {
+                self.heap.track(&value);
+                value
+            }
+        };
+        self.stack_push(value);
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    pub fn define_native(&mut self, name: &str, arity: RangeInclusive<u8>, function: NativeFn) {
+        let name = self.heap.intern(Str::from(name));
+        self.globals
+            .insert(name, Value::Native(Native::new(function, arity)));
     }
 
     pub fn interpret(&mut self, chunk: &Chunk) -> InterpretResult {
-        use self::InterpretResult::*;
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        self.interpret_with_output(chunk, &mut out)
+    }
+
+    pub(crate) fn run_source<W: Write>(&mut self, source: &str, out: &mut W) -> InterpretResult {
+        self.run_source_timed(source, out).0
+    }
+
+    // Like `run_source`, also returning how long compiling and running took.
+    pub(crate) fn run_source_timed<W: Write>(
+        &mut self,
+        source: &str,
+        out: &mut W,
+    ) -> (InterpretResult, Timings) {
+        let start = Instant::now();
+        let compiled = compile(source);
+        let mut timings = Timings {
+            compile: start.elapsed(),
+            run: Duration::default(),
+        };
+
+        let result = match compiled {
+            Ok(chunk) => {
+                let start = Instant::now();
+                let result = self.interpret_source(&chunk, source, 1, out);
+                timings.run = start.elapsed();
+                result
+            }
+            Err(errors) => InterpretResult::CompileError(errors),
+        };
+        (result, timings)
+    }
+
+    // `line_number` is the line's position in the REPL session, which errors
+    // report.
+    pub(crate) fn run_repl_line<W: Write>(
+        &mut self,
+        line: &str,
+        line_number: usize,
+        out: &mut W,
+    ) -> InterpretResult {
+        match compile_repl_line(line, line_number) {
+            Ok(chunk) => self.interpret_source(&chunk, line, line_number, out),
+            Err(errors) => InterpretResult::CompileError(errors),
+        }
+    }
+
+    // Runs the snippet and returns the value of its trailing expression, or
+    // nil if it doesn't end with one.
+    pub fn eval(&mut self, source: &str) -> Result<Value, InterpretError> {
+        let chunk = compile_eval(source).map_err(InterpretError::Compile)?;
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        self.execute(&chunk, None, &mut out)
+            .map_err(InterpretError::Runtime)
+    }
+
+    pub(crate) fn uses_color(&self) -> bool {
+        self.color
+    }
+
+    // Like `interpret`, but `print` writes to `out` instead of stdout.
+    pub fn interpret_with_output<W: Write>(
+        &mut self,
+        chunk: &Chunk,
+        out: &mut W,
+    ) -> InterpretResult {
+        match self.execute(chunk, None, out) {
+            Ok(value) => InterpretResult::Ok(value),
+            Err(error) => InterpretResult::RuntimeError(error),
+        }
+    }
+
+    // Like `interpret_with_output`, ending the report of a runtime error with
+    // the line it happened on, taken from the chunk's `source`.
+    fn interpret_source<W: Write>(
+        &mut self,
+        chunk: &Chunk,
+        source: &str,
+        first_line: usize,
+        out: &mut W,
+    ) -> InterpretResult {
+        match self.execute(chunk, Some((source, first_line)), out) {
+            Ok(value) => InterpretResult::Ok(value),
+            Err(error) => InterpretResult::RuntimeError(error),
+        }
+    }
+
+    // Runs the chunk as the top-level script and returns the value it returns.
+    // `source`, with the number of its first line, is what the chunk was
+    // compiled from. A runtime error in code from the chunk shows the line
+    // it happened on; code from earlier chunks has no source at hand.
+    fn execute<W: Write>(
+        &mut self,
+        chunk: &Chunk,
+        source: Option<(&str, usize)>,
+        out: &mut W,
+    ) -> Result<Value, RuntimeErrorWithLine> {
+        let mut script = Function::new(None);
+        script.chunk = self.load(chunk);
+        let script = Rc::new(script);
+        self.stack_push(Value::Function(script.clone()));
+        let mut result = self.call(script.clone(), 0).and_then(|()| self.run(out));
+
+        if let Err(RuntimeErrorWithLine(_, line, ref mut report)) = result {
+            let failed = self.frames.last().map(|frame| frame.function.clone());
+            if let (Some((source, first_line)), Some(failed)) = (source, failed) {
+                if defines(&script, &failed) {
+                    report.push('\n');
+                    report.push_str(&source_context(source, first_line, line, None, self.color));
+                }
+            }
+            self.stack.clear();
+            self.frames.clear();
+        }
+        result
+    }
+
+    fn run<W: Write>(&mut self, out: &mut W) -> Result<Value, RuntimeErrorWithLine> {
         use common::Instruction::*;
+
         loop {
-            match self.read_instruction(chunk).0 {
+            if self.trace {
+                self.trace_instruction(out)
+                    .map_err(|_| self.runtime_error(RuntimeError::OutputFailed))?;
+            }
+
+            let instruction = match self.read_instruction() {
+                Some(instruction) => instruction,
+                None => return Err(self.runtime_error(RuntimeError::IpOutOfBounds)),
+            };
+
+            match instruction {
                 Return => {
-                    println!("{:?}", self.stack_pop());
-                    return Ok;
+                    let result = match self.stack_pop() {
+                        Some(v) => v,
+                        None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    let frame = self.frames.pop().expect("Call frame stack is empty");
+                    self.stack.truncate(frame.slot_base);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack_push(result);
                 }
                 Constant(c) => {
-                    let value = chunk.read_constant(c);
-                    self.stack_push(value.clone())
+                    let value = self.read_constant(c);
+                    self.stack_push(value)
+                }
+                Nil => self.stack_push(Value::Nil),
+                True => self.stack_push(Value::Bool(true)),
+                False => self.stack_push(Value::Bool(false)),
+                Duplicate => {
+                    if let Some(v) = self.stack.last().cloned() {
+                        self.stack_push(v);
+                    } else {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                Pop => {
+                    if self.stack_pop().is_none() {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                PopN(count) => {
+                    if count > self.stack.len() {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                    let len = self.stack.len() - count;
+                    self.stack.truncate(len);
+                }
+                DefineGlobal(c) => {
+                    let name = self.read_name(c)?;
+                    if let Some(v) = self.stack_pop() {
+                        self.globals.insert(name, v);
+                    } else {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                GetGlobal(c) => {
+                    let name = self.read_name(c)?;
+                    if let Some(v) = self.globals.get(&name).cloned() {
+                        self.stack_push(v);
+                    } else {
+                        return Err(
+                            self.runtime_error(RuntimeError::UndefinedVariable(name.to_string()))
+                        );
+                    }
                 }
-                Negate => {
+                SetGlobal(c) => {
+                    let name = self.read_name(c)?;
+                    if !self.globals.contains_key(&name) {
+                        return Err(
+                            self.runtime_error(RuntimeError::UndefinedVariable(name.to_string()))
+                        );
+                    }
+                    if let Some(v) = self.stack.last().cloned() {
+                        self.globals.insert(name, v);
+                    } else {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                // Compiled chunks only use slots that exist, but a
+                // deserialized one may not.
+                GetLocal(slot) => {
+                    let slot_base = self.frame().slot_base;
+                    match self.stack.get(slot_base + slot).cloned() {
+                        Some(v) => self.stack_push(v),
+                        None => return Err(self.runtime_error(RuntimeError::InvalidLocal(slot))),
+                    }
+                }
+                SetLocal(slot) => {
+                    let v = match self.stack.last().cloned() {
+                        Some(v) => v,
+                        None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    let slot_base = self.frame().slot_base;
+                    match self.stack.get_mut(slot_base + slot) {
+                        Some(local) => *local = v,
+                        None => return Err(self.runtime_error(RuntimeError::InvalidLocal(slot))),
+                    }
+                }
+                // The operand is negated in place without being popped, and
+                // a non-number is left on the stack for the error, like
+                // clox's `isNumber(peek(0))` guard.
+                Negate => match self.stack.last_mut() {
+                    Some(Value::Double(d)) => *d = -*d,
+                    Some(v @ Value::Int(i64::MIN)) => *v = Value::Double(-(i64::MIN as f64)),
+                    Some(Value::Int(i)) => *i = -*i,
+                    Some(v) => {
+                        let got = v.type_name();
+                        return Err(self.runtime_error(RuntimeError::TypeMismatch {
+                            op: "-",
+                            expected: "number",
+                            got,
+                        }));
+                    }
+                    None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                },
+                Not => {
                     if let Some(v) = self.stack_pop() {
-                        self.stack_push(v.negate());
+                        self.stack_push(v.not());
                     } else {
-                        return RuntimeError;
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
                     }
                 }
                 Add => binary_stack_op!(self, add),
                 Multiply => binary_stack_op!(self, multiply),
+                Power => binary_stack_op!(self, power),
                 Divide => binary_stack_op!(self, divide),
+                Modulo => binary_stack_op!(self, modulo),
                 Subtract => binary_stack_op!(self, subtract),
+                Greater => binary_stack_op!(self, greater),
+                Less => binary_stack_op!(self, less),
+                Equal => {
+                    if let (Some(r), Some(l)) = (self.stack_pop(), self.stack_pop()) {
+                        self.stack_push(l.equal(&r));
+                    } else {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                Print => {
+                    if let Some(v) = self.stack_pop() {
+                        if writeln!(out, "{}", v).is_err() {
+                            return Err(self.runtime_error(RuntimeError::OutputFailed));
+                        }
+                    } else {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                }
+                Jump(target) => self.frame_mut().ip = target,
+                Loop(target) => self.frame_mut().ip = target,
+                JumpIfFalse(target) => match self.stack.last() {
+                    Some(condition) => {
+                        if !condition.is_truthy() {
+                            self.frame_mut().ip = target;
+                        }
+                    }
+                    None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                },
+                Call(arg_count) => {
+                    if self.stack.len() <= arg_count {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                    let callee = self.stack[self.stack.len() - 1 - arg_count].clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                Class(c) => {
+                    let name = self.read_name(c)?;
+                    let class = Rc::new(value::Class::new(name.to_string()));
+                    self.stack_push(Value::Class(class));
+                }
+                GetProperty(c) => {
+                    let name = self.read_name(c)?;
+                    let instance = match self.stack_pop() {
+                        Some(Value::Instance(instance)) => instance,
+                        Some(v) => {
+                            return Err(
+                                self.runtime_error(RuntimeError::NotAnInstance(v.type_name()))
+                            )
+                        }
+                        None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    let method = instance.class.methods.borrow().get(&name).cloned();
+                    match (field, method) {
+                        (Some(value), _) => self.stack_push(value),
+                        (None, Some(method)) => {
+                            let receiver = Value::Instance(instance.clone());
+                            let bound = BoundMethod { receiver, method };
+                            self.stack_push(Value::BoundMethod(Rc::new(bound)));
+                        }
+                        (None, None) => {
+                            return Err(self
+                                .runtime_error(RuntimeError::UndefinedProperty(name.to_string())))
+                        }
+                    }
+                }
+                Invoke(c, arg_count) => {
+                    if self.stack.len() <= arg_count {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                    let name = self.read_name(c)?;
+                    self.invoke(name, arg_count)?;
+                }
+                SetProperty(c) => {
+                    let name = self.read_name(c)?;
+                    let (value, target) = match (self.stack_pop(), self.stack_pop()) {
+                        (Some(value), Some(target)) => (value, target),
+                        _ => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    match target {
+                        Value::Instance(instance) => {
+                            instance.fields.borrow_mut().insert(name, value.clone());
+                            self.stack_push(value);
+                        }
+                        v => {
+                            return Err(
+                                self.runtime_error(RuntimeError::NotAnInstance(v.type_name()))
+                            )
+                        }
+                    }
+                }
+                BuildMap(entry_count) => {
+                    if self.stack.len() < entry_count * 2 {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                    let entries = self.stack.split_off(self.stack.len() - entry_count * 2);
+                    let mut map = value::Map::new();
+                    for entry in entries.chunks(2) {
+                        match to_key(&entry[0]) {
+                            Ok(key) => map.insert(key, entry[1].clone()),
+                            Err(error) => return Err(self.runtime_error(error)),
+                        }
+                    }
+                    self.push_object(Value::Map(Rc::new(RefCell::new(map))));
+                }
+                BuildList(element_count) => {
+                    if self.stack.len() < element_count {
+                        return Err(self.runtime_error(RuntimeError::StackUnderflow));
+                    }
+                    let elements = self.stack.split_off(self.stack.len() - element_count);
+                    self.push_object(Value::list(elements));
+                }
+                GetIndex => {
+                    let (index, target) = match (self.stack_pop(), self.stack_pop()) {
+                        (Some(index), Some(target)) => (index, target),
+                        _ => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    let element = match target {
+                        Value::List(list) => {
+                            let list = list.borrow();
+                            to_index(&index, list.len()).map(|i| list[i].clone())
+                        }
+                        Value::Str(s) => to_index(&index, s.chars().count()).map(|i| {
+                            let c = s.chars().nth(i).expect("Index was checked");
+                            Value::Str(self.heap.intern(Str::from(&*c.encode_utf8(&mut [0; 4]))))
+                        }),
+                        // Reading a missing key gives nil.
+                        Value::Map(map) => to_key(&index)
+                            .map(|key| map.borrow().get(&key).cloned().unwrap_or(Value::Nil)),
+                        v => Err(RuntimeError::NotIndexable(v.type_name())),
+                    };
+                    match element {
+                        Ok(element) => self.stack_push(element),
+                        Err(error) => return Err(self.runtime_error(error)),
+                    }
+                }
+                SetIndex => {
+                    let (value, index, target) =
+                        match (self.stack_pop(), self.stack_pop(), self.stack_pop()) {
+                            (Some(value), Some(index), Some(target)) => (value, index, target),
+                            _ => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                        };
+                    let stored = match target {
+                        Value::List(list) => {
+                            let mut list = list.borrow_mut();
+                            to_index(&index, list.len()).map(|i| list[i] = value.clone())
+                        }
+                        Value::Map(map) => {
+                            to_key(&index).map(|key| map.borrow_mut().insert(key, value.clone()))
+                        }
+                        v => Err(RuntimeError::NotIndexAssignable(v.type_name())),
+                    };
+                    match stored {
+                        Ok(()) => self.stack_push(value),
+                        Err(error) => return Err(self.runtime_error(error)),
+                    }
+                }
+                Method(c) => {
+                    let name = self.read_name(c)?;
+                    let method = match self.stack_pop() {
+                        Some(Value::Function(method)) => method,
+                        Some(v) => return Err(self.type_mismatch("method", "function", &v)),
+                        None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    };
+                    match self.stack.last() {
+                        Some(Value::Class(class)) => {
+                            class.methods.borrow_mut().insert(name, method);
+                        }
+                        Some(v) => {
+                            let v = v.clone();
+                            return Err(self.type_mismatch("method", "class", &v));
+                        }
+                        None => return Err(self.runtime_error(RuntimeError::StackUnderflow)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeErrorWithLine> {
+        match callee {
+            Value::Function(function) => self.call(function, arg_count),
+            Value::BoundMethod(bound) => {
+                let callee_slot = self.stack.len() - 1 - arg_count;
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call(bound.method.clone(), arg_count)
+            }
+            Value::Class(class) => {
+                if arg_count != 0 {
+                    return Err(self.runtime_error(RuntimeError::ArityMismatch {
+                        expected: 0,
+                        got: arg_count,
+                    }));
+                }
+                let instance = Value::Instance(Rc::new(Instance::new(class)));
+                self.stack.pop();
+                self.push_object(instance);
+                Ok(())
+            }
+            Value::Native(native) => {
+                let (min, max) = (native.min_arity as usize, native.max_arity as usize);
+                if arg_count < min || arg_count > max {
+                    let error = if min == max {
+                        RuntimeError::ArityMismatch {
+                            expected: min,
+                            got: arg_count,
+                        }
+                    } else {
+                        RuntimeError::ArityRangeMismatch {
+                            min,
+                            max,
+                            got: arg_count,
+                        }
+                    };
+                    return Err(self.runtime_error(error));
+                }
+                let args_start = self.stack.len() - arg_count;
+                let result = (native.function)(&mut self.native_context, &self.stack[args_start..])
+                    .map_err(|error| self.runtime_error(error))?;
+                // An object only the result refers to was just created by the
+                // native; anything else is already tracked or not an object.
+                let created = match result {
+                    Value::Instance(ref o) => Rc::strong_count(o) == 1,
+                    Value::List(ref o) => Rc::strong_count(o) == 1,
+                    Value::Map(ref o) => Rc::strong_count(o) == 1,
+                    Value::Str(_) => true,
+                    _ => false,
+                };
+                self.stack.truncate(args_start - 1);
+                if created {
+                    self.push_object(result);
+                } else {
+                    self.stack_push(result);
+                }
+                Ok(())
             }
+            _ => Err(self.runtime_error(RuntimeError::NotCallable(callee.type_name()))),
         }
     }
 
-    fn read_instruction<'a>(&mut self, chunk: &'a Chunk) -> &'a InstructionWithLine {
-        self.ip += 1;
-        &chunk.instructions[self.ip - 1]
+    // Calls the receiver's method with the receiver left in the callee slot
+    // as `this`. A field holding a callable is called as it would be after
+    // `GetProperty`.
+    fn invoke(&mut self, name: Str, arg_count: usize) -> Result<(), RuntimeErrorWithLine> {
+        let callee_slot = self.stack.len() - 1 - arg_count;
+        let instance = match self.stack[callee_slot] {
+            Value::Instance(ref instance) => instance.clone(),
+            ref v => {
+                let got = v.type_name();
+                return Err(self.runtime_error(RuntimeError::NotAnInstance(got)));
+            }
+        };
+        let field = instance.fields.borrow().get(&name).cloned();
+        if let Some(field) = field {
+            self.stack[callee_slot] = field.clone();
+            return self.call_value(field, arg_count);
+        }
+        let method = instance.class.methods.borrow().get(&name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(RuntimeError::UndefinedProperty(name.to_string()))),
+        }
+    }
+
+    fn call(
+        &mut self,
+        function: Rc<Function>,
+        arg_count: usize,
+    ) -> Result<(), RuntimeErrorWithLine> {
+        if arg_count != function.arity {
+            return Err(self.runtime_error(RuntimeError::ArityMismatch {
+                expected: function.arity,
+                got: arg_count,
+            }));
+        }
+        if self.stack.len() > self.stack_max {
+            return Err(self.runtime_error(RuntimeError::StackOverflow));
+        }
+        if self.frames.len() >= self.frames_max() {
+            return Err(self.runtime_error(RuntimeError::CallStackOverflow));
+        }
+
+        let slot_base = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slot_base,
+        });
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Call frame stack is empty")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Call frame stack is empty")
+    }
+
+    fn trace_instruction<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut line = String::from("          ");
+        for value in &self.stack {
+            line.push_str(&format!("[ {} ]", value));
+        }
+        writeln!(out, "{}", line)?;
+
+        let frame = self.frame();
+        if frame.ip >= frame.function.chunk.len() {
+            return Ok(());
+        }
+        write!(
+            out,
+            "{}",
+            frame.function.chunk.disassemble_instruction(frame.ip)
+        )
+    }
+
+    fn read_instruction(&mut self) -> Option<Instruction> {
+        let frame = self.frame_mut();
+        let instruction = frame.function.chunk.instructions.get(frame.ip).cloned()?;
+        frame.ip += 1;
+        Some(instruction)
+    }
+
+    // A copy of the chunk with its string constants, and those of the
+    // functions among them, interned. Names and literals then share one
+    // allocation with the strings the VM makes and compare by pointer.
+    fn load(&mut self, chunk: &Chunk) -> Chunk {
+        let mut chunk = chunk.clone();
+        for constant in &mut chunk.constants {
+            match constant {
+                Value::Str(s) => *s = self.heap.intern(s.clone()),
+                Value::Function(function) => {
+                    let mut loaded = Function::new(function.name.clone());
+                    loaded.arity = function.arity;
+                    loaded.chunk = self.load(&function.chunk);
+                    *function = Rc::new(loaded);
+                }
+                _ => {}
+            }
+        }
+        chunk
+    }
+
+    fn read_constant(&self, c: usize) -> Value {
+        self.frame().function.chunk.read_constant(c).clone()
+    }
+
+    // Verifying a chunk checks its names are strings, but `interpret` may be
+    // given one that wasn't verified.
+    fn read_name(&mut self, c: usize) -> Result<Str, RuntimeErrorWithLine> {
+        match self.read_constant(c) {
+            Value::Str(name) => Ok(name),
+            v => Err(self.type_mismatch("name", "string", &v)),
+        }
+    }
+
+    fn type_mismatch(
+        &mut self,
+        op: &'static str,
+        expected: &'static str,
+        got: &Value,
+    ) -> RuntimeErrorWithLine {
+        self.runtime_error(RuntimeError::TypeMismatch {
+            op,
+            expected,
+            got: got.type_name(),
+        })
     }
 
     fn stack_push(&mut self, value: Value) {
@@ -75,9 +837,1524 @@ impl VM {
     fn stack_pop(&mut self) -> Option<Value> {
         self.stack.pop()
     }
+
+    // Reports the error with a stack trace and unwinds the VM.
+    fn runtime_error(&mut self, error: RuntimeError) -> RuntimeErrorWithLine {
+        let mut report = paint_error(&error.to_string(), self.color);
+        for frame in self.trace() {
+            report.push('\n');
+            report.push_str(&frame);
+        }
+        let line = self.frames.last().map_or(0, CallFrame::line);
+        // `execute` clears the stack and frames once it has the context.
+        RuntimeErrorWithLine(error, line, report)
+    }
+
+    // The call stack, innermost frame first, eliding the middle of deep ones.
+    fn trace(&self) -> Vec<String> {
+        let describe = |frame: &CallFrame| match frame.function.name {
+            Some(ref name) => format!("[line {}] in {}()", frame.line(), name),
+            None => format!("[line {}] in script", frame.line()),
+        };
+        let frames = &self.frames;
+        if frames.len() <= 2 * TRACE_EDGE {
+            return frames.iter().rev().map(describe).collect();
+        }
+        let mut trace: Vec<String> = frames[frames.len() - TRACE_EDGE..]
+            .iter()
+            .rev()
+            .map(describe)
+            .collect();
+        trace.push(format!("... {} more", frames.len() - 2 * TRACE_EDGE));
+        trace.extend(frames[..TRACE_EDGE].iter().rev().map(describe));
+        trace
+    }
+}
+
+// Checks that `index` is a whole number addressing one of `len` elements.
+fn to_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    let index = index.as_number("[]")?;
+    if index < 0.0 || index.fract() != 0.0 {
+        return Err(RuntimeError::InvalidIndex(index));
+    }
+    let index = index as usize;
+    if index >= len {
+        return Err(RuntimeError::IndexOutOfRange { index, len });
+    }
+    Ok(index)
+}
+
+fn to_key(key: &Value) -> Result<Str, RuntimeError> {
+    key.as_string("[]").cloned()
+}
+
+// Whether `function` is `script` or was compiled with it, as one of the
+// functions among its constants.
+fn defines(script: &Rc<Function>, function: &Rc<Function>) -> bool {
+    Rc::ptr_eq(script, function)
+        || script
+            .chunk
+            .constants
+            .iter()
+            .any(|constant| match constant {
+                Value::Function(nested) => defines(nested, function),
+                _ => false,
+            })
+}
+
+pub fn eval(source: &str) -> Result<Value, InterpretError> {
+    VM::new().eval(source)
 }
 
 pub fn interpret_source(source: &str) -> InterpretResult {
-    compile(source);
-    InterpretResult::Ok
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    VM::new().run_source(source, &mut out)
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+
+    #[test]
+    fn arithmetic() {
+        check("print 1 + 2 * 3 - 4 / 2;", "5\n");
+        check("print -(1 + 2);", "-3\n");
+    }
+
+    #[test]
+    fn power() {
+        check("print 2 ** 3 ** 2;", "512\n");
+        check("print 2 ** -1;", "0.5\n");
+        let (result, _) = run("print 2 ** nil;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn binary_operator_semantics() {
+        check(
+            "print \"a\" + \"b\"; print 7 % 3; print 1 == \"1\"; print nil != false;",
+            "ab\n1\nfalse\ntrue\n",
+        );
+        for source in [
+            "\"a\" < 1;",
+            "1 + \"b\";",
+            "\"a\" * 2;",
+            "1 % nil;",
+            "1 % 0;",
+        ] {
+            let (result, _) = run(source);
+            assert!(
+                matches!(result, InterpretResult::RuntimeError(_)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn comparisons() {
+        check(
+            "print 1 < 2; print 2 <= 1; print 1 == 1; print nil != false;",
+            "true\nfalse\ntrue\ntrue\n",
+        );
+    }
+
+    #[test]
+    fn logical_and() {
+        check(
+            "print 1 and 2; print nil and 2; print 1 and false;",
+            "2\nnil\nfalse\n",
+        );
+    }
+
+    #[test]
+    fn logical_and_short_circuits() {
+        check("print false and (1 / 0);", "false\n");
+        check("print false and undefined;", "false\n");
+    }
+
+    #[test]
+    fn logical_or() {
+        check(
+            "print 1 or 2; print nil or 2; print false or nil;",
+            "1\n2\nnil\n",
+        );
+    }
+
+    #[test]
+    fn logical_or_short_circuits() {
+        check("print true or somethingUndefined;", "true\n");
+        let (result, _) = run("print false or somethingUndefined;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn comma_operator() {
+        match eval("1, 2, 3") {
+            Ok(value) => assert_eq!(Value::Double(3.0), value),
+            other => panic!("Expected a value, got: {:?}", other),
+        }
+        check("var a; print (a = 1, a + 1);", "2\n");
+        check("fun f(a, b) { return b; } print f(1, (2, 3));", "3\n");
+    }
+
+    #[test]
+    fn conditional_operator() {
+        check("print true ? 1 : 2;", "1\n");
+        check("print nil ? 1 : 2;", "2\n");
+        check("print 1 < 2 ? \"yes\" : \"no\";", "yes\n");
+        check("print false ? 1 : false ? 2 : 3;", "3\n");
+        check("var a = 0; true ? a = 1 : 2; print a;", "1\n");
+    }
+
+    #[test]
+    fn if_then_branch() {
+        check("if (true) print 1; else print 2;", "1\n");
+    }
+
+    #[test]
+    fn if_else_branch_runs_when_falsey() {
+        check("if (false) print 1; else print 2;", "2\n");
+        check("if (nil) print 1; else print 2;", "2\n");
+        check("if (nil) print 1; print 3;", "3\n");
+    }
+
+    #[test]
+    fn zero_and_empty_string_are_truthy() {
+        check("if (0) print 1; else print 2;", "1\n");
+        check("if (\"\") print 1; else print 2;", "1\n");
+        check("print !0;", "false\n");
+    }
+
+    #[test]
+    fn type_error() {
+        let (result, output) = run("print 1; print -true; print 2;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        assert_eq!("1\n", output);
+    }
+
+    #[test]
+    fn globals() {
+        check(
+            "var a = 1; var b; print a; print b; a = b = 2; print a; print b;",
+            "1\nnil\n2\n2\n",
+        );
+    }
+
+    #[test]
+    fn undefined_global() {
+        let (result, _) = run("print a;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+
+        let (result, _) = run("a = 1;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn nil_coalescing_assignment_assigns_nil() {
+        check("var x = nil; x ??= 5; print x;", "5\n");
+        check("var x; print x ??= 5;", "5\n");
+    }
+
+    #[test]
+    fn nil_coalescing_assignment_keeps_non_nil() {
+        check(
+            "var x = 1; x ??= undefined; print x; print x ??= 2;",
+            "1\n1\n",
+        );
+        check("var x = false; x ??= 5; print x;", "false\n");
+    }
+
+    #[test]
+    fn compound_assignment() {
+        check("var a = 1; a += 4; print a;", "5\n");
+        check(
+            "{ var s = \"a\"; s += \"b\"; var n = 10; n -= 4; n *= 2; print s; print n /= 3; }",
+            "ab\n4\n",
+        );
+        check(
+            "class P {} var p = P(); p.x = 1; p.x += 2; print p.x;",
+            "3\n",
+        );
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        check(
+            "var a = 1; print a++; print a; print ++a; print a;",
+            "1\n2\n3\n3\n",
+        );
+        check("{ var b = 5; print b--; print --b; print b; }", "5\n3\n3\n");
+        check(
+            "var s = 0; for (var i = 0; i < 4; i++) s += i; print s;",
+            "6\n",
+        );
+    }
+
+    #[test]
+    fn locals() {
+        check(
+            "var a = 1; { var a = 2; { var b = a; a = 3; print b; } print a; } print a;",
+            "2\n3\n1\n",
+        );
+        check("{ var x; x ??= 4; print x; }", "4\n");
+    }
+
+    #[test]
+    fn while_loop() {
+        check(
+            "var i = 0; while (i < 3) { print i; i = i + 1; }",
+            "0\n1\n2\n",
+        );
+    }
+
+    #[test]
+    fn for_loop() {
+        check(
+            "var sum = 0; for (var i = 0; i < 4; i = i + 1) sum = sum + i; print sum;",
+            "6\n",
+        );
+        check("var i = 10; for (i = 0; i < 2;) i = i + 1; print i;", "2\n");
+    }
+
+    #[test]
+    fn break_exits_the_loop() {
+        check(
+            "var i = 0; while (true) { var j = i; if (j == 2) break; print j; i = i + 1; } print \"done\";",
+            "0\n1\ndone\n",
+        );
+        check(
+            "for (var i = 0; i < 3; i = i + 1) { for (;;) break; if (i == 1) break; print i; }",
+            "0\n",
+        );
+    }
+
+    #[test]
+    fn continue_skips_the_rest_of_the_iteration() {
+        check(
+            "for (var i = 0; i < 4; i = i + 1) { var j = i; if (j == 1) continue; print j; }",
+            "0\n2\n3\n",
+        );
+        check(
+            "var i = 0; while (i < 3) { i = i + 1; if (i == 2) continue; print i; }",
+            "1\n3\n",
+        );
+    }
+
+    #[test]
+    fn switch_runs_the_matching_case() {
+        let source = "fun f(x) { switch (x) { case 1: print \"one\"; case \"a\": var y = x; print y; default: print \"other\"; } print x; } f(1); f(\"a\"); f(nil);";
+        check(source, "one\n1\na\na\nother\nnil\n");
+        check("switch (3) { case 1: print 1; }", "");
+    }
+
+    #[test]
+    fn instance_fields() {
+        check(
+            "class A {} var a = A(); a.x = 1; print a.x; print a.y = 2; print a;",
+            "1\n2\nA instance\n",
+        );
+    }
+
+    #[test]
+    fn methods() {
+        check(
+            "class A { m() { return \"m\"; } } var a = A(); print a.m(); print A;",
+            "m\nA\n",
+        );
+        check(
+            "class A { m() { return 1; } } var a = A(); a.m = 2; print a.m;",
+            "2\n",
+        );
+    }
+
+    #[test]
+    fn this_is_the_receiver() {
+        check(
+            "class P { getX() { return this.x; } setX(x) { this.x = x; return this; } } var p = P(); p.x = 3; print p.getX(); print p.setX(4).getX();",
+            "3\n4\n",
+        );
+        check(
+            "class A { m() { return this; } } var a = A(); var m = a.m; print m; print m() == a;",
+            "<fn m>\ntrue\n",
+        );
+    }
+
+    #[test]
+    fn lists() {
+        check("var a = [10, 20]; print a[1];", "20\n");
+        check(
+            "var a = [1, [2, 3]]; a[0] = a[1][0] = 4; print a; print [];",
+            "[4, [4, 3]]\n[]\n",
+        );
+        check("var a = [1]; var b = a; b[0] = 2; print a[0];", "2\n");
+    }
+
+    #[test]
+    fn assert_native() {
+        check("assert(1 == 1); assert(true, \"fine\");", "");
+        let (result, _) = run("\nassert(1 == 2, \"nope\");");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::AssertionFailed(ref message),
+                2,
+                _
+            )) if message == "nope"
+        ));
+        let (result, _) = run("assert(nil);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn substr_native() {
+        check(
+            "print substr(\"hello\", 1, 3); print substr(\"héllo\", 0, 2);",
+            "ell\nhé\n",
+        );
+        check("print substr(\"abc\", 3, 0) == \"\";", "true\n");
+        let (result, _) = run("substr(\"abc\", 4, 0);");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::IndexOutOfRange { index: 4, len: 3 },
+                _,
+                _
+            ))
+        ));
+        let (result, _) = run("substr(\"abc\", 1, 3);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("substr(\"abc\", 0.5, 1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("substr(1, 0, 1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("substr(\"abc\", 1, 9223372036854775807);");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::IndexOutOfRange { len: 3, .. },
+                _,
+                _
+            ))
+        ));
+        for count in ["100000000000000000000000.0", "-1"] {
+            let (result, _) = run(&format!("substr(\"abc\", 0, {});", count));
+            assert!(matches!(
+                result,
+                InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                    RuntimeError::InvalidIndex(_),
+                    _,
+                    _
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn upper_and_lower_natives() {
+        check(
+            "print upper(\"Hello, world\"); print lower(\"Hello, WORLD\");",
+            "HELLO, WORLD\nhello, world\n",
+        );
+        let (result, _) = run("upper(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("lower();");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn trim_native() {
+        check("print trim(\"  a b\\t\\n\") + \"|\";", "a b|\n");
+        check("print trim(\"\") == \"\";", "true\n");
+        let (result, _) = run("trim(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn split_and_join_natives() {
+        check("print split(\"a,b,,c\", \",\");", "[a, b, , c]\n");
+        check("print split(\"héy\", \"\");", "[h, é, y]\n");
+        check("print len(split(\"\", \",\"));", "1\n");
+        check("print join([\"a\", \"b\"], \", \");", "a, b\n");
+        check("print join([], \"-\") == \"\";", "true\n");
+        check("print join(split(\"a,b,c\", \",\"), \"-\");", "a-b-c\n");
+
+        let (result, _) = run("split(\"a\", 1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("join(\"ab\", \"-\");");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::TypeMismatch {
+                    expected: "list",
+                    ..
+                },
+                _,
+                _
+            ))
+        ));
+        let (result, _) = run("join([\"a\", 1], \"-\");");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::TypeMismatch { got: "number", .. },
+                _,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn math_natives() {
+        check(
+            "print min(1, 2.5); print max(1, 2.5); print abs(-3); print abs(2);",
+            "1\n2.5\n3\n2\n",
+        );
+        check(
+            "print floor(1.5); print floor(-1.5); print ceil(1.5); print ceil(-1.5);",
+            "1\n-2\n2\n-1\n",
+        );
+        check(
+            "print sqrt(16); print sqrt(2); print sqrt(0);",
+            "4\n1.41421\n0\n",
+        );
+        match eval("floor(2)") {
+            Ok(Value::Double(d)) => assert_eq!(2.0, d),
+            other => panic!("Expected a double, got: {:?}", other),
+        }
+        for (source, expected) in &[("abs(-3)", 3), ("min(1, 2)", 1), ("max(1, 2)", 2)] {
+            match eval(source) {
+                Ok(Value::Int(i)) => assert_eq!(*expected, i),
+                other => panic!("Expected an integer from {}, got: {:?}", source, other),
+            }
+        }
+        match eval("min(1, 2.5)") {
+            Ok(Value::Double(d)) => assert_eq!(1.0, d),
+            other => panic!("Expected a double, got: {:?}", other),
+        }
+        match eval("abs(-9223372036854775807 - 1)") {
+            Err(InterpretError::Runtime(RuntimeErrorWithLine(
+                RuntimeError::IntegerOverflow("abs"),
+                _,
+                _,
+            ))) => {}
+            other => panic!("Expected an overflow, got: {:?}", other),
+        }
+
+        let (result, _) = run("sqrt(-1);");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::NegativeSquareRoot(x),
+                _, _
+            )) if x == -1.0
+        ));
+        let (result, _) = run("min(1, \"2\");");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::TypeMismatch { op: "min", .. },
+                _,
+                _
+            ))
+        ));
+        let (result, _) = run("abs();");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn random_natives_repeat_for_a_seed() {
+        let source = "for (var i = 0; i < 5; i = i + 1) print random(); print randint(1, 100);";
+        let run_seeded = |seed| {
+            let mut output = Vec::new();
+            let result = VM::new().with_seed(seed).run_source(source, &mut output);
+            assert_eq!(InterpretResult::Ok(Value::Nil), result);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(run_seeded(42), run_seeded(42));
+        assert_ne!(run_seeded(42), run_seeded(43));
+        check(
+            "var ok = true; for (var i = 0; i < 100; i = i + 1) { \
+             var r = random(); if (r < 0 or r >= 1) ok = false; \
+             if (randint(1, 1) != 1) ok = false; \
+             var n = randint(-2, 2); if (n < -2 or n > 2 or floor(n) != n) ok = false; } \
+             print ok;",
+            "true\n",
+        );
+
+        let (result, _) = run("randint(2, 1);");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::InvalidRange { lo: 2, hi: 1 },
+                _,
+                _
+            ))
+        ));
+        let (result, _) = run("randint(1.5, 2);");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::NotAnInteger(_),
+                _,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn str_native() {
+        check(
+            "print str(3.5) == \"3.5\"; print str(true) == \"true\";",
+            "true\ntrue\n",
+        );
+        check(
+            "print str(nil) + str([1, \"a\"]) + str(\"s\");",
+            "nil[1, a]s\n",
+        );
+    }
+
+    #[test]
+    fn num_native() {
+        check(
+            "print num(\"42\") == 42; print num(\" -1.5 \");",
+            "true\n-1.5\n",
+        );
+        let (result, _) = run("num(\"x\");");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::InvalidNumber(ref s),
+                1,
+                _
+            )) if s == "x"
+        ));
+        let (result, _) = run("num(\"inf\");");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("num(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn len_native() {
+        check(
+            "print len(\"abc\"); print len([1, 2]); print len(\"\");",
+            "3\n2\n0\n",
+        );
+        check("print len(\"héllo\");", "5\n");
+        let (result, _) = run("len(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("len(nil);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("len();");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn string_indexing() {
+        check("print \"abc\"[0]; print \"héllo\"[1];", "a\né\n");
+        check("print \"abc\"[2] == \"c\";", "true\n");
+        let (result, _) = run("print \"abc\"[3];");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("var s = \"abc\"; s[0] = \"x\";");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn maps() {
+        check(
+            "var m = {\"a\": 1}; m[\"b\"] = 2; m[\"a\"] = 3; print m[\"a\"]; print m[\"b\"]; print m[\"c\"];",
+            "3\n2\nnil\n",
+        );
+        check(
+            "var m = {\"y\": 1, \"x\": 2, \"y\": 3}; print m; print len(m);",
+            "{y: 3, x: 2}\n2\n",
+        );
+        let (result, _) = run("var m = {}; m[1] = 2;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("print {1: 2};");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn printing_a_list_that_contains_itself() {
+        check("var a = [1]; a[0] = a; print a;", "[[...]]\n");
+    }
+
+    #[test]
+    fn printing_a_map_that_contains_itself() {
+        check("var m = {}; m[\"k\"] = m; print m;", "{k: {...}}\n");
+    }
+
+    #[test]
+    fn index_errors() {
+        let result = VM::new().eval("[1, 2][2]");
+        match result {
+            Err(InterpretError::Runtime(RuntimeErrorWithLine(error, 1, _))) => {
+                assert_eq!(RuntimeError::IndexOutOfRange { index: 2, len: 2 }, error)
+            }
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+        let (result, _) = run("[1][0.5];");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("[1][-1];");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("[1][nil];");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("var a = 1; a[0] = 1;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn property_chains() {
+        check(
+            "class Node { value() { return this.v; } } \
+             var a = Node(); a.next = Node(); a.next.next = Node(); \
+             a.next.next.v = 3; print a.next.next.v; print a.next.next.value();",
+            "3\n3\n",
+        );
+    }
+
+    #[test]
+    fn properties_of_non_instances() {
+        let (result, _) = run("var a = 1;\nprint nil.x;");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::NotAnInstance("nil"),
+                2,
+                _
+            ))
+        ));
+        assert_eq!(
+            "Only instances have properties, got nil",
+            RuntimeError::NotAnInstance("nil").to_string()
+        );
+        let (result, _) = run("class A {} var a = A(); a.b = 1; a.b.c;");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::NotAnInstance("number"),
+                1,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn invoked_methods_match_bound_method_calls() {
+        let class = "class A { init() {} add(n) { return this.base + n; } }\n\
+                     var a = A(); a.base = 10;\n";
+        check(
+            &format!("{}print a.add(1); var add = a.add; print add(1);", class),
+            "11\n11\n",
+        );
+        // A field shadows the method, and is called like any value.
+        check(
+            &format!(
+                "{}fun twice(n) {{ return n * 2; }} a.add = twice; print a.add(4);",
+                class
+            ),
+            "8\n",
+        );
+        check(
+            "class A { m() { return this.x; } } var a = A(); a.x = 1; var b = A(); b.x = 2;\n\
+             a.other = b.m; print a.other();",
+            "2\n",
+        );
+
+        let (result, _) = run("class A {} A().missing();");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::UndefinedProperty(_),
+                1,
+                _
+            ))
+        ));
+        let (result, _) = run("nil.m();");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::NotAnInstance("nil"),
+                1,
+                _
+            ))
+        ));
+        let (result, _) = run("class A { m(a) {} } A().m();");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::ArityMismatch {
+                    expected: 1,
+                    got: 0
+                },
+                1,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn invoking_methods_in_a_loop() {
+        check(
+            "class Counter { bump(n) { this.count = this.count + n; return this; } }\n\
+             var c = Counter(); c.count = 0;\n\
+             for (var i = 0; i < 10000; i = i + 1) c.bump(1).bump(2);\n\
+             print c.count;",
+            "30000\n",
+        );
+    }
+
+    #[test]
+    fn property_errors() {
+        let (result, _) = run("class A {} print A().x;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("var a = 1; a.x = 2;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+        let (result, _) = run("class A {} A(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn for_loop_variable_is_scoped_to_the_loop() {
+        let (result, _) = run("for (var i = 0; i < 1; i = i + 1) {} print i;");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn function_call() {
+        check("fun add(a, b) { return a + b; } print add(1, 2);", "3\n");
+    }
+
+    #[test]
+    fn recursive_function() {
+        check(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } print fib(10);",
+            "55\n",
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_the_stack() {
+        let source = "fun f(n) { return f(n + 1); }\nf(0);";
+        let result = VM::new()
+            .with_stack_max(100)
+            .with_frames_max(1000)
+            .run_source(source, &mut Vec::new());
+
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(RuntimeError::StackOverflow, 1, _))
+        ));
+        check(
+            "fun f(n) { if (n > 0) return f(n - 1); return n; } print f(1000);",
+            "0\n",
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion_hits_the_frame_limit() {
+        let (result, _) = run("fun f() { return f(); }\nf();");
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::CallStackOverflow,
+                1,
+                _
+            ))
+        ));
+
+        let source = "fun f(n) { if (n > 0) return f(n - 1); return n; }\nprint f(10);";
+        let result = VM::new()
+            .with_frames_max(10)
+            .run_source(source, &mut Vec::new());
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::CallStackOverflow,
+                1,
+                _
+            ))
+        ));
+        let mut output = Vec::new();
+        let result = VM::new()
+            .with_frames_max(12)
+            .run_source(source, &mut output);
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+        assert_eq!("0\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn the_frame_limit_follows_the_stack_size() {
+        let source = "fun f(n) { if (n > 0) return f(n - 1); return n; }\nprint f(10);";
+        let result = VM::new()
+            .with_stack_max(10 * STACK_VALUES_PER_FRAME)
+            .run_source(source, &mut Vec::new());
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(
+                RuntimeError::CallStackOverflow,
+                1,
+                _
+            ))
+        ));
+        let result = VM::new()
+            .with_stack_max(12 * STACK_VALUES_PER_FRAME)
+            .run_source(source, &mut Vec::new());
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+    }
+
+    #[test]
+    fn deep_traces_elide_the_middle_frames() {
+        let mut vm = VM::new();
+        for i in 0..25 {
+            vm.frames.push(CallFrame {
+                function: Rc::new(Function::new(Some(format!("f{}", i)))),
+                ip: 0,
+                slot_base: 0,
+            });
+        }
+
+        let trace = vm.trace();
+        assert_eq!(21, trace.len());
+        assert_eq!("[line 0] in f24()", trace[0]);
+        assert_eq!("[line 0] in f15()", trace[9]);
+        assert_eq!("... 5 more", trace[10]);
+        assert_eq!("[line 0] in f9()", trace[11]);
+        assert_eq!("[line 0] in f0()", trace[20]);
+
+        vm.frames.truncate(20);
+        assert_eq!(20, vm.trace().len());
+    }
+
+    #[test]
+    fn local_function() {
+        check(
+            "{ var x = 10; fun f(a) { var y = a * 2; return y; } print f(x) + x; }",
+            "30\n",
+        );
+    }
+
+    #[test]
+    fn early_return() {
+        check(
+            "fun f(n) { while (true) { if (n > 3) return n; n = n + 1; } print \"unreachable\"; } print f(0);",
+            "4\n",
+        );
+        check(
+            "fun g() { print 1; return; print 2; } print g();",
+            "1\nnil\n",
+        );
+    }
+
+    #[test]
+    fn return_discards_callee_locals() {
+        check(
+            "fun f(a) { var b = a + 1; { var c = b + 1; return c; } } var x = 1; print f(x) + x;",
+            "4\n",
+        );
+    }
+
+    #[test]
+    fn function_without_return_yields_nil() {
+        check("fun f() {} print f();", "nil\n");
+    }
+
+    #[test]
+    fn arity_mismatch_is_a_runtime_error() {
+        let (result, _) = run("fun f(a, b) {} f(1);");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn calling_a_non_function_is_a_runtime_error() {
+        let (result, _) = run("var a = 1; a();");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn clock_native() {
+        let mut vm = VM::new();
+        let chunk = compile("var a = clock(); var b = clock();").expect("compile error");
+        assert_eq!(
+            InterpretResult::Ok(Value::Nil),
+            vm.interpret_with_output(&chunk, &mut Vec::new())
+        );
+
+        match (&vm.globals[&Str::from("a")], &vm.globals[&Str::from("b")]) {
+            (&Value::Double(a), &Value::Double(b)) => {
+                assert!(a >= 0.0);
+                assert!(b >= a);
+            }
+            other => panic!("Expected clock() to return numbers, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn define_native() {
+        fn answer(_context: &mut NativeContext, _args: &[Value]) -> Result<Value, RuntimeError> {
+            Ok(Value::Double(42.0))
+        }
+
+        let mut vm = VM::new();
+        vm.define_native("answer", 2..=2, answer);
+        let chunk = compile("print answer(1, 2) + 1;").expect("compile error");
+        let mut output = Vec::new();
+
+        assert_eq!(
+            InterpretResult::Ok(Value::Nil),
+            vm.interpret_with_output(&chunk, &mut output)
+        );
+        assert_eq!("43\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn natives_are_called_with_their_arity() {
+        fn answer(_context: &mut NativeContext, _args: &[Value]) -> Result<Value, RuntimeError> {
+            Ok(Value::Double(42.0))
+        }
+
+        let mut vm = VM::new();
+        vm.define_native("answer", 1..=2, answer);
+        for (source, got) in &[("answer();", 0), ("answer(1, 2, 3);", 3)] {
+            let chunk = compile(source).expect("compile error");
+            match vm.interpret_with_output(&chunk, &mut Vec::new()) {
+                InterpretResult::RuntimeError(RuntimeErrorWithLine(error, _, _)) => assert_eq!(
+                    RuntimeError::ArityRangeMismatch {
+                        min: 1,
+                        max: 2,
+                        got: *got
+                    },
+                    error
+                ),
+                other => panic!("Expected an arity mismatch, got: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn assert_takes_one_or_two_arguments() {
+        match run("assert(true, \"a\", \"b\");").0 {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(error, _, _)) => {
+                assert_eq!("Expected 1 or 2 arguments but got 3", error.to_string())
+            }
+            other => panic!("Expected an arity mismatch, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn eval_returns_the_trailing_expression() {
+        match eval("1 + 2") {
+            Ok(value) => assert_eq!(Value::Double(3.0), value),
+            other => panic!("Expected a value, got: {:?}", other),
+        }
+        match eval("var a = 2; a * 3") {
+            Ok(value) => assert_eq!(Value::Double(6.0), value),
+            other => panic!("Expected a value, got: {:?}", other),
+        }
+        match eval("1 + 2;") {
+            Ok(value) => assert_eq!(Value::Nil, value),
+            other => panic!("Expected nil, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn eval_errors() {
+        match eval("(") {
+            Err(InterpretError::Compile(ref errors)) if !errors.is_empty() => {}
+            other => panic!("Expected a compile error, got: {:?}", other),
+        }
+        match eval("-nil") {
+            Err(InterpretError::Runtime(_)) => {}
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn too_many_constants_is_a_compile_error() {
+        let literals: Vec<String> = (0..=MAX_CONSTANTS).map(|i| i.to_string()).collect();
+        let source = format!("print [{}];", literals.join(", "));
+
+        match VM::new().run_source(&source, &mut Vec::new()) {
+            InterpretResult::CompileError(errors) => {
+                assert_eq!("Too many constants in one chunk", errors[0].message())
+            }
+            other => panic!("Expected a compile error, got: {:?}", other),
+        }
+
+        // Added up, the literals fold into a single constant.
+        let source = format!("print {};", literals.join(" + "));
+        check(&source, "32896\n");
+    }
+
+    #[test]
+    fn interpret_results_carry_details() {
+        let result = VM::new().run_source("print 1;\nprint -true;", &mut Vec::new());
+        match result {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(error, line, _)) => {
+                assert_eq!(
+                    "Operand of '-' must be a number, got boolean",
+                    error.to_string()
+                );
+                assert_eq!(2, line);
+            }
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+
+        match VM::new().run_source("print;", &mut Vec::new()) {
+            InterpretResult::CompileError(errors) => {
+                assert_eq!(
+                    "[line 1] Error at ';': Expect expression",
+                    errors[0].to_string()
+                )
+            }
+            other => panic!("Expected a compile error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn negate_checks_its_operand() {
+        check("print -5; print - -5;", "-5\n5\n");
+        match eval("1;\n-true") {
+            Err(InterpretError::Runtime(RuntimeErrorWithLine(error, line, _))) => {
+                assert_eq!(
+                    RuntimeError::TypeMismatch {
+                        op: "-",
+                        expected: "number",
+                        got: "boolean"
+                    },
+                    error
+                );
+                assert_eq!(2, line);
+            }
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn type_errors_name_the_types() {
+        match eval("-true") {
+            Err(InterpretError::Runtime(RuntimeErrorWithLine(error, _, _))) => {
+                assert_eq!(
+                    RuntimeError::TypeMismatch {
+                        op: "-",
+                        expected: "number",
+                        got: "boolean"
+                    },
+                    error
+                );
+                assert_eq!(
+                    "Operand of '-' must be a number, got boolean",
+                    error.to_string()
+                );
+            }
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+        match eval("1 < nil") {
+            Err(InterpretError::Runtime(RuntimeErrorWithLine(error, _, _))) => assert_eq!(
+                "Operands must be numbers, got number and nil",
+                error.to_string()
+            ),
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn runtime_errors_carry_their_report() {
+        let source = "fun f() {\n  return -nil;\n}\nf();";
+        match VM::new().run_source(source, &mut Vec::new()) {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(_, 2, report)) => assert_eq!(
+                "Operand of '-' must be a number, got nil\n\
+                 [line 2] in f()\n\
+                 [line 4] in script\n  \
+                 |\n\
+                 2 |   return -nil;",
+                report
+            ),
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_in_functions_from_earlier_scripts_have_no_context() {
+        let mut vm = VM::new();
+        vm.run_source("fun f() {\n  return -nil;\n}", &mut Vec::new());
+        match vm.run_source("// b\nprint \"start\";\nf();", &mut Vec::new()) {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(_, 2, report)) => assert_eq!(
+                "Operand of '-' must be a number, got nil\n\
+                 [line 2] in f()\n\
+                 [line 3] in script",
+                report
+            ),
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_in_functions_from_earlier_repl_lines_have_no_context() {
+        let mut vm = VM::new();
+        vm.run_repl_line("fun f() { return -nil; }", 1, &mut Vec::new());
+        match vm.run_repl_line("f();", 2, &mut Vec::new()) {
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(_, 1, report)) => assert_eq!(
+                "Operand of '-' must be a number, got nil\n\
+                 [line 1] in f()\n\
+                 [line 2] in script",
+                report
+            ),
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn division_by_zero() {
+        match eval("var a = 1;\nvar b = 0;\na / b") {
+            Err(InterpretError::Runtime(error)) => {
+                assert!(matches!(
+                    error,
+                    RuntimeErrorWithLine(RuntimeError::DivisionByZero, 3, _)
+                ))
+            }
+            other => panic!("Expected a division error, got: {:?}", other),
+        }
+        check("print 1 / 4;", "0.25\n");
+    }
+
+    #[test]
+    fn trace_execution() {
+        let chunk = compile("1 + 2;").expect("compile error");
+        let mut output = Vec::new();
+
+        let result = VM::new()
+            .with_trace(true)
+            .interpret_with_output(&chunk, &mut output);
+
+        // `1 + 2` is folded into `3` when compiled.
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+        assert_eq!(
+            "          [ <script> ]\n\
+             0000    1 OP_CONSTANT         0 '3'\n\
+             \x20         [ <script> ][ 3 ]\n\
+             0001    | OP_POP\n\
+             \x20         [ <script> ]\n\
+             0002    | OP_NIL\n\
+             \x20         [ <script> ][ nil ]\n\
+             0003    | OP_RETURN\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn scopes_leave_the_stack_balanced() {
+        let source = "{ var a = 1; { var b = 2; var c = 3; var d = 4; } var e = 5; print a + e; }\n\
+                      for (var i = 0; i < 2; i = i + 1) { var x = i; var y = x; if (y > 0) break; }";
+        let chunk = compile(source).expect("compile error");
+        let mut output = Vec::new();
+
+        let result = VM::new()
+            .with_trace(true)
+            .interpret_with_output(&chunk, &mut output);
+
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines.contains(&"6"));
+        // The stack before the script's implicit `return nil`.
+        assert_eq!("          [ <script> ]", lines[lines.len() - 4]);
+        assert!(lines[lines.len() - 3].ends_with("OP_NIL"));
+    }
+
+    #[test]
+    fn collect_garbage_frees_cycles() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.run_source(
+            "class A {} var a = A(); a.me = a; var l = [1]; l[0] = l; var kept = A(); kept.me = kept;",
+            &mut output,
+        );
+        let instance = match vm.globals[&Str::from("a")] {
+            Value::Instance(ref instance) => Rc::downgrade(instance),
+            ref v => panic!("Expected an instance, got: {:?}", v),
+        };
+        let list = match vm.globals[&Str::from("l")] {
+            Value::List(ref list) => Rc::downgrade(list),
+            ref v => panic!("Expected a list, got: {:?}", v),
+        };
+        vm.run_source("a = nil; l = nil;", &mut output);
+        assert!(instance.upgrade().is_some());
+        assert!(list.upgrade().is_some());
+
+        vm.collect_garbage();
+        assert!(instance.upgrade().is_none());
+        assert!(list.upgrade().is_none());
+        vm.run_source("print kept.me == kept;", &mut output);
+        assert_eq!("true\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn collect_garbage_keeps_objects_held_outside_the_vm() {
+        let mut vm = VM::new();
+        let list = vm.eval("[1, 2, 3]").expect("eval failed");
+        let cycle = vm.eval("var c = [nil]; c[0] = c; c").expect("eval failed");
+        vm.run_source("c = nil;", &mut Vec::new());
+        vm.run_source(
+            "for (var i = 0; i < 3000; i = i + 1) { var l = [i]; }",
+            &mut Vec::new(),
+        );
+
+        vm.collect_garbage();
+        assert_eq!("[1, 2, 3]", list.to_string());
+        match cycle {
+            Value::List(ref l) => assert_eq!(cycle, l.borrow()[0]),
+            ref v => panic!("Expected a list, got: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn live_object_count_stays_bounded() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.run_source("var keep = [\"kept\"];", &mut output);
+        vm.collect_garbage();
+        let live = vm.live_object_count();
+
+        vm.run_source(
+            "for (var i = 0; i < 500; i = i + 1) { var s = str(i) + \"!\"; var l = [s]; l[0] = l; }",
+            &mut output,
+        );
+        assert!(vm.live_object_count() > live);
+
+        vm.collect_garbage();
+        assert_eq!(live, vm.live_object_count());
+        vm.run_source("print keep[0];", &mut output);
+        assert_eq!("kept\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn live_object_count_is_per_vm() {
+        // The names of the natives are interned strings in use.
+        let natives = VM::new().live_object_count();
+
+        // A list, a map and a string, and the two global names.
+        let mut vm = VM::new();
+        vm.run_source("var a = [str(1)]; var b = {};", &mut Vec::new());
+        assert_eq!(natives + 5, vm.live_object_count());
+
+        let mut other = VM::new();
+        other.run_source("var c = [str(2), str(3)];", &mut Vec::new());
+        assert_eq!(natives + 5, vm.live_object_count());
+        assert_eq!(natives + 4, other.live_object_count());
+    }
+
+    #[test]
+    fn strings_created_while_running_are_interned() {
+        let mut vm = VM::new();
+        vm.run_source(
+            "var p = \"a\"; var q = \"ab\"; var a = p + \"bc\"; var b = q + \"c\";",
+            &mut Vec::new(),
+        );
+
+        match (&vm.globals[&Str::from("a")], &vm.globals[&Str::from("b")]) {
+            (Value::Str(a), Value::Str(b)) => assert_eq!(a.as_ptr(), b.as_ptr()),
+            other => panic!("Expected two strings, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn literals_compiled_separately_are_interned_to_one_string() {
+        let mut vm = VM::new();
+        vm.run_source("var a = \"hello\";", &mut Vec::new());
+        vm.run_source("var b = \"hello\";", &mut Vec::new());
+
+        match (&vm.globals[&Str::from("a")], &vm.globals[&Str::from("b")]) {
+            (Value::Str(a), Value::Str(b)) => assert_eq!(a.as_ptr(), b.as_ptr()),
+            other => panic!("Expected two strings, got: {:?}", other),
+        }
+        // The names the globals are stored under are the interned ones too.
+        let (name, _) = vm.globals.get_key_value(&Str::from("a")).unwrap();
+        assert_eq!(name.as_ptr(), vm.heap.intern(Str::from("a")).as_ptr());
+    }
+
+    #[test]
+    fn strings_are_released_as_they_are_created() {
+        let mut vm = VM::new();
+        vm.run_source(
+            "for (var i = 0; i < 200000; i = i + 1) { var s = str(i) + \"!\"; }",
+            &mut Vec::new(),
+        );
+
+        assert!(vm.heap.interned_count() < 10000);
+    }
+
+    #[test]
+    fn garbage_is_collected_as_objects_are_created() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.run_source("class A {} var a = A(); a.me = a;", &mut output);
+        let instance = match vm.globals[&Str::from("a")] {
+            Value::Instance(ref instance) => Rc::downgrade(instance),
+            ref v => panic!("Expected an instance, got: {:?}", v),
+        };
+
+        vm.run_source(
+            "a = nil; for (var i = 0; i < 2000; i = i + 1) { var l = [i]; }",
+            &mut output,
+        );
+        assert!(instance.upgrade().is_none());
+    }
+
+    #[test]
+    fn duplicate_copies_the_top_of_the_stack() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(3.0));
+        chunk.add_instruction(Instruction::Constant(c), 1);
+        chunk.add_instruction(Instruction::Duplicate, 1);
+        chunk.add_instruction(Instruction::Add, 1);
+        chunk.add_instruction(Instruction::Return, 1);
+
+        assert_eq!(
+            Ok(Value::Double(6.0)),
+            VM::new().execute(&chunk, None, &mut Vec::new())
+        );
+    }
+
+    #[test]
+    fn binary_operators_keep_the_operand_order() {
+        let double = Value::Double;
+        let string = |s| Value::Str(Str::from(s));
+
+        assert_eq!(
+            double(7.0),
+            binary(double(10.0), double(3.0), Instruction::Subtract)
+        );
+        assert_eq!(
+            double(5.0),
+            binary(double(10.0), double(2.0), Instruction::Divide)
+        );
+        assert_eq!(
+            double(1.0),
+            binary(double(10.0), double(3.0), Instruction::Modulo)
+        );
+        assert_eq!(
+            double(8.0),
+            binary(double(2.0), double(3.0), Instruction::Power)
+        );
+        assert_eq!(
+            string("ab"),
+            binary(string("a"), string("b"), Instruction::Add)
+        );
+        assert_eq!(
+            Value::Bool(true),
+            binary(double(1.0), double(2.0), Instruction::Less)
+        );
+        assert_eq!(
+            Value::Bool(false),
+            binary(double(1.0), double(2.0), Instruction::Greater)
+        );
+        // Constant folding doesn't apply to variables, so the VM does these.
+        check(
+            "var a = 10; var b = 3; print a - b; print b / a;",
+            "7\n0.3\n",
+        );
+    }
+
+    #[test]
+    fn integers_stay_exact_until_they_overflow() {
+        match eval("2 + 2") {
+            Ok(Value::Int(4)) => {}
+            other => panic!("Expected the integer 4, got: {:?}", other),
+        }
+        check(
+            "var a = 2; print a + 2; print a / 4; print 9007199254740993 + 1; \
+             print 9223372036854775807 + 1; print -a; print 2 == 2.0;",
+            "4\n0.5\n9007199254740994\n9.22337e+18\n-2\ntrue\n",
+        );
+    }
+
+    // Runs `l op r` on the VM, bypassing the compiler's constant folding.
+    fn binary(l: Value, r: Value, op: Instruction) -> Value {
+        let mut chunk = Chunk::new();
+        let l = chunk.add_constant(l);
+        let r = chunk.add_constant(r);
+        chunk.add_instruction(Instruction::Constant(l), 1);
+        chunk.add_instruction(Instruction::Constant(r), 1);
+        chunk.add_instruction(op, 1);
+        chunk.add_instruction(Instruction::Return, 1);
+        VM::new()
+            .execute(&chunk, None, &mut Vec::new())
+            .expect("runtime error")
+    }
+
+    #[test]
+    fn running_off_the_end_of_a_chunk() {
+        let mut chunk = Chunk::new();
+        let c = chunk.add_constant(Value::Double(1.0));
+        chunk.add_instruction(Instruction::Constant(c), 1);
+
+        assert!(matches!(
+            VM::new().execute(&chunk, None, &mut Vec::new()),
+            Err(RuntimeErrorWithLine(RuntimeError::IpOutOfBounds, 1, _))
+        ));
+        assert!(matches!(
+            VM::new().interpret_with_output(&Chunk::new(), &mut Vec::new()),
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(RuntimeError::IpOutOfBounds, 0, _))
+        ));
+    }
+
+    #[test]
+    fn repl_lines_report_their_position_in_the_session() {
+        let mut vm = VM::new();
+        let result = vm.run_repl_line("print -nil;", 5, &mut Vec::new());
+
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(RuntimeErrorWithLine(_, 5, _))
+        ));
+    }
+
+    #[test]
+    fn interpret_with_output_captures_prints() {
+        let chunk = compile("print 1; print 2;").expect("compile error");
+        let mut output: Vec<u8> = Vec::new();
+
+        let result = VM::new().interpret_with_output(&chunk, &mut output);
+
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+        assert_eq!(b"1\n2\n".to_vec(), output);
+    }
+
+    #[test]
+    fn reset_keeps_globals_and_the_stack_allocation() {
+        let mut vm = VM::with_capacity(16);
+        let mut output = Vec::new();
+        vm.run_source("var a = 1;", &mut output);
+        vm.stack_push(Value::Nil);
+
+        vm.reset();
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+        assert!(vm.globals.contains_key(&Str::from("a")));
+
+        let stack = vm.stack.as_ptr();
+        let capacity = vm.stack.capacity();
+        vm.run_source("{ var b = a + 1; print b * 2; }", &mut output);
+        assert_eq!("4\n", String::from_utf8(output).unwrap());
+        assert_eq!(stack, vm.stack.as_ptr());
+        assert_eq!(capacity, vm.stack.capacity());
+    }
+
+    fn check(source: &str, expected_output: &str) {
+        let (result, output) = run(source);
+        assert_eq!(InterpretResult::Ok(Value::Nil), result);
+        assert_eq!(expected_output, output);
+    }
+
+    fn run(source: &str) -> (InterpretResult, String) {
+        let chunk = compile(source).expect("compile error");
+        let mut output = Vec::new();
+        let result = VM::new().interpret_with_output(&chunk, &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
 }
@@ -0,0 +1,91 @@
+// Runs every `tests/fixtures/*.lox` script and compares what it prints with
+// the `.expected` file next to it. A script failing to compile or run ends
+// its output with an `error: compile` or `error: runtime` line.
+
+extern crate rvlox;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rvlox::compiler::compile;
+use rvlox::vm::{InterpretResult, VM};
+
+#[test]
+fn fixtures() {
+    let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&fixtures_dir)
+        .expect("Unable to read the fixtures directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "lox"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "No fixtures in {:?}", fixtures_dir);
+
+    let failures: Vec<String> = scripts.iter().filter_map(|s| check_fixture(s)).collect();
+
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} fixtures failed:\n\n{}",
+            failures.len(),
+            scripts.len(),
+            failures.join("\n")
+        );
+    }
+}
+
+fn check_fixture(script: &Path) -> Option<String> {
+    let source = fs::read_to_string(script).unwrap();
+    let expected_path = script.with_extension("expected");
+    let expected = fs::read_to_string(&expected_path)
+        .unwrap_or_else(|err| panic!("Unable to read {:?}: {}", expected_path, err));
+
+    let actual = run(&source);
+    if actual == expected {
+        None
+    } else {
+        Some(format!(
+            "{}:\n{}",
+            script.display(),
+            diff(&expected, &actual)
+        ))
+    }
+}
+
+fn run(source: &str) -> String {
+    let chunk = match compile(source) {
+        Ok(chunk) => chunk,
+        Err(_) => return "error: compile\n".to_string(),
+    };
+
+    let mut output = Vec::new();
+    let result = VM::new().interpret_with_output(&chunk, &mut output);
+    let mut output = String::from_utf8(output).unwrap();
+    match result {
+        InterpretResult::Ok(_) => {}
+        InterpretResult::CompileError(_) => output.push_str("error: compile\n"),
+        InterpretResult::RuntimeError(_) => output.push_str("error: runtime\n"),
+    }
+    output
+}
+
+// Lists every line, marking expected lines missing from the output with `-`
+// and unexpected output lines with `+`.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => out.push_str(&format!("  {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    out.push_str(&format!("- {}\n", e));
+                }
+                if let Some(a) = a {
+                    out.push_str(&format!("+ {}\n", a));
+                }
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,48 @@
+// Runs several script files in one VM, the way `rvlox a.lox b.lox` does.
+
+extern crate rvlox;
+
+mod support;
+
+use rvlox::util::{run_scripts, Options, RunError, ScriptSource};
+use support::TempScript;
+
+#[test]
+fn globals_carry_across_scripts() {
+    let first = TempScript::new("globals-a", "var greeting = \"hello\";");
+    let second = TempScript::new("globals-b", "print greeting;");
+
+    let mut output = Vec::new();
+    let result = run_scripts(
+        &[source(&first), source(&second)],
+        Options::default(),
+        &mut output,
+    );
+
+    assert!(result.is_ok());
+    assert_eq!("hello\n", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn the_first_failing_script_stops_the_run() {
+    let first = TempScript::new("stop-a", "print 1;");
+    let second = TempScript::new("stop-b", "print -nil;");
+    let third = TempScript::new("stop-c", "print 3;");
+
+    let mut output = Vec::new();
+    let result = run_scripts(
+        &[source(&first), source(&second), source(&third)],
+        Options::default(),
+        &mut output,
+    );
+
+    match result {
+        Err(ref err @ RunError::Runtime) => assert_eq!(1, err.exit_code()),
+        other => panic!("Expected a runtime error, got: {:?}", other),
+    }
+    assert_eq!("1\n", String::from_utf8(output).unwrap());
+}
+
+fn source(script: &TempScript) -> ScriptSource {
+    ScriptSource::File(script.path())
+}
@@ -0,0 +1,36 @@
+// Helpers shared by the integration tests.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A script written to the temp directory and removed when dropped, even if
+// the test using it fails.
+pub struct TempScript {
+    path: PathBuf,
+}
+
+impl TempScript {
+    // `name` tells the files apart when one is left behind; the counter
+    // keeps tests running at the same time from sharing a file.
+    pub fn new(name: &str, source: &str) -> TempScript {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let file_name = format!("rvlox-{}-{}-{}.lox", name, process::id(), id);
+        let path = env::temp_dir().join(file_name);
+        fs::write(&path, source).unwrap();
+        TempScript { path }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}